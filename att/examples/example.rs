@@ -44,27 +44,27 @@ impl Handler for H {
         ) {
             (0x000F, 0x000F) => {
                 Ok(vec![
-                    (0x000F.into(), Uuid::new_uuid16(0x2902).into()), // Client Characteristic Configuration
+                    (0x000F.into(), Uuid::new_uuid16(0x2902)), // Client Characteristic Configuration
                 ]
                 .into_iter()
                 .collect())
             }
             (0x0026, 0x0027) => {
                 Ok(vec![
-                    (0x0026.into(), Uuid::new_uuid16(0x2902).into()), // Client Characteristic Configuration
+                    (0x0026.into(), Uuid::new_uuid16(0x2902)), // Client Characteristic Configuration
                 ]
                 .into_iter()
                 .collect())
             }
             (0x0027, 0x0027) => {
                 Ok(vec![
-                    (0x0027.into(), Uuid::new_uuid16(0x2904).into()), // Characteristic Presentation Format Descriptor
+                    (0x0027.into(), Uuid::new_uuid16(0x2904)), // Characteristic Presentation Format Descriptor
                 ]
                 .into_iter()
                 .collect())
             }
             (x, _) => Err(ErrorResponse::new(
-                x.clone().into(),
+                x.into(),
                 pkt::ErrorCode::AttributeNotFound,
             )),
         }
@@ -111,7 +111,7 @@ impl Handler for H {
                 .collect())
             }
             (x, _, _) => Err(ErrorResponse::new(
-                x.clone().into(),
+                x.into(),
                 pkt::ErrorCode::AttributeNotFound,
             )),
         }
@@ -153,7 +153,7 @@ impl Handler for H {
                 .collect())
             }
             (x, _) => Err(ErrorResponse::new(
-                x.clone().into(),
+                x.into(),
                 pkt::ErrorCode::AttributeNotFound,
             )),
         }
@@ -23,7 +23,7 @@ impl Handler for MyHandler {
                 .collect())
             }
             (x, _) => Err(ErrorResponse::new(
-                x.clone().into(),
+                x.into(),
                 pkt::ErrorCode::AttributeNotFound,
             )),
         }
@@ -0,0 +1,633 @@
+//! ATT Protocol Client
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use futures_core::ready;
+use futures_util::future::FutureExt;
+use futures_util::lock::{Mutex, MutexGuard};
+use futures_util::sink::SinkExt;
+use futures_util::stream::StreamExt;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::packet as pkt;
+use crate::sock::AttStream;
+use crate::stream::{self, PacketStream, DEFAULT_TRANSACTION_TIMEOUT};
+use crate::transport::BoxTransport;
+use crate::uuid::Uuid16;
+use crate::{Handle, Transport, Uuid};
+use pkt::pack;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    Pack(#[from] pack::Error),
+
+    #[error("error response {0:?}")]
+    ErrorResponse(pkt::ErrorResponse),
+
+    #[error("unexpected response {0:?}")]
+    UnexpectedResponse(pkt::ClientRecv),
+
+    #[error("connection closed.")]
+    Closed,
+
+    /// No response in time. No more PDUs are sent on the bearer.
+    #[error("transaction timed out")]
+    TransactionTimeout,
+}
+
+impl From<stream::Error> for Error {
+    fn from(v: stream::Error) -> Self {
+        match v {
+            stream::Error::Io(err) => Self::Io(err),
+            stream::Error::Pack(err) | stream::Error::Unparsable(_, err) => Self::Pack(err),
+            stream::Error::TransactionTimeout => Self::TransactionTimeout,
+            err @ stream::Error::PayloadTooLarge { .. } => Self::Io(err.into()),
+        }
+    }
+}
+
+type Result<R> = std::result::Result<R, Error>;
+
+/// Notification or Indication from the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Notification(Handle, Box<[u8]>),
+    Indication(Handle, Box<[u8]>),
+}
+
+struct Inner<IO> {
+    stream: PacketStream<IO, pkt::ClientRecv>,
+    response: Option<pkt::ClientRecv>,
+    response_waker: Option<Waker>,
+    events: VecDeque<Event>,
+    events_waker: Option<Waker>,
+}
+
+impl<IO> Inner<IO> {
+    fn new(io: IO) -> Self {
+        Self {
+            stream: PacketStream::new(io),
+            response: None,
+            response_waker: None,
+            events: VecDeque::new(),
+            events_waker: None,
+        }
+    }
+
+    fn take_response(&mut self, waker: &Waker) -> Option<pkt::ClientRecv> {
+        let response = self.response.take();
+        if response.is_none() {
+            self.response_waker = Some(waker.clone());
+        }
+        response
+    }
+
+    fn take_event(&mut self, waker: &Waker) -> Option<Event> {
+        let event = self.events.pop_front();
+        if event.is_none() {
+            self.events_waker = Some(waker.clone());
+        }
+        event
+    }
+}
+
+enum Next<T> {
+    Taken(T),
+    Received(Option<stream::Result<pkt::ClientRecv>>),
+}
+
+/// Take an already dispatched item, or receive next packet.
+struct TryLockNext<'a, IO, T> {
+    inner: &'a Mutex<Inner<IO>>,
+    take: fn(&mut Inner<IO>, &Waker) -> Option<T>,
+}
+
+impl<'a, IO, T> Future for TryLockNext<'a, IO, T>
+where
+    IO: AsyncRead + Unpin,
+{
+    type Output = (MutexGuard<'a, Inner<IO>>, Next<T>);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let Self { inner, take } = self.get_mut();
+
+        let mut guard = ready!(inner.lock().poll_unpin(cx));
+        if let Some(item) = take(&mut guard, cx.waker()) {
+            return Poll::Ready((guard, Next::Taken(item)));
+        }
+        let item = ready!(guard.stream.poll_next_unpin(cx));
+        Poll::Ready((guard, Next::Received(item)))
+    }
+}
+
+async fn dispatch<IO>(inner: &mut Inner<IO>, item: pkt::ClientRecv) -> Result<()>
+where
+    IO: AsyncWrite + Unpin,
+{
+    match item {
+        pkt::ClientRecv::HandleValueNotification(item) => {
+            let event = Event::Notification(
+                item.attribute_handle().clone(),
+                item.attribute_value().clone(),
+            );
+            inner.events.push_back(event);
+            if let Some(waker) = inner.events_waker.take() {
+                waker.wake();
+            }
+        }
+
+        pkt::ClientRecv::MultipleHandleValueNotification(item) => {
+            for (handle, value) in item {
+                inner.events.push_back(Event::Notification(handle, value));
+            }
+            if let Some(waker) = inner.events_waker.take() {
+                waker.wake();
+            }
+        }
+
+        pkt::ClientRecv::HandleValueIndication(item) => {
+            let event = Event::Indication(
+                item.attribute_handle().clone(),
+                item.attribute_value().clone(),
+            );
+            inner.events.push_back(event);
+            if let Some(waker) = inner.events_waker.take() {
+                waker.wake();
+            }
+            inner
+                .stream
+                .send(pkt::HandleValueConfirmation::new())
+                .await?;
+        }
+
+        item => {
+            if inner.response.is_some() {
+                log::warn!("unexpected response {:?}", item);
+            }
+            inner.response = Some(item);
+            if let Some(waker) = inner.response_waker.take() {
+                waker.wake();
+            }
+        }
+    }
+    Ok(())
+}
+
+struct EventsInner<IO> {
+    inner: Arc<Mutex<Inner<IO>>>,
+}
+
+impl<IO> EventsInner<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    async fn next(&mut self) -> Result<Option<Event>> {
+        loop {
+            let next = TryLockNext {
+                inner: &self.inner,
+                take: Inner::take_event,
+            };
+            let (mut guard, next) = next.await;
+            match next {
+                Next::Taken(event) => return Ok(Some(event)),
+                Next::Received(None) => return Ok(None),
+                Next::Received(Some(item)) => dispatch(&mut guard, item?).await?,
+            }
+        }
+    }
+}
+
+struct ClientInner<IO> {
+    inner: Arc<Mutex<Inner<IO>>>,
+    transaction: Mutex<()>,
+    timeout: Duration,
+}
+
+impl<IO> ClientInner<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    fn new(io: IO) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner::new(io))),
+            transaction: Mutex::new(()),
+            timeout: DEFAULT_TRANSACTION_TIMEOUT,
+        }
+    }
+
+    fn events(&self) -> EventsInner<IO> {
+        EventsInner {
+            inner: self.inner.clone(),
+        }
+    }
+
+    async fn send<C>(&self, command: C) -> Result<()>
+    where
+        C: pkt::ClientSend,
+    {
+        let mut guard = self.inner.lock().await;
+        guard.stream.send(command).await?;
+        Ok(())
+    }
+
+    /// Only one request may be outstanding at a time.
+    async fn request<R>(&self, request: R) -> Result<R::Response>
+    where
+        R: pkt::Request + pkt::ClientSend,
+        R::Response: TryFrom<pkt::ClientRecv, Error = pkt::ClientRecv>,
+    {
+        let _transaction = self.transaction.lock().await;
+        {
+            let mut guard = self.inner.lock().await;
+            guard.response = None;
+            guard.stream.send(request).await?;
+        }
+
+        let response = async {
+            loop {
+                let next = TryLockNext {
+                    inner: &self.inner,
+                    take: Inner::take_response,
+                };
+                let (mut guard, next) = next.await;
+                match next {
+                    Next::Taken(response) => return Ok(response),
+                    Next::Received(None) => return Err(Error::Closed),
+                    Next::Received(Some(item)) => dispatch(&mut guard, item?).await?,
+                }
+            }
+        };
+        let response = match tokio::time::timeout(self.timeout, response).await {
+            Ok(response) => response?,
+            Err(..) => {
+                log::warn!("no response in time");
+                self.inner.lock().await.stream.time_out();
+                return Err(Error::TransactionTimeout);
+            }
+        };
+
+        match response {
+            pkt::ClientRecv::ErrorResponse(err) => Err(Error::ErrorResponse(err)),
+            response => R::Response::try_from(response).map_err(Error::UnexpectedResponse),
+        }
+    }
+
+    async fn exchange_mtu(&self, client_rx_mtu: u16) -> Result<pkt::ExchangeMtuResponse> {
+        let response = self
+            .request(pkt::ExchangeMtuRequest::new(client_rx_mtu))
+            .await?;
+        let mut guard = self.inner.lock().await;
+        let mtu = client_rx_mtu.min(*response.server_rx_mtu());
+        guard.stream.set_txmtu(mtu as usize);
+        guard.stream.set_rxmtu(client_rx_mtu as usize);
+        Ok(response)
+    }
+}
+
+/// ATT Event Stream
+pub struct Events {
+    inner: EventsInner<BoxTransport>,
+}
+
+impl Events {
+    /// Next notification or indication. Indications are confirmed on receipt.
+    pub async fn next(&mut self) -> Result<Option<Event>> {
+        self.inner.next().await
+    }
+}
+
+/// ATT Protocol Client
+pub struct Client {
+    inner: ClientInner<BoxTransport>,
+    addr: crate::Address,
+}
+
+impl Client {
+    /// Connect to the ATT fixed channel of remote device.
+    pub async fn connect(address: &crate::Address) -> io::Result<Self> {
+        let sock = AttStream::connect(address).await?;
+        log::debug!("Connected.");
+        Ok(Self::new(sock, address.clone()))
+    }
+
+    /// Run over an arbitrary packet-preserving transport.
+    pub fn new<IO>(io: IO, address: crate::Address) -> Self
+    where
+        IO: Transport,
+    {
+        Self {
+            inner: ClientInner::new(Box::new(io)),
+            addr: address,
+        }
+    }
+
+    pub fn address(&self) -> &crate::Address {
+        &self.addr
+    }
+
+    pub fn events(&self) -> Events {
+        Events {
+            inner: self.inner.events(),
+        }
+    }
+
+    /// Time the server has to respond to a request. Defaults to 30 seconds.
+    ///
+    /// When it expires, the request fails with [`Error::TransactionTimeout`]
+    /// and no more PDUs are sent on the bearer.
+    pub fn set_transaction_timeout(&mut self, timeout: Duration) {
+        self.inner.timeout = timeout;
+    }
+
+    /// Exchange MTU Request
+    pub async fn exchange_mtu(&self, client_rx_mtu: u16) -> Result<pkt::ExchangeMtuResponse> {
+        self.inner.exchange_mtu(client_rx_mtu).await
+    }
+
+    /// Find Information Request
+    pub async fn find_information(
+        &self,
+        starting_handle: Handle,
+        ending_handle: Handle,
+    ) -> Result<pkt::FindInformationResponse> {
+        let request = pkt::FindInformationRequest::new(starting_handle, ending_handle);
+        self.inner.request(request).await
+    }
+
+    /// Find By Type Value Request
+    pub async fn find_by_type_value(
+        &self,
+        starting_handle: Handle,
+        ending_handle: Handle,
+        attribute_type: Uuid16,
+        attribute_value: &[u8],
+    ) -> Result<pkt::FindByTypeValueResponse> {
+        let request = pkt::FindByTypeValueRequest::new(
+            starting_handle,
+            ending_handle,
+            attribute_type,
+            attribute_value.into(),
+        );
+        self.inner.request(request).await
+    }
+
+    /// Read By Type Request
+    pub async fn read_by_type(
+        &self,
+        starting_handle: Handle,
+        ending_handle: Handle,
+        attribute_type: Uuid,
+    ) -> Result<pkt::ReadByTypeResponse> {
+        let request = pkt::ReadByTypeRequest::new(starting_handle, ending_handle, attribute_type);
+        self.inner.request(request).await
+    }
+
+    /// Read Request
+    pub async fn read(&self, attribute_handle: Handle) -> Result<pkt::ReadResponse> {
+        let request = pkt::ReadRequest::new(attribute_handle);
+        self.inner.request(request).await
+    }
+
+    /// Read Blob Request
+    pub async fn read_blob(
+        &self,
+        attribute_handle: Handle,
+        attribute_offset: u16,
+    ) -> Result<pkt::ReadBlobResponse> {
+        let request = pkt::ReadBlobRequest::new(attribute_handle, attribute_offset);
+        self.inner.request(request).await
+    }
+
+    /// Read Multiple Request
+    pub async fn read_multiple<I>(&self, set_of_handles: I) -> Result<pkt::ReadMultipleResponse>
+    where
+        I: IntoIterator<Item = Handle>,
+    {
+        let request = set_of_handles
+            .into_iter()
+            .collect::<pkt::ReadMultipleRequest>();
+        self.inner.request(request).await
+    }
+
+    /// Read Multiple Variable Length Request
+    pub async fn read_multiple_variable<I>(
+        &self,
+        set_of_handles: I,
+    ) -> Result<pkt::ReadMultipleVariableResponse>
+    where
+        I: IntoIterator<Item = Handle>,
+    {
+        let request = set_of_handles
+            .into_iter()
+            .collect::<pkt::ReadMultipleVariableRequest>();
+        self.inner.request(request).await
+    }
+
+    /// Read By Group Type Request
+    pub async fn read_by_group_type(
+        &self,
+        starting_handle: Handle,
+        ending_handle: Handle,
+        attribute_group_type: Uuid,
+    ) -> Result<pkt::ReadByGroupTypeResponse> {
+        let request =
+            pkt::ReadByGroupTypeRequest::new(starting_handle, ending_handle, attribute_group_type);
+        self.inner.request(request).await
+    }
+
+    /// Write Request
+    pub async fn write(
+        &self,
+        attribute_handle: Handle,
+        attribute_value: &[u8],
+    ) -> Result<pkt::WriteResponse> {
+        let request = pkt::WriteRequest::new(attribute_handle, attribute_value.into());
+        self.inner.request(request).await
+    }
+
+    /// Write Command
+    pub async fn write_command(
+        &self,
+        attribute_handle: Handle,
+        attribute_value: &[u8],
+    ) -> Result<()> {
+        let command = pkt::WriteCommand::new(attribute_handle, attribute_value.into());
+        self.inner.send(command).await
+    }
+
+    /// Signed Write Command
+    pub async fn signed_write_command(
+        &self,
+        attribute_handle: Handle,
+        attribute_value: &[u8],
+        authentication_signature: &[u8],
+    ) -> Result<()> {
+        let command = pkt::SignedWriteCommand::new(
+            attribute_handle,
+            attribute_value.into(),
+            authentication_signature.into(),
+        );
+        self.inner.send(command).await
+    }
+
+    /// Prepare Write Request
+    pub async fn prepare_write(
+        &self,
+        attribute_handle: Handle,
+        value_offset: u16,
+        part_attribute_value: &[u8],
+    ) -> Result<pkt::PrepareWriteResponse> {
+        let request = pkt::PrepareWriteRequest::new(
+            attribute_handle,
+            value_offset,
+            part_attribute_value.into(),
+        );
+        self.inner.request(request).await
+    }
+
+    /// Execute Write Request
+    ///
+    /// `flags`: `true` to write all prepared values, `false` to cancel.
+    pub async fn execute_write(&self, flags: bool) -> Result<pkt::ExecuteWriteResponse> {
+        let request = pkt::ExecuteWriteRequest::new(flags);
+        self.inner.request(request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_test::io::Builder;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_transaction_timeout() {
+        let stream = Builder::new()
+            .write(&[0x0A, 0x03, 0x00])
+            .wait(Duration::from_secs(60))
+            .build();
+        let mut client = ClientInner::new(stream);
+        client.timeout = Duration::from_secs(10);
+
+        let err = client
+            .request(pkt::ReadRequest::new(0x0003.into()))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::TransactionTimeout));
+        let err = client
+            .request(pkt::ReadRequest::new(0x0003.into()))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::TransactionTimeout));
+    }
+
+    #[tokio::test]
+    async fn test_request() {
+        let stream = Builder::new()
+            .write(&[0x02, 0x00, 0x01])
+            .read(&[0x03, 0x40, 0x00])
+            .write(&[0x0A, 0x03, 0x00])
+            .read(&[0x0B, 0x6F, 0x6B])
+            .build();
+        let client = ClientInner::new(stream);
+
+        let response = client.exchange_mtu(0x0100).await.unwrap();
+        assert_eq!(*response.server_rx_mtu(), 0x0040);
+        assert_eq!(client.inner.lock().await.stream.txmtu(), 0x0040);
+
+        let response = client
+            .request(pkt::ReadRequest::new(0x0003.into()))
+            .await
+            .unwrap();
+        assert_eq!(&**response.attribute_value(), b"ok");
+    }
+
+    #[tokio::test]
+    async fn test_exchange_mtu() {
+        let stream = Builder::new()
+            .write(&[0x02, 0x40, 0x00])
+            .read(&[0x03, 0x00, 0x01])
+            .build();
+        let client = ClientInner::new(stream);
+
+        client.exchange_mtu(0x0040).await.unwrap();
+        let guard = client.inner.lock().await;
+        assert_eq!(guard.stream.txmtu(), 0x0040);
+    }
+
+    #[tokio::test]
+    async fn test_read_multiple_variable() {
+        let stream = Builder::new()
+            .write(&[0x20, 0x03, 0x00, 0x05, 0x00])
+            .read(&[0x21, 0x02, 0x00, 0x6F, 0x6B, 0x05, 0x00, 0x61, 0x62])
+            .build();
+        let client = ClientInner::new(stream);
+
+        let request = vec![Handle::new(0x0003), Handle::new(0x0005)]
+            .into_iter()
+            .collect::<pkt::ReadMultipleVariableRequest>();
+        let response = client.request(request).await.unwrap();
+        let values = response.into_iter().collect::<Vec<_>>();
+        assert_eq!(values, vec![b"ok".as_ref().into(), b"ab".as_ref().into()]);
+    }
+
+    #[tokio::test]
+    async fn test_error_response() {
+        let stream = Builder::new()
+            .write(&[0x0A, 0x03, 0x00])
+            .read(&[0x01, 0x0A, 0x03, 0x00, 0x02])
+            .build();
+        let client = ClientInner::new(stream);
+
+        let err = client
+            .request(pkt::ReadRequest::new(0x0003.into()))
+            .await
+            .unwrap_err();
+        match err {
+            Error::ErrorResponse(err) => {
+                assert_eq!(err.request_opcode_in_error(), &pkt::OpCode::ReadRequest);
+                assert_eq!(err.attribute_handle_in_error(), &Handle::new(0x0003));
+                assert_eq!(err.error_code(), &pkt::ErrorCode::ReadNotPermitted);
+            }
+            err => panic!("{:?}", err),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_events() {
+        let stream = Builder::new()
+            .write(&[0x0A, 0x03, 0x00])
+            .read(&[0x1B, 0x01, 0x00, 0x01])
+            .read(&[0x1D, 0x02, 0x00, 0x02])
+            .write(&[0x1E])
+            .read(&[0x0B, 0x6F, 0x6B])
+            .build();
+        let client = ClientInner::new(stream);
+        let mut events = client.events();
+
+        let response = client
+            .request(pkt::ReadRequest::new(0x0003.into()))
+            .await
+            .unwrap();
+        assert_eq!(&**response.attribute_value(), b"ok");
+
+        let event = events.next().await.unwrap();
+        assert_eq!(
+            event,
+            Some(Event::Notification(0x0001.into(), [0x01].into()))
+        );
+        let event = events.next().await.unwrap();
+        assert_eq!(event, Some(Event::Indication(0x0002.into(), [0x02].into())));
+        let event = events.next().await.unwrap();
+        assert_eq!(event, None);
+    }
+}
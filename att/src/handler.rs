@@ -1,3 +1,7 @@
+use std::future::Future;
+
+use futures_util::future;
+
 use crate::packet as pkt;
 use crate::Handle;
 
@@ -84,7 +88,24 @@ pub trait Handler {
         item: &pkt::ReadMultipleRequest,
     ) -> Result<pkt::ReadMultipleResponse, ErrorResponse> {
         Err(ErrorResponse::new(
-            item.into_iter().next().unwrap().clone(),
+            item.into_iter()
+                .next()
+                .cloned()
+                .unwrap_or_else(|| 0x0000.into()),
+            pkt::ErrorCode::RequestNotSupported,
+        ))
+    }
+
+    /// handle `read multiple variable length request`
+    fn handle_read_multiple_variable_request(
+        &mut self,
+        item: &pkt::ReadMultipleVariableRequest,
+    ) -> Result<pkt::ReadMultipleVariableResponse, ErrorResponse> {
+        Err(ErrorResponse::new(
+            item.into_iter()
+                .next()
+                .cloned()
+                .unwrap_or_else(|| 0x0000.into()),
             pkt::ErrorCode::RequestNotSupported,
         ))
     }
@@ -145,4 +166,267 @@ pub trait Handler {
     fn handle_signed_write_command(&mut self, item: &pkt::SignedWriteCommand) {
         // nop
     }
+
+    /// handle `handle value confirmation` of the indication of `handle`
+    #[allow(unused_variables)]
+    fn handle_confirmation(&mut self, handle: &Handle) {
+        // nop
+    }
+}
+
+/// ATT Protocol Handler which may await while handling.
+///
+/// Every [`Handler`] is an `AsyncHandler`.
+/// Confirmations are still processed while a handler is pending, and
+/// passed to [`AsyncHandler::handle_confirmation`] once it completes.
+pub trait AsyncHandler {
+    /// handle `exchange mtu request`
+    fn handle_exchange_mtu_request(
+        &mut self,
+        item: &pkt::ExchangeMtuRequest,
+    ) -> impl Future<Output = Result<pkt::ExchangeMtuResponse, ErrorResponse>> {
+        future::ready(Handler::handle_exchange_mtu_request(&mut Unsupported, item))
+    }
+
+    /// handle `find information request`
+    fn handle_find_information_request(
+        &mut self,
+        item: &pkt::FindInformationRequest,
+    ) -> impl Future<Output = Result<pkt::FindInformationResponse, ErrorResponse>> {
+        future::ready(Handler::handle_find_information_request(
+            &mut Unsupported,
+            item,
+        ))
+    }
+
+    /// handle `find by type value request`
+    fn handle_find_by_type_value_request(
+        &mut self,
+        item: &pkt::FindByTypeValueRequest,
+    ) -> impl Future<Output = Result<pkt::FindByTypeValueResponse, ErrorResponse>> {
+        future::ready(Handler::handle_find_by_type_value_request(
+            &mut Unsupported,
+            item,
+        ))
+    }
+
+    /// handle `read by type request`
+    fn handle_read_by_type_request(
+        &mut self,
+        item: &pkt::ReadByTypeRequest,
+    ) -> impl Future<Output = Result<pkt::ReadByTypeResponse, ErrorResponse>> {
+        future::ready(Handler::handle_read_by_type_request(&mut Unsupported, item))
+    }
+
+    /// handle `read request`
+    fn handle_read_request(
+        &mut self,
+        item: &pkt::ReadRequest,
+    ) -> impl Future<Output = Result<pkt::ReadResponse, ErrorResponse>> {
+        future::ready(Handler::handle_read_request(&mut Unsupported, item))
+    }
+
+    /// handle `read blob request`
+    fn handle_read_blob_request(
+        &mut self,
+        item: &pkt::ReadBlobRequest,
+    ) -> impl Future<Output = Result<pkt::ReadBlobResponse, ErrorResponse>> {
+        future::ready(Handler::handle_read_blob_request(&mut Unsupported, item))
+    }
+
+    /// handle `read multiple request`
+    fn handle_read_multiple_request(
+        &mut self,
+        item: &pkt::ReadMultipleRequest,
+    ) -> impl Future<Output = Result<pkt::ReadMultipleResponse, ErrorResponse>> {
+        future::ready(Handler::handle_read_multiple_request(
+            &mut Unsupported,
+            item,
+        ))
+    }
+
+    /// handle `read multiple variable length request`
+    fn handle_read_multiple_variable_request(
+        &mut self,
+        item: &pkt::ReadMultipleVariableRequest,
+    ) -> impl Future<Output = Result<pkt::ReadMultipleVariableResponse, ErrorResponse>> {
+        future::ready(Handler::handle_read_multiple_variable_request(
+            &mut Unsupported,
+            item,
+        ))
+    }
+
+    /// handle `read by group type request`
+    fn handle_read_by_group_type_request(
+        &mut self,
+        item: &pkt::ReadByGroupTypeRequest,
+    ) -> impl Future<Output = Result<pkt::ReadByGroupTypeResponse, ErrorResponse>> {
+        future::ready(Handler::handle_read_by_group_type_request(
+            &mut Unsupported,
+            item,
+        ))
+    }
+
+    /// handle `write request`
+    fn handle_write_request(
+        &mut self,
+        item: &pkt::WriteRequest,
+    ) -> impl Future<Output = Result<pkt::WriteResponse, ErrorResponse>> {
+        future::ready(Handler::handle_write_request(&mut Unsupported, item))
+    }
+
+    /// handle `write command`
+    fn handle_write_command(&mut self, item: &pkt::WriteCommand) -> impl Future<Output = ()> {
+        Handler::handle_write_command(&mut Unsupported, item);
+        future::ready(())
+    }
+
+    /// handle `prepare write request`
+    fn handle_prepare_write_request(
+        &mut self,
+        item: &pkt::PrepareWriteRequest,
+    ) -> impl Future<Output = Result<pkt::PrepareWriteResponse, ErrorResponse>> {
+        future::ready(Handler::handle_prepare_write_request(
+            &mut Unsupported,
+            item,
+        ))
+    }
+
+    /// handle `execute write request`
+    fn handle_execute_write_request(
+        &mut self,
+        item: &pkt::ExecuteWriteRequest,
+    ) -> impl Future<Output = Result<pkt::ExecuteWriteResponse, ErrorResponse>> {
+        future::ready(Handler::handle_execute_write_request(
+            &mut Unsupported,
+            item,
+        ))
+    }
+
+    /// handle `signed write command`
+    fn handle_signed_write_command(
+        &mut self,
+        item: &pkt::SignedWriteCommand,
+    ) -> impl Future<Output = ()> {
+        Handler::handle_signed_write_command(&mut Unsupported, item);
+        future::ready(())
+    }
+
+    /// handle `handle value confirmation` of the indication of `handle`
+    fn handle_confirmation(&mut self, handle: &Handle) -> impl Future<Output = ()> {
+        Handler::handle_confirmation(&mut Unsupported, handle);
+        future::ready(())
+    }
+}
+
+/// Defaults of [`Handler`].
+struct Unsupported;
+
+impl Handler for Unsupported {}
+
+impl<H> AsyncHandler for H
+where
+    H: Handler,
+{
+    fn handle_exchange_mtu_request(
+        &mut self,
+        item: &pkt::ExchangeMtuRequest,
+    ) -> impl Future<Output = Result<pkt::ExchangeMtuResponse, ErrorResponse>> {
+        future::ready(Handler::handle_exchange_mtu_request(self, item))
+    }
+
+    fn handle_find_information_request(
+        &mut self,
+        item: &pkt::FindInformationRequest,
+    ) -> impl Future<Output = Result<pkt::FindInformationResponse, ErrorResponse>> {
+        future::ready(Handler::handle_find_information_request(self, item))
+    }
+
+    fn handle_find_by_type_value_request(
+        &mut self,
+        item: &pkt::FindByTypeValueRequest,
+    ) -> impl Future<Output = Result<pkt::FindByTypeValueResponse, ErrorResponse>> {
+        future::ready(Handler::handle_find_by_type_value_request(self, item))
+    }
+
+    fn handle_read_by_type_request(
+        &mut self,
+        item: &pkt::ReadByTypeRequest,
+    ) -> impl Future<Output = Result<pkt::ReadByTypeResponse, ErrorResponse>> {
+        future::ready(Handler::handle_read_by_type_request(self, item))
+    }
+
+    fn handle_read_request(
+        &mut self,
+        item: &pkt::ReadRequest,
+    ) -> impl Future<Output = Result<pkt::ReadResponse, ErrorResponse>> {
+        future::ready(Handler::handle_read_request(self, item))
+    }
+
+    fn handle_read_blob_request(
+        &mut self,
+        item: &pkt::ReadBlobRequest,
+    ) -> impl Future<Output = Result<pkt::ReadBlobResponse, ErrorResponse>> {
+        future::ready(Handler::handle_read_blob_request(self, item))
+    }
+
+    fn handle_read_multiple_request(
+        &mut self,
+        item: &pkt::ReadMultipleRequest,
+    ) -> impl Future<Output = Result<pkt::ReadMultipleResponse, ErrorResponse>> {
+        future::ready(Handler::handle_read_multiple_request(self, item))
+    }
+
+    fn handle_read_multiple_variable_request(
+        &mut self,
+        item: &pkt::ReadMultipleVariableRequest,
+    ) -> impl Future<Output = Result<pkt::ReadMultipleVariableResponse, ErrorResponse>> {
+        future::ready(Handler::handle_read_multiple_variable_request(self, item))
+    }
+
+    fn handle_read_by_group_type_request(
+        &mut self,
+        item: &pkt::ReadByGroupTypeRequest,
+    ) -> impl Future<Output = Result<pkt::ReadByGroupTypeResponse, ErrorResponse>> {
+        future::ready(Handler::handle_read_by_group_type_request(self, item))
+    }
+
+    fn handle_write_request(
+        &mut self,
+        item: &pkt::WriteRequest,
+    ) -> impl Future<Output = Result<pkt::WriteResponse, ErrorResponse>> {
+        future::ready(Handler::handle_write_request(self, item))
+    }
+
+    fn handle_write_command(&mut self, item: &pkt::WriteCommand) -> impl Future<Output = ()> {
+        Handler::handle_write_command(self, item);
+        future::ready(())
+    }
+
+    fn handle_prepare_write_request(
+        &mut self,
+        item: &pkt::PrepareWriteRequest,
+    ) -> impl Future<Output = Result<pkt::PrepareWriteResponse, ErrorResponse>> {
+        future::ready(Handler::handle_prepare_write_request(self, item))
+    }
+
+    fn handle_execute_write_request(
+        &mut self,
+        item: &pkt::ExecuteWriteRequest,
+    ) -> impl Future<Output = Result<pkt::ExecuteWriteResponse, ErrorResponse>> {
+        future::ready(Handler::handle_execute_write_request(self, item))
+    }
+
+    fn handle_signed_write_command(
+        &mut self,
+        item: &pkt::SignedWriteCommand,
+    ) -> impl Future<Output = ()> {
+        Handler::handle_signed_write_command(self, item);
+        future::ready(())
+    }
+
+    fn handle_confirmation(&mut self, handle: &Handle) -> impl Future<Output = ()> {
+        Handler::handle_confirmation(self, handle);
+        future::ready(())
+    }
 }
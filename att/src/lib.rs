@@ -6,7 +6,7 @@
 //!
 //! ## Example
 //!
-//! ```
+//! ```no_run
 //! use att::packet as pkt;
 //! use att::server::*;
 //!
@@ -61,6 +61,7 @@
 //!   ([LICENSE-APACHE](LICENSE-APACHE) or http://www.apache.org/licenses/LICENSE-2.0)
 //! * MIT license
 //!   ([LICENSE-MIT](LICENSE-MIT) or http://opensource.org/licenses/MIT)
+//!
 //! at your option.
 //!
 //! ## Contribution
@@ -71,19 +72,26 @@
 
 pub use crate::uuid::Uuid;
 pub use bdaddr::Address;
+pub use client::Client;
 pub use handle::Handle;
-pub use handler::{ErrorResponse, Handler};
+pub use handler::{AsyncHandler, ErrorResponse, Handler};
+pub use security::{Security, SecurityQuery};
 pub use server::Server;
+pub use transport::Transport;
 
 #[macro_use]
 mod macros;
 
+pub mod client;
 mod handle;
 mod handler;
 pub mod packet;
+mod security;
 pub mod server;
 mod size;
 mod sock;
+mod stream;
+mod transport;
 
 #[macro_use]
 pub mod uuid;
@@ -186,6 +186,85 @@ impl Unpack for SetOfHandles {
     }
 }
 
+/// Length Value Tuple List. The last value may be truncated to fit the MTU,
+/// while its length still holds the full length.
+#[derive(Debug)]
+struct LengthValueTupleList(Vec<(u16, Box<[u8]>)>);
+
+impl Pack for LengthValueTupleList {
+    fn pack<W>(self, write: &mut W) -> PackResult<()>
+    where
+        W: io::Write,
+    {
+        for (len, value) in self.0 {
+            len.pack(write)?;
+            write.write_all(&value)?;
+        }
+        Ok(())
+    }
+}
+
+impl Unpack for LengthValueTupleList {
+    fn unpack<R>(read: &mut R) -> PackResult<Self>
+    where
+        R: io::Read,
+    {
+        use io::Read;
+
+        let mut v = vec![];
+        loop {
+            let len = match u16::unpack(read) {
+                Ok(len) => len,
+                Err(PackError::NoDataAvailable) => break,
+                Err(err) => return Err(err),
+            };
+            let mut value = vec![];
+            read.by_ref().take(len as u64).read_to_end(&mut value)?;
+            v.push((len, value.into()));
+        }
+        Ok(Self(v))
+    }
+}
+
+/// Handle Length Value Tuple List
+#[derive(Debug)]
+struct HandleLengthValueTupleList(Vec<(Handle, Box<[u8]>)>);
+
+impl Pack for HandleLengthValueTupleList {
+    fn pack<W>(self, write: &mut W) -> PackResult<()>
+    where
+        W: io::Write,
+    {
+        for (handle, value) in self.0 {
+            handle.pack(write)?;
+            (value.len() as u16).pack(write)?;
+            write.write_all(&value)?;
+        }
+        Ok(())
+    }
+}
+
+impl Unpack for HandleLengthValueTupleList {
+    fn unpack<R>(read: &mut R) -> PackResult<Self>
+    where
+        R: io::Read,
+    {
+        let mut v = vec![];
+        loop {
+            let handle = match Handle::unpack(read) {
+                Ok(handle) => handle,
+                Err(PackError::NoDataAvailable) => break,
+                Err(err) => return Err(err),
+            };
+            let len = u16::unpack(read)?;
+            let mut value = vec![0; len as usize];
+            read.read_exact(&mut value)?;
+            v.push((handle, value.into()));
+        }
+        Ok(Self(v))
+    }
+}
+
 trait AttributeData: Pack + Unpack {
     fn format(&self) -> NonZeroU8;
     fn len(val: NonZeroU8) -> PackResult<NonZeroUsize> {
@@ -345,6 +424,7 @@ macro_rules! packet {
 packet! {
     /// Error Response
     #[derive(Debug, New, Getters)]
+    #[get = "pub"]
     pub struct ErrorResponse: 0x01 {
         request_opcode_in_error: OpCode,
         attribute_handle_in_error: Handle,
@@ -446,7 +526,8 @@ packet! {
     }
 
     /// Read Multiple Response
-    #[derive(Debug, New)]
+    #[derive(Debug, New, Getters)]
+    #[get = "pub"]
     pub struct ReadMultipleResponse: 0x0F {
         set_of_values: Box<[u8]>, // FIXME
     }
@@ -547,40 +628,66 @@ packet! {
     pub struct HandleValueConfirmation: 0x1E {
     }
 
+    /// Read Multiple Variable Length Request
+    #[derive(Debug)]
+    pub struct ReadMultipleVariableRequest: 0x20 {
+        set_of_handles: SetOfHandles,
+    }
+
+    /// Read Multiple Variable Length Response
+    #[derive(Debug)]
+    pub struct ReadMultipleVariableResponse: 0x21 {
+        values: LengthValueTupleList,
+    }
+
+    /// Multiple Handle Value Notification
+    #[derive(Debug)]
+    pub struct MultipleHandleValueNotification: 0x23 {
+        values: HandleLengthValueTupleList,
+    }
+
 }
 
-macro_rules! device_recv {
+macro_rules! recv {
     (
-        $( $ident:ident, )*
+        $(#[$attrs:meta])*
+        $vis:vis enum $name:ident {
+            $( $ident:ident, )*
+        }
     ) => {
+        $(#[$attrs])*
         #[derive(Debug)]
-        pub enum DeviceRecv {
+        $vis enum $name {
             $( $ident($ident), )*
         }
 
-        trait AssertUnpack: Packet + Unpack + Sized {}
-
         $(
-            impl AssertUnpack for $ident {}
-
-            impl From<$ident> for DeviceRecv {
+            impl From<$ident> for $name {
                 fn from(v: $ident) -> Self {
                     Self::$ident(v)
                 }
             }
 
-            impl TryFrom<DeviceRecv> for $ident {
-                type Error = DeviceRecv;
-                fn try_from(v: DeviceRecv) -> std::result::Result<Self, Self::Error> {
+            impl TryFrom<$name> for $ident {
+                type Error = $name;
+                fn try_from(v: $name) -> std::result::Result<Self, Self::Error> {
                     match v {
-                        DeviceRecv::$ident(v) => Ok(v),
+                        $name::$ident(v) => Ok(v),
                         v => Err(v),
                     }
                 }
             }
         )*
 
-        impl Unpack for DeviceRecv {
+        impl $name {
+            /// Whether `opcode` is one of the packets.
+            #[allow(dead_code)]
+            pub(crate) fn supports(opcode: u8) -> bool {
+                $( opcode == OpCode::$ident as u8 || )* false
+            }
+        }
+
+        impl Unpack for $name {
             fn unpack<R>(read: &mut R) -> PackResult<Self> where R: io::Read {
                 Ok(match OpCode::unpack(read)? {
                     $( OpCode::$ident => $ident::unpack(read)?.into(), )*
@@ -591,32 +698,59 @@ macro_rules! device_recv {
     }
 }
 
-macro_rules! device_send {
-    ( $( $ident:ident, )* ) => {
+macro_rules! send {
+    ( $trait:ident: $( $ident:ident, )* ) => {
         $(
-            impl DeviceSend for $ident { }
+            impl $trait for $ident { }
         )*
     }
 }
 
-device_recv![
-    ExchangeMtuRequest,
-    FindInformationRequest,
-    FindByTypeValueRequest,
-    ReadByTypeRequest,
-    ReadRequest,
-    ReadBlobRequest,
-    ReadMultipleRequest,
-    ReadByGroupTypeRequest,
-    WriteRequest,
-    PrepareWriteRequest,
-    ExecuteWriteRequest,
-    WriteCommand,
-    SignedWriteCommand,
-    HandleValueConfirmation,
-];
+recv! {
+    /// Packets received by the device (server).
+    pub enum DeviceRecv {
+        ExchangeMtuRequest,
+        FindInformationRequest,
+        FindByTypeValueRequest,
+        ReadByTypeRequest,
+        ReadRequest,
+        ReadBlobRequest,
+        ReadMultipleRequest,
+        ReadByGroupTypeRequest,
+        WriteRequest,
+        PrepareWriteRequest,
+        ExecuteWriteRequest,
+        WriteCommand,
+        SignedWriteCommand,
+        HandleValueConfirmation,
+        ReadMultipleVariableRequest,
+    }
+}
+
+recv! {
+    /// Packets received by the client.
+    pub enum ClientRecv {
+        ErrorResponse,
+        ExchangeMtuResponse,
+        FindInformationResponse,
+        FindByTypeValueResponse,
+        ReadByTypeResponse,
+        ReadResponse,
+        ReadBlobResponse,
+        ReadMultipleResponse,
+        ReadByGroupTypeResponse,
+        WriteResponse,
+        PrepareWriteResponse,
+        ExecuteWriteResponse,
+        HandleValueNotification,
+        HandleValueIndication,
+        ReadMultipleVariableResponse,
+        MultipleHandleValueNotification,
+    }
+}
 
-device_send![
+send![
+    DeviceSend:
     ErrorResponse,
     ExchangeMtuResponse,
     FindInformationResponse,
@@ -631,8 +765,30 @@ device_send![
     ExecuteWriteResponse,
     HandleValueNotification,
     HandleValueIndication,
+    ReadMultipleVariableResponse,
+    MultipleHandleValueNotification,
+];
+
+send![
+    ClientSend:
+    ExchangeMtuRequest,
+    FindInformationRequest,
+    FindByTypeValueRequest,
+    ReadByTypeRequest,
+    ReadRequest,
+    ReadBlobRequest,
+    ReadMultipleRequest,
+    ReadByGroupTypeRequest,
+    WriteRequest,
+    PrepareWriteRequest,
+    ExecuteWriteRequest,
+    WriteCommand,
+    SignedWriteCommand,
+    HandleValueConfirmation,
+    ReadMultipleVariableRequest,
 ];
 
+/// Packet sent by the device (server).
 pub trait DeviceSend: Packet + Pack + Sized {
     fn pack_with_code<W>(self, write: &mut W) -> PackResult<()>
     where
@@ -644,6 +800,18 @@ pub trait DeviceSend: Packet + Pack + Sized {
     }
 }
 
+/// Packet sent by the client.
+pub trait ClientSend: Packet + Pack + Sized {
+    fn pack_with_code<W>(self, write: &mut W) -> PackResult<()>
+    where
+        W: io::Write,
+    {
+        Self::OPCODE.pack(write)?;
+        self.pack(write)?;
+        Ok(())
+    }
+}
+
 /// ATT Request
 pub trait Request: Packet + TryFrom<DeviceRecv> {
     type Response: Response;
@@ -770,6 +938,29 @@ impl Response for ReadMultipleResponse {
     }
 }
 
+impl Request for ReadMultipleVariableRequest {
+    type Response = ReadMultipleVariableResponse;
+}
+impl Response for ReadMultipleVariableResponse {
+    fn truncate(&mut self, mtu: usize) {
+        let mut remaining = mtu - 1;
+        let mut len = 0;
+        for (_, value) in &mut self.values.0 {
+            if remaining < 2 {
+                break;
+            }
+            remaining -= 2;
+            len += 1;
+            if value.len() > remaining {
+                *value = (&value[..remaining]).into();
+                break;
+            }
+            remaining -= value.len();
+        }
+        self.values.0.truncate(len);
+    }
+}
+
 impl Request for ReadByGroupTypeRequest {
     type Response = ReadByGroupTypeResponse;
 }
@@ -818,6 +1009,8 @@ impl Response for ExecuteWriteResponse {
 
 impl Notification for HandleValueNotification {}
 
+impl Notification for MultipleHandleValueNotification {}
+
 impl Indication for HandleValueIndication {
     type Confirmation = HandleValueConfirmation;
 }
@@ -73,3 +73,105 @@ impl<'a> IntoIterator for &'a ReadMultipleRequest {
         self.set_of_handles.into_iter()
     }
 }
+
+impl FromIterator<Handle> for ReadMultipleRequest {
+    fn from_iter<T: IntoIterator<Item = Handle>>(iter: T) -> Self {
+        Self {
+            set_of_handles: SetOfHandles(iter.into_iter().collect()),
+        }
+    }
+}
+
+impl IntoIterator for ReadMultipleVariableRequest {
+    type Item = Handle;
+    type IntoIter = std::vec::IntoIter<Self::Item>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.set_of_handles.0.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a ReadMultipleVariableRequest {
+    type Item = &'a Handle;
+    type IntoIter = std::slice::Iter<'a, Handle>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.set_of_handles.into_iter()
+    }
+}
+
+impl FromIterator<Handle> for ReadMultipleVariableRequest {
+    fn from_iter<T: IntoIterator<Item = Handle>>(iter: T) -> Self {
+        Self {
+            set_of_handles: SetOfHandles(iter.into_iter().collect()),
+        }
+    }
+}
+
+impl FromIterator<Box<[u8]>> for ReadMultipleVariableResponse {
+    fn from_iter<T: IntoIterator<Item = Box<[u8]>>>(iter: T) -> Self {
+        Self {
+            values: LengthValueTupleList(
+                iter.into_iter()
+                    .map(|value| (value.len() as u16, value))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// Yields each value. The last one may be truncated to fit the MTU.
+impl IntoIterator for ReadMultipleVariableResponse {
+    type Item = Box<[u8]>;
+    type IntoIter =
+        std::iter::Map<std::vec::IntoIter<(u16, Box<[u8]>)>, fn((u16, Box<[u8]>)) -> Box<[u8]>>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.values.0.into_iter().map(|(_, value)| value)
+    }
+}
+
+impl FromIterator<(Handle, Box<[u8]>)> for MultipleHandleValueNotification {
+    fn from_iter<T: IntoIterator<Item = (Handle, Box<[u8]>)>>(iter: T) -> Self {
+        Self {
+            values: HandleLengthValueTupleList(iter.into_iter().collect()),
+        }
+    }
+}
+
+impl IntoIterator for MultipleHandleValueNotification {
+    type Item = (Handle, Box<[u8]>);
+    type IntoIter = std::vec::IntoIter<Self::Item>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.values.0.into_iter()
+    }
+}
+
+impl IntoIterator for FindInformationResponse {
+    type Item = (Handle, Uuid);
+    type IntoIter = std::vec::IntoIter<Self::Item>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.values.0.into_iter()
+    }
+}
+
+impl IntoIterator for FindByTypeValueResponse {
+    type Item = (Handle, Handle);
+    type IntoIter = std::vec::IntoIter<Self::Item>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.values.0.into_iter()
+    }
+}
+
+impl IntoIterator for ReadByTypeResponse {
+    type Item = (Handle, Box<[u8]>);
+    type IntoIter = std::vec::IntoIter<Self::Item>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.values.0.into_iter()
+    }
+}
+
+impl IntoIterator for ReadByGroupTypeResponse {
+    type Item = (Handle, Handle, Box<[u8]>);
+    type IntoIter = std::vec::IntoIter<Self::Item>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.values.0.into_iter()
+    }
+}
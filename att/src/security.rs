@@ -0,0 +1,83 @@
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+/// Security of the link a bearer runs on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Security {
+    /// The link is encrypted.
+    pub encrypted: bool,
+    /// The encryption key was generated with MITM protection.
+    pub authenticated: bool,
+    /// Encryption key size in octets. `0` if unknown or not encrypted.
+    pub key_size: u8,
+}
+
+impl Security {
+    /// Map a `BT_SECURITY` level and key size.
+    pub(crate) fn from_level(level: u8, key_size: u8) -> Self {
+        let encrypted = level >= crate::sock::BT_SECURITY_MEDIUM;
+        Self {
+            encrypted,
+            authenticated: level >= crate::sock::BT_SECURITY_HIGH,
+            key_size: if encrypted { key_size } else { 0 },
+        }
+    }
+}
+
+/// Queries the current [`Security`] of a link.
+///
+/// Links without a way to tell report no security.
+#[derive(Clone, Default)]
+pub struct SecurityQuery(Option<Arc<dyn Fn() -> io::Result<Security> + Send + Sync>>);
+
+impl SecurityQuery {
+    pub fn new<F>(query: F) -> Self
+    where
+        F: Fn() -> io::Result<Security> + Send + Sync + 'static,
+    {
+        Self(Some(Arc::new(query)))
+    }
+
+    pub fn security(&self) -> io::Result<Security> {
+        match &self.0 {
+            Some(query) => query(),
+            None => Ok(Security::default()),
+        }
+    }
+}
+
+impl fmt::Debug for SecurityQuery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SecurityQuery")
+            .field(&self.0.is_some())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_level() {
+        assert_eq!(Security::from_level(1, 16), Security::default());
+        assert_eq!(
+            Security::from_level(2, 7),
+            Security {
+                encrypted: true,
+                authenticated: false,
+                key_size: 7,
+            }
+        );
+        assert_eq!(
+            Security::from_level(3, 16),
+            Security {
+                encrypted: true,
+                authenticated: true,
+                key_size: 16,
+            }
+        );
+        assert!(Security::from_level(4, 16).authenticated);
+    }
+}
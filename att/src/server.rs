@@ -1,160 +1,111 @@
+use std::collections::VecDeque;
 use std::future::Future;
 use std::io;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU16, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll, Waker};
+use std::time::Duration;
 
 use futures_channel::oneshot;
 use futures_core::ready;
 use futures_core::stream::Stream;
 use futures_sink::Sink;
-use futures_util::future::FutureExt;
+use futures_util::future::{self, Either, FutureExt};
 use futures_util::lock::{Mutex, MutexGuard};
+use futures_util::pin_mut;
 use futures_util::sink::SinkExt;
 use futures_util::stream::{StreamExt, TryStreamExt};
-use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{AcquireError, OwnedSemaphorePermit, Semaphore};
+use tokio::time::Sleep;
 
 use crate::packet as pkt;
-use crate::sock::{AttListener, AttStream};
-use crate::Handle;
-pub use crate::{ErrorResponse, Handler};
-use pkt::pack::{self, Unpack};
+use crate::packet::pack::{self, Pack};
+use crate::sock::AttListener;
+pub use crate::stream::Error;
+use crate::stream::{
+    Outgoing, PacketStream, Result, DEFAULT_MAX_MTU, DEFAULT_MTU, DEFAULT_TRANSACTION_TIMEOUT,
+};
+use crate::transport::BoxTransport;
+pub use crate::{AsyncHandler, ErrorResponse, Handler};
+use crate::{Handle, SecurityQuery, Transport};
 
-const DEFAULT_MTU: usize = 23;
-
-#[derive(Debug, thiserror::Error)]
-pub enum Error {
-    #[error(transparent)]
-    Io(#[from] io::Error),
-
-    #[error(transparent)]
-    Pack(#[from] pack::Error),
+struct Inner<IO> {
+    stream: PacketStream<IO>,
+    await_confirmation: Option<Outstanding>,
+    /// Run loop to wake when an indication is sent.
+    run_waker: Option<Waker>,
+    // TODO used notification / indication handles
 }
 
-type Result<R> = std::result::Result<R, Error>;
-
-struct PacketStream<R> {
-    inner: R,
-    rxbuf: Box<[u8]>,
-    txbuf: Box<[u8]>,
-    txlen: usize,
-    txwaker: Vec<Waker>,
+/// Indication sent and not confirmed yet.
+///
+/// Holds the bearer's only indication permit, so the next indication waits
+/// for the confirmation even if the writer of this one is gone.
+struct Outstanding {
+    handle: Handle,
+    /// Fails with [`Error::TransactionTimeout`] when not confirmed in time.
+    confirmed: oneshot::Sender<Result<()>>,
+    /// Watched by the run loop only, as the writer may be dropped while waiting.
+    expires: Pin<Box<Sleep>>,
+    _permit: OwnedSemaphorePermit,
 }
 
-impl<R> PacketStream<R> {
-    fn new(inner: R) -> Self {
+impl<IO> Inner<IO> {
+    fn new(io: IO) -> Self {
         Self {
-            inner,
-            rxbuf: [0; DEFAULT_MTU].into(),
-            txbuf: [0; DEFAULT_MTU].into(),
-            txlen: 0,
-            txwaker: vec![],
-        }
-    }
-
-    fn txmtu(&self) -> usize {
-        self.txbuf.len()
-    }
-
-    fn set_txmtu(&mut self, mtu: usize) {
-        let mut buf = vec![0; mtu];
-        let len = mtu.min(self.txbuf.len());
-        (&mut buf[..len]).copy_from_slice(&self.txbuf[..len]);
-        self.txbuf = buf.into();
-    }
-
-    fn set_rxmtu(&mut self, mtu: usize) {
-        let mut buf = vec![0; mtu];
-        let len = mtu.min(self.rxbuf.len());
-        (&mut buf[..len]).copy_from_slice(&self.rxbuf[..len]);
-        self.rxbuf = buf.into();
-    }
-}
-
-impl<R> Stream for PacketStream<R>
-where
-    R: AsyncRead + Unpin,
-{
-    type Item = Result<pkt::DeviceRecv>;
-
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let Self { inner, rxbuf, .. } = self.get_mut();
-
-        let mut buf = ReadBuf::new(rxbuf);
-        ready!(Pin::new(inner).poll_read(cx, &mut buf))?;
-        let mut filled = buf.filled();
-        if filled.is_empty() {
-            Poll::Ready(None)
-        } else {
-            let item = Unpack::unpack(&mut filled)?;
-            log::trace!("packet recv {:?}", item);
-            Poll::Ready(Some(Ok(item)))
+            stream: PacketStream::new(io),
+            await_confirmation: Default::default(),
+            run_waker: None,
         }
     }
-}
 
-impl<W, S> Sink<S> for PacketStream<W>
-where
-    W: AsyncWrite + Unpin,
-    S: pkt::DeviceSend,
-{
-    type Error = Error;
-
-    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
-        let Self { txlen, txwaker, .. } = self.get_mut();
-        if *txlen != 0 {
-            txwaker.push(cx.waker().clone());
-            Poll::Pending
-        } else {
-            Poll::Ready(Ok(()))
+    /// Give up on an indication not confirmed in time, which releases the
+    /// permit and stops all traffic on the bearer. Wakes the run loop once it expires.
+    ///
+    /// Returns `true` if the indication timed out.
+    fn poll_confirmation_timeout(&mut self, cx: &mut Context<'_>) -> bool {
+        match &self.run_waker {
+            Some(waker) if waker.will_wake(cx.waker()) => {}
+            _ => self.run_waker = Some(cx.waker().clone()),
         }
-    }
-
-    fn start_send(self: Pin<&mut Self>, item: S) -> Result<()> {
-        let Self { txlen, txbuf, .. } = self.get_mut();
-        log::trace!("packet send {:?}", item);
-
-        let mut write = txbuf.as_mut();
-        let len = write.len();
-        item.pack_with_code(&mut write)?;
-        *txlen = len - write.len();
-        Ok(())
-    }
-
-    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
-        let Self {
-            inner,
-            txlen,
-            txbuf,
-            ..
-        } = self.get_mut();
-
-        while *txlen != 0 {
-            *txlen -= ready!(Pin::new(&mut *inner).poll_write(cx, &txbuf[..*txlen]))?;
+        let expired = match &mut self.await_confirmation {
+            Some(outstanding) => outstanding.expires.as_mut().poll(cx).is_ready(),
+            None => false,
+        };
+        if expired {
+            log::warn!("indication not confirmed in time");
+            if let Some(outstanding) = self.await_confirmation.take() {
+                outstanding
+                    .confirmed
+                    .send(Err(Error::TransactionTimeout))
+                    .ok();
+            }
+            self.stream.time_out();
         }
-        ready!(Pin::new(&mut *inner).poll_flush(cx))?;
-        Poll::Ready(Ok(()))
-    }
-
-    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
-        let this = self.get_mut();
-        ready!(Sink::<S>::poll_flush(Pin::new(this), cx))?;
-        ready!(Pin::new(&mut this.inner).poll_shutdown(cx))?;
-        Poll::Ready(Ok(()))
+        expired
     }
 }
 
-struct Inner<IO> {
-    stream: PacketStream<IO>,
-    await_confirmation: Option<oneshot::Sender<()>>,
-    // TODO used notification / indication handles
+/// How a notification or indication writer treats a buffer larger than ATT_MTU - 3.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WriteMode {
+    /// Every write is one value. A larger one fails with [`Error::PayloadTooLarge`].
+    #[default]
+    Strict,
+    /// Writes send as much as fits into one PDU and return the length sent,
+    /// so `write_all` splits a byte stream into MTU-sized values.
+    Stream,
 }
 
-impl<IO> Inner<IO> {
-    fn new(io: IO) -> Self {
-        Self {
-            stream: PacketStream::new(io),
-            await_confirmation: Default::default(),
+impl WriteMode {
+    /// Length of the prefix of `buf` sent in a single PDU.
+    fn payload_len(self, mtu: usize, buf: &[u8]) -> Result<usize> {
+        let max = mtu - 3;
+        match self {
+            Self::Strict if buf.len() > max => Err(Error::PayloadTooLarge { max }),
+            _ => Ok(buf.len().min(max)),
         }
     }
 }
@@ -168,6 +119,18 @@ struct NotificationInner<IO> {
     handle: Handle,
     inner: Arc<Mutex<Inner<IO>>>,
     state: NotificationState,
+    mode: WriteMode,
+}
+
+impl<IO> NotificationInner<IO> {
+    fn new(handle: Handle, inner: Arc<Mutex<Inner<IO>>>) -> Self {
+        Self {
+            handle,
+            inner,
+            state: NotificationState::Write,
+            mode: WriteMode::default(),
+        }
+    }
 }
 
 impl<IO> AsyncWrite for NotificationInner<IO>
@@ -183,7 +146,7 @@ where
             handle,
             inner,
             state,
-            ..
+            mode,
         } = self.get_mut();
         let mut guard = ready!(inner.lock().poll_unpin(cx));
 
@@ -196,13 +159,17 @@ where
                             cx
                         ))
                     {
-                        return Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, err)));
+                        return Poll::Ready(Err(err.into()));
                     }
-                    let item = pkt::HandleValueNotificationBorrow::new(handle.clone(), buf);
+                    let len = match mode.payload_len(guard.stream.txmtu(), buf) {
+                        Ok(len) => len,
+                        Err(err) => return Poll::Ready(Err(err.into())),
+                    };
+                    let item = pkt::HandleValueNotificationBorrow::new(handle.clone(), &buf[..len]);
                     if let Err(err) = guard.stream.start_send_unpin(item) {
-                        return Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, err)));
+                        return Poll::Ready(Err(err.into()));
                     }
-                    *state = NotificationState::NeedFlush(buf.len());
+                    *state = NotificationState::NeedFlush(len);
                 }
 
                 NotificationState::NeedFlush(len) => {
@@ -212,7 +179,7 @@ where
                             cx
                         ))
                     {
-                        return Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, err)));
+                        return Poll::Ready(Err(err.into()));
                     }
                     let len = *len;
                     *state = NotificationState::Write;
@@ -233,22 +200,33 @@ where
             Pin::new(&mut guard.stream),
             cx,
         )) {
-            return Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, err)));
+            return Poll::Ready(Err(err.into()));
         }
         Poll::Ready(Ok(()))
     }
 }
 
+type Acquire = Pin<
+    Box<dyn Future<Output = std::result::Result<OwnedSemaphorePermit, AcquireError>> + Send + Sync>,
+>;
+
 enum IndicationState {
     Write,
+    Acquire(Acquire),
+    Send,
     NeedFlush(usize),
-    AwaitConfirmation(usize, oneshot::Receiver<()>),
+    AwaitConfirmation(usize, oneshot::Receiver<Result<()>>),
 }
 
 struct IndicationInner<IO> {
     handle: Handle,
     inner: Arc<Mutex<Inner<IO>>>,
     state: IndicationState,
+    mode: WriteMode,
+    timeout: Timeout,
+    /// One outstanding indication per bearer; the others queue in order.
+    queue: Arc<Semaphore>,
+    permit: Option<OwnedSemaphorePermit>,
 }
 
 impl<IO> AsyncWrite for IndicationInner<IO>
@@ -264,45 +242,85 @@ where
             state,
             handle,
             inner,
-            ..
+            mode,
+            timeout,
+            queue,
+            permit,
         } = self.get_mut();
-        let mut guard = ready!(inner.lock().poll_unpin(cx));
 
         loop {
             match state {
                 IndicationState::Write => {
+                    *state = IndicationState::Acquire(Box::pin(queue.clone().acquire_owned()));
+                }
+
+                IndicationState::Acquire(acquire) => {
+                    let acquired = ready!(acquire.as_mut().poll(cx)).map_err(io::Error::other)?;
+                    *permit = Some(acquired);
+                    *state = IndicationState::Send;
+                }
+
+                IndicationState::Send => {
+                    let mut guard = ready!(inner.lock().poll_unpin(cx));
                     if let Err(err) = ready!(Sink::<pkt::HandleValueIndicationBorrow>::poll_ready(
                         Pin::new(&mut guard.stream),
                         cx
                     )) {
-                        return Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, err)));
+                        *permit = None;
+                        *state = IndicationState::Write;
+                        return Poll::Ready(Err(err.into()));
                     }
-                    let item = pkt::HandleValueIndicationBorrow::new(handle.clone(), buf);
+                    let len = match mode.payload_len(guard.stream.txmtu(), buf) {
+                        Ok(len) => len,
+                        Err(err) => {
+                            *permit = None;
+                            *state = IndicationState::Write;
+                            return Poll::Ready(Err(err.into()));
+                        }
+                    };
+                    let item = pkt::HandleValueIndicationBorrow::new(handle.clone(), &buf[..len]);
                     if let Err(err) = guard.stream.start_send_unpin(item) {
-                        return Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, err)));
+                        *permit = None;
+                        *state = IndicationState::Write;
+                        return Poll::Ready(Err(err.into()));
                     }
-                    *state = IndicationState::NeedFlush(buf.len());
+                    *state = IndicationState::NeedFlush(len);
                 }
 
                 IndicationState::NeedFlush(len) => {
+                    let mut guard = ready!(inner.lock().poll_unpin(cx));
                     if let Err(err) = ready!(Sink::<pkt::HandleValueIndicationBorrow>::poll_flush(
                         Pin::new(&mut guard.stream),
                         cx
                     )) {
-                        return Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, err)));
+                        *permit = None;
+                        *state = IndicationState::Write;
+                        return Poll::Ready(Err(err.into()));
                     }
                     let (tx, rx) = oneshot::channel();
-                    guard.await_confirmation = Some(tx); // TODO check existence
+                    let acquired = permit.take().expect("permit acquired before send");
+                    guard.await_confirmation = Some(Outstanding {
+                        handle: handle.clone(),
+                        confirmed: tx,
+                        expires: Box::pin(tokio::time::sleep(timeout.get())),
+                        _permit: acquired,
+                    });
+                    // the run loop watches the deadline
+                    if let Some(waker) = &guard.run_waker {
+                        waker.wake_by_ref();
+                    }
                     *state = IndicationState::AwaitConfirmation(*len, rx);
                 }
 
                 IndicationState::AwaitConfirmation(len, rx) => {
-                    if let Err(err) = ready!(rx.poll_unpin(cx)) {
-                        return Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, err)));
-                    }
+                    let confirmed = ready!(rx.poll_unpin(cx));
                     let len = *len;
                     *state = IndicationState::Write;
-                    return Poll::Ready(Ok(len));
+                    return match confirmed {
+                        Ok(Ok(())) => Poll::Ready(Ok(len)),
+                        Ok(Err(err)) => Poll::Ready(Err(err.into())),
+                        Err(_) => Poll::Ready(Err(io::ErrorKind::ConnectionAborted.into())),
+                    };
                 }
             }
         }
@@ -319,12 +337,58 @@ where
             Pin::new(&mut guard.stream),
             cx
         )) {
-            return Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, err)));
+            return Poll::Ready(Err(err.into()));
         }
         Poll::Ready(Ok(()))
     }
 }
 
+struct MultipleNotificationInner<IO> {
+    inner: Arc<Mutex<Inner<IO>>>,
+}
+
+impl<IO> MultipleNotificationInner<IO>
+where
+    IO: AsyncWrite + Unpin,
+{
+    async fn send(&self, values: &[(Handle, &[u8])]) -> Result<usize> {
+        let mut guard = self.inner.lock().await;
+        let mtu = guard.stream.txmtu();
+
+        let mut remaining = mtu - 1;
+        let mut n = 0;
+        for (_, value) in values {
+            let len = 2 + 2 + value.len();
+            if len > remaining {
+                break;
+            }
+            remaining -= len;
+            n += 1;
+        }
+
+        match (n, values.first()) {
+            (_, None) => Ok(0),
+            (0, Some((handle, value))) | (1, Some((handle, value))) => {
+                // at least two tuples are required. fall back to single notification.
+                if value.len() > mtu - 3 {
+                    return Err(Error::PayloadTooLarge { max: mtu - 3 });
+                }
+                let item = pkt::HandleValueNotificationBorrow::new(handle.clone(), value);
+                guard.stream.send(item).await?;
+                Ok(1)
+            }
+            (n, _) => {
+                let item = values[..n]
+                    .iter()
+                    .map(|(handle, value)| (handle.clone(), (*value).into()))
+                    .collect::<pkt::MultipleHandleValueNotification>();
+                guard.stream.send(item).await?;
+                Ok(n)
+            }
+        }
+    }
+}
+
 struct TryLockNext<'a, IO> {
     inner: &'a Mutex<Inner<IO>>,
 }
@@ -339,6 +403,9 @@ where
         let Self { inner } = self.get_mut();
 
         let mut guard = ready!(inner.lock().poll_unpin(cx));
+        if guard.poll_confirmation_timeout(cx) {
+            return Poll::Ready((guard, Some(Err(Error::TransactionTimeout))));
+        }
         let item = ready!(guard.stream.poll_next_unpin(cx));
         Poll::Ready((guard, item))
     }
@@ -367,136 +434,409 @@ where
 }
 
 async fn handle<IO, H>(
-    inner: &mut Inner<IO>,
+    inner: &Mutex<Inner<IO>>,
+    mtu: &Mtu,
     handler: &mut H,
     request: pkt::DeviceRecv,
 ) -> Result<()>
 where
     IO: AsyncWrite + Unpin,
-    H: crate::Handler,
+    H: crate::AsyncHandler,
 {
     match request {
+        pkt::DeviceRecv::ExchangeMtuRequest(..) if mtu.fixed => {
+            let response = Err(ErrorResponse::new(
+                Handle::new(0x0000),
+                pkt::ErrorCode::RequestNotSupported,
+            ));
+            respond::<_, pkt::ExchangeMtuRequest>(&mut inner.lock().await.stream, response).await?;
+        }
+
         pkt::DeviceRecv::ExchangeMtuRequest(item) => {
-            let response = handler.handle_exchange_mtu_request(&item);
+            // never offer more than can be received
+            let response = handler
+                .handle_exchange_mtu_request(&item)
+                .await
+                .map(|r| pkt::ExchangeMtuResponse::new((*r.server_rx_mtu()).min(mtu.max)));
+            let mut inner = inner.lock().await;
             if let Ok(response) = &response {
-                let client_rx_mtu = *item.client_rx_mtu() as usize;
-                let server_rx_mtu = *response.server_rx_mtu() as usize;
-                inner.stream.set_txmtu(client_rx_mtu);
-                inner.stream.set_rxmtu(server_rx_mtu);
+                let client_rx_mtu = *item.client_rx_mtu();
+                let server_rx_mtu = *response.server_rx_mtu();
+                let att_mtu = client_rx_mtu.min(server_rx_mtu).max(DEFAULT_MTU as u16);
+                inner.stream.set_txmtu(att_mtu as usize);
+                mtu.current.store(att_mtu, Ordering::SeqCst);
             }
             respond::<_, pkt::ExchangeMtuRequest>(&mut inner.stream, response).await?;
         }
 
         pkt::DeviceRecv::FindInformationRequest(item) => {
-            let response = handler.handle_find_information_request(&item);
-            respond::<_, pkt::FindInformationRequest>(&mut inner.stream, response).await?;
+            let response = handler.handle_find_information_request(&item).await;
+            respond::<_, pkt::FindInformationRequest>(&mut inner.lock().await.stream, response)
+                .await?;
         }
 
         pkt::DeviceRecv::FindByTypeValueRequest(item) => {
-            let response = handler.handle_find_by_type_value_request(&item);
-            respond::<_, pkt::FindByTypeValueRequest>(&mut inner.stream, response).await?;
+            let response = handler.handle_find_by_type_value_request(&item).await;
+            respond::<_, pkt::FindByTypeValueRequest>(&mut inner.lock().await.stream, response)
+                .await?;
         }
 
         pkt::DeviceRecv::ReadByTypeRequest(item) => {
-            let response = handler.handle_read_by_type_request(&item);
-            respond::<_, pkt::ReadByTypeRequest>(&mut inner.stream, response).await?;
+            let response = handler.handle_read_by_type_request(&item).await;
+            respond::<_, pkt::ReadByTypeRequest>(&mut inner.lock().await.stream, response).await?;
         }
 
         pkt::DeviceRecv::ReadRequest(item) => {
-            let response = handler.handle_read_request(&item);
-            respond::<_, pkt::ReadRequest>(&mut inner.stream, response).await?;
+            let response = handler.handle_read_request(&item).await;
+            respond::<_, pkt::ReadRequest>(&mut inner.lock().await.stream, response).await?;
         }
 
         pkt::DeviceRecv::ReadBlobRequest(item) => {
-            let response = handler.handle_read_blob_request(&item);
-            respond::<_, pkt::ReadBlobRequest>(&mut inner.stream, response).await?;
+            let response = handler.handle_read_blob_request(&item).await;
+            respond::<_, pkt::ReadBlobRequest>(&mut inner.lock().await.stream, response).await?;
         }
 
         pkt::DeviceRecv::ReadMultipleRequest(item) => {
-            let response = handler.handle_read_multiple_request(&item);
-            respond::<_, pkt::ReadMultipleRequest>(&mut inner.stream, response).await?;
+            let response = handler.handle_read_multiple_request(&item).await;
+            respond::<_, pkt::ReadMultipleRequest>(&mut inner.lock().await.stream, response)
+                .await?;
+        }
+
+        pkt::DeviceRecv::ReadMultipleVariableRequest(item) => {
+            let response = handler.handle_read_multiple_variable_request(&item).await;
+            respond::<_, pkt::ReadMultipleVariableRequest>(
+                &mut inner.lock().await.stream,
+                response,
+            )
+            .await?;
         }
 
         pkt::DeviceRecv::ReadByGroupTypeRequest(item) => {
-            let response = handler.handle_read_by_group_type_request(&item);
-            respond::<_, pkt::ReadByGroupTypeRequest>(&mut inner.stream, response).await?;
+            let response = handler.handle_read_by_group_type_request(&item).await;
+            respond::<_, pkt::ReadByGroupTypeRequest>(&mut inner.lock().await.stream, response)
+                .await?;
         }
 
         pkt::DeviceRecv::WriteRequest(item) => {
-            let response = handler.handle_write_request(&item);
-            respond::<_, pkt::WriteRequest>(&mut inner.stream, response).await?;
+            let response = handler.handle_write_request(&item).await;
+            respond::<_, pkt::WriteRequest>(&mut inner.lock().await.stream, response).await?;
         }
 
         pkt::DeviceRecv::WriteCommand(item) => {
-            handler.handle_write_command(&item);
+            handler.handle_write_command(&item).await;
         }
 
         pkt::DeviceRecv::PrepareWriteRequest(item) => {
-            let response = handler.handle_prepare_write_request(&item);
-            respond::<_, pkt::PrepareWriteRequest>(&mut inner.stream, response).await?;
+            let response = handler.handle_prepare_write_request(&item).await;
+            respond::<_, pkt::PrepareWriteRequest>(&mut inner.lock().await.stream, response)
+                .await?;
         }
 
         pkt::DeviceRecv::ExecuteWriteRequest(item) => {
-            let response = handler.handle_execute_write_request(&item);
-            respond::<_, pkt::ExecuteWriteRequest>(&mut inner.stream, response).await?;
+            let response = handler.handle_execute_write_request(&item).await;
+            respond::<_, pkt::ExecuteWriteRequest>(&mut inner.lock().await.stream, response)
+                .await?;
         }
 
         pkt::DeviceRecv::SignedWriteCommand(item) => {
-            handler.handle_signed_write_command(&item);
+            handler.handle_signed_write_command(&item).await;
         }
 
         pkt::DeviceRecv::HandleValueConfirmation(..) => {
-            if let Some(channel) = inner.await_confirmation.take() {
-                channel.send(()).ok();
+            let confirmed = confirm(&mut *inner.lock().await);
+            if let Some(handle) = confirmed {
+                handler.handle_confirmation(&handle).await;
             }
         }
     }
     Ok(())
 }
 
+/// Answer a PDU which could not be unpacked as the spec requires.
+async fn reject<IO>(inner: &Mutex<Inner<IO>>, diagnostics: &Diagnostics, opcode: u8) -> Result<()>
+where
+    IO: AsyncWrite + Unpin,
+{
+    let code = if opcode & COMMAND_FLAG != 0 {
+        log::debug!("ignore unparsable command {:#04x}", opcode);
+        diagnostics
+            .0
+            .ignored_commands
+            .fetch_add(1, Ordering::Relaxed);
+        return Ok(());
+    } else if pkt::ClientRecv::supports(opcode)
+        || opcode == pkt::OpCode::HandleValueConfirmation as u8
+    {
+        // only requests are answered
+        log::debug!("ignore unparsable PDU {:#04x}", opcode);
+        diagnostics.0.ignored_pdus.fetch_add(1, Ordering::Relaxed);
+        return Ok(());
+    } else if pkt::DeviceRecv::supports(opcode) {
+        diagnostics.0.invalid_pdus.fetch_add(1, Ordering::Relaxed);
+        pkt::ErrorCode::InvalidPDU
+    } else {
+        diagnostics
+            .0
+            .unsupported_requests
+            .fetch_add(1, Ordering::Relaxed);
+        pkt::ErrorCode::RequestNotSupported
+    };
+    log::debug!("reject request {:#04x} with {:?}", opcode, code);
+    let err = RawErrorResponse { opcode, code };
+    inner.lock().await.stream.send(err).await
+}
+
+/// Error Response to any request op code, including ones [`pkt::OpCode`] does not know.
+#[derive(Debug)]
+struct RawErrorResponse {
+    opcode: u8,
+    code: pkt::ErrorCode,
+}
+
+impl Outgoing<pkt::DeviceRecv> for RawErrorResponse {
+    fn pack_with_code<W>(self, write: &mut W) -> pack::Result<()>
+    where
+        W: io::Write,
+    {
+        pkt::OpCode::ErrorResponse.pack(write)?;
+        self.opcode.pack(write)?;
+        Handle::new(0x0000).pack(write)?;
+        self.code.pack(write)
+    }
+}
+
+/// Returns the handle of the confirmed indication, if one was outstanding.
+fn confirm<IO>(inner: &mut Inner<IO>) -> Option<Handle> {
+    // releasing the permit lets the next queued indication go out
+    let outstanding = inner.await_confirmation.take()?;
+    outstanding.confirmed.send(Ok(())).ok();
+    Some(outstanding.handle)
+}
+
+/// Command Flag of the Attribute Opcode.
+const COMMAND_FLAG: u8 = 0x40;
+
+/// Counts of received PDUs which could not be unpacked.
+#[derive(Debug, Clone, Default)]
+pub struct Diagnostics(Arc<DiagnosticsInner>);
+
+#[derive(Debug, Default)]
+struct DiagnosticsInner {
+    unsupported_requests: AtomicU64,
+    invalid_pdus: AtomicU64,
+    ignored_commands: AtomicU64,
+    ignored_pdus: AtomicU64,
+}
+
+impl Diagnostics {
+    /// Requests of unknown op codes, answered with Request Not Supported.
+    pub fn unsupported_requests(&self) -> u64 {
+        self.0.unsupported_requests.load(Ordering::Relaxed)
+    }
+
+    /// Malformed requests, answered with Invalid PDU.
+    pub fn invalid_pdus(&self) -> u64 {
+        self.0.invalid_pdus.load(Ordering::Relaxed)
+    }
+
+    /// Unknown or malformed commands, ignored.
+    pub fn ignored_commands(&self) -> u64 {
+        self.0.ignored_commands.load(Ordering::Relaxed)
+    }
+
+    /// Responses, notifications, indications and confirmations, ignored.
+    pub fn ignored_pdus(&self) -> u64 {
+        self.0.ignored_pdus.load(Ordering::Relaxed)
+    }
+}
+
+/// ATT_MTU of a bearer.
+#[derive(Debug, Clone)]
+struct Mtu {
+    /// Largest MTU offered, which the receive buffer holds from the start.
+    max: u16,
+    /// Negotiated with Exchange MTU.
+    current: Arc<AtomicU16>,
+    /// Taken from the L2CAP channel of an Enhanced ATT bearer, which has no Exchange MTU.
+    fixed: bool,
+}
+
+impl Default for Mtu {
+    fn default() -> Self {
+        Self {
+            max: DEFAULT_MAX_MTU,
+            current: Arc::new(AtomicU16::new(DEFAULT_MTU as u16)),
+            fixed: false,
+        }
+    }
+}
+
+/// ATT transaction timeout in milliseconds, shared by a bearer and its indications.
+#[derive(Debug, Clone)]
+struct Timeout(Arc<AtomicU64>);
+
+impl Default for Timeout {
+    fn default() -> Self {
+        Self(Arc::new(AtomicU64::new(
+            DEFAULT_TRANSACTION_TIMEOUT.as_millis() as u64,
+        )))
+    }
+}
+
+impl Timeout {
+    fn get(&self) -> Duration {
+        Duration::from_millis(self.0.load(Ordering::SeqCst))
+    }
+
+    fn set(&self, timeout: Duration) {
+        let millis = timeout.as_millis().min(u64::MAX as u128) as u64;
+        self.0.store(millis, Ordering::SeqCst);
+    }
+}
+
 struct ConnectionInner<IO> {
     inner: Arc<Mutex<Inner<IO>>>,
+    diagnostics: Diagnostics,
+    mtu: Mtu,
+    timeout: Timeout,
+    indications: Arc<Semaphore>,
 }
 
 impl<IO> ConnectionInner<IO>
 where
     IO: AsyncRead + AsyncWrite + Unpin,
 {
-    fn notification(&self, handle: Handle) -> NotificationInner<IO> {
-        NotificationInner {
-            handle,
-            inner: self.inner.clone(),
-            state: NotificationState::Write,
+    fn new(io: IO) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner::new(io))),
+            diagnostics: Diagnostics::default(),
+            mtu: Mtu::default(),
+            timeout: Timeout::default(),
+            indications: Arc::new(Semaphore::new(1)),
         }
     }
 
+    fn notification(&self, handle: Handle) -> NotificationInner<IO> {
+        NotificationInner::new(handle, self.inner.clone())
+    }
+
     fn indication(&self, handle: Handle) -> IndicationInner<IO> {
         IndicationInner {
             handle,
             inner: self.inner.clone(),
             state: IndicationState::Write,
+            mode: WriteMode::default(),
+            timeout: self.timeout.clone(),
+            queue: self.indications.clone(),
+            permit: None,
+        }
+    }
+
+    fn multiple_notification(&self) -> MultipleNotificationInner<IO> {
+        MultipleNotificationInner {
+            inner: self.inner.clone(),
         }
     }
 
-    async fn run<H>(self, mut handler: H) -> Result<()>
+    async fn run<H>(self, handler: H) -> Result<()>
+    where
+        H: crate::AsyncHandler,
+    {
+        let inner = self.inner.clone();
+        let result = self.serve(handler).await;
+        // no confirmation can arrive anymore
+        inner.lock().await.await_confirmation = None;
+        result
+    }
+
+    async fn serve<H>(self, mut handler: H) -> Result<()>
     where
-        H: crate::Handler,
+        H: crate::AsyncHandler,
     {
+        {
+            let mut inner = self.inner.lock().await;
+            // a larger PDU would be truncated by the packet-preserving transport
+            inner.stream.set_rxmtu(self.mtu.max as usize);
+            inner
+                .stream
+                .set_txmtu(self.mtu.current.load(Ordering::SeqCst) as usize);
+        }
+
+        // Requests received while a handler is pending.
+        let mut backlog = VecDeque::new();
+        // Indications confirmed while a handler is pending.
+        let mut confirmed = vec![];
         loop {
-            let (mut guard, request) = TryLockNext { inner: &self.inner }.await;
-            let request = if let Some(request) = request {
-                request?
+            let request = if let Some(request) = backlog.pop_front() {
+                request
             } else {
-                return Ok(());
+                let (guard, request) = TryLockNext { inner: &self.inner }.await;
+                drop(guard);
+                match request {
+                    Some(request) => request,
+                    None => return Ok(()),
+                }
+            };
+            let request = match request {
+                Ok(request) => request,
+                Err(Error::Unparsable(opcode, err)) => {
+                    log::debug!("{}", err);
+                    reject(&self.inner, &self.diagnostics, opcode).await?;
+                    continue;
+                }
+                Err(err) => return Err(err),
             };
 
-            handle(&mut *guard, &mut handler, request).await?;
+            let closed = {
+                let task = handle(&self.inner, &self.mtu, &mut handler, request);
+                pin_mut!(task);
+                loop {
+                    let next = TryLockNext { inner: &self.inner };
+                    match future::select(task.as_mut(), next).await {
+                        Either::Left((result, _)) => {
+                            result?;
+                            break false;
+                        }
+                        Either::Right(((mut guard, Some(request)), _)) => match request {
+                            Ok(pkt::DeviceRecv::HandleValueConfirmation(..)) => {
+                                confirmed.extend(confirm(&mut *guard))
+                            }
+                            request @ (Ok(..) | Err(Error::Unparsable(..))) => {
+                                backlog.push_back(request)
+                            }
+                            Err(err) => return Err(err),
+                        },
+                        Either::Right(((guard, None), _)) => {
+                            drop(guard);
+                            task.await?;
+                            break true;
+                        }
+                    }
+                }
+            };
+            for handle in confirmed.drain(..) {
+                handler.handle_confirmation(&handle).await;
+            }
+            if closed {
+                return Ok(());
+            }
         }
     }
 }
 
 pub struct Notification {
-    inner: NotificationInner<AttStream>,
+    inner: NotificationInner<BoxTransport>,
+}
+
+impl Notification {
+    pub fn write_mode(&self) -> WriteMode {
+        self.inner.mode
+    }
+
+    /// Choose how values larger than ATT_MTU - 3 are written. Defaults to [`WriteMode::Strict`].
+    pub fn set_write_mode(&mut self, mode: WriteMode) {
+        self.inner.mode = mode;
+    }
 }
 
 impl AsyncWrite for Notification {
@@ -517,8 +857,50 @@ impl AsyncWrite for Notification {
     }
 }
 
+/// Creates [`Notification`] writers of a bearer, also while its [`Connection`] runs.
+#[derive(Clone)]
+pub struct Notifier {
+    inner: Arc<Mutex<Inner<BoxTransport>>>,
+}
+
+impl Notifier {
+    pub fn notification(&self, handle: Handle) -> Notification {
+        Notification {
+            inner: NotificationInner::new(handle, self.inner.clone()),
+        }
+    }
+}
+
+/// Multiple Handle Value Notification sender
+pub struct MultipleNotification {
+    inner: MultipleNotificationInner<BoxTransport>,
+}
+
+impl MultipleNotification {
+    /// Notify as many values as fit into the current tx MTU in a single PDU.
+    /// Returns the number of values sent.
+    ///
+    /// A single value is sent as Handle Value Notification. One that does not fit
+    /// fails with [`Error::PayloadTooLarge`].
+    pub async fn send(&mut self, values: &[(Handle, &[u8])]) -> Result<usize> {
+        self.inner.send(values).await
+    }
+}
+
 pub struct Indication {
-    inner: IndicationInner<AttStream>,
+    inner: IndicationInner<BoxTransport>,
+}
+
+impl Indication {
+    pub fn write_mode(&self) -> WriteMode {
+        self.inner.mode
+    }
+
+    /// Choose how values larger than ATT_MTU - 3 are written. Defaults to [`WriteMode::Strict`].
+    /// In [`WriteMode::Stream`] each value waits for its confirmation.
+    pub fn set_write_mode(&mut self, mode: WriteMode) {
+        self.inner.mode = mode;
+    }
 }
 
 impl AsyncWrite for Indication {
@@ -548,44 +930,117 @@ where
     L: Stream<Item = io::Result<(IO, socket2::SockAddr)>> + Unpin,
     IO: AsyncRead + AsyncWrite + Unpin,
 {
-    async fn accept(&mut self) -> io::Result<Option<(ConnectionInner<IO>, socket2::SockAddr)>> {
-        if let Some((sock, addr)) = self.inner.try_next().await? {
-            return Ok(Some((
-                ConnectionInner {
-                    inner: Arc::new(Mutex::new(Inner::new(sock))),
-                },
-                addr,
-            )));
-        }
-        Ok(None)
+    async fn accept(&mut self) -> io::Result<Option<(IO, socket2::SockAddr)>> {
+        self.inner.try_next().await
     }
 }
 
 pub struct Connection {
-    inner: ConnectionInner<AttStream>,
+    inner: ConnectionInner<BoxTransport>,
     addr: crate::Address,
+    security: SecurityQuery,
 }
 
 impl Connection {
+    /// Serve over an arbitrary packet-preserving transport.
+    pub fn new<IO>(io: IO, address: crate::Address) -> Self
+    where
+        IO: Transport,
+    {
+        Self {
+            inner: ConnectionInner::new(Box::new(io)),
+            addr: address,
+            security: SecurityQuery::default(),
+        }
+    }
+
     pub fn address(&self) -> &crate::Address {
         &self.addr
     }
 
+    /// Query for the current security of the link.
+    pub fn security_query(&self) -> SecurityQuery {
+        self.security.clone()
+    }
+
+    /// Replace the query for the security of the link.
+    /// Accepted connections query the socket; others report no security.
+    pub fn set_security_query(&mut self, query: SecurityQuery) {
+        self.security = query;
+    }
+
     pub fn notification(&self, handle: Handle) -> Notification {
         Notification {
             inner: self.inner.notification(handle),
         }
     }
 
+    pub fn notifier(&self) -> Notifier {
+        Notifier {
+            inner: self.inner.inner.clone(),
+        }
+    }
+
     pub fn indication(&self, handle: Handle) -> Indication {
         Indication {
             inner: self.inner.indication(handle),
         }
     }
 
+    pub fn multiple_notification(&self) -> MultipleNotification {
+        MultipleNotification {
+            inner: self.inner.multiple_notification(),
+        }
+    }
+
+    /// Current ATT_MTU. `23` until the client exchanges MTU, unless set with
+    /// [`Connection::set_eatt_mtu`].
+    pub fn mtu(&self) -> u16 {
+        self.inner.mtu.current.load(Ordering::SeqCst)
+    }
+
+    /// Largest ATT_MTU offered to the client.
+    pub fn max_mtu(&self) -> u16 {
+        self.inner.mtu.max
+    }
+
+    /// Offer at most `mtu`, at least 23, in Exchange MTU.
+    /// Accepted connections take it from [`Server::set_mtu`].
+    pub fn set_max_mtu(&mut self, mtu: u16) {
+        self.inner.mtu.max = mtu.max(DEFAULT_MTU as u16);
+    }
+
+    /// Make this an Enhanced ATT bearer with ATT_MTU `mtu`, the smaller of the
+    /// MTUs of its L2CAP channel. Exchange MTU fails with Request Not Supported.
+    /// Accepted connections of [`Server::new_eatt`] take it from the channel.
+    pub fn set_eatt_mtu(&mut self, mtu: u16) {
+        let mtu = mtu.max(DEFAULT_MTU as u16);
+        self.inner.mtu.max = mtu;
+        self.inner.mtu.current.store(mtu, Ordering::SeqCst);
+        self.inner.mtu.fixed = true;
+        // for writers used before running. otherwise set once running
+        if let Some(mut inner) = self.inner.inner.try_lock() {
+            inner.stream.set_txmtu(mtu as usize);
+        }
+    }
+
+    /// Time a client has to confirm an indication. Defaults to 30 seconds.
+    ///
+    /// When it expires, the indication and [`Connection::run`] fail with
+    /// [`Error::TransactionTimeout`] and no more PDUs are sent on the bearer.
+    pub fn set_transaction_timeout(&mut self, timeout: Duration) {
+        self.inner.timeout.set(timeout);
+    }
+
+    /// Counts of unparsable PDUs, which are answered or ignored without
+    /// closing the connection.
+    pub fn diagnostics(&self) -> Diagnostics {
+        self.inner.diagnostics.clone()
+    }
+
     pub async fn run<H>(self, handler: H) -> Result<()>
     where
-        H: crate::Handler,
+        H: crate::AsyncHandler,
     {
         log::debug!("Start serving.");
         self.inner.run(handler).await?;
@@ -596,6 +1051,8 @@ impl Connection {
 
 pub struct Server {
     inner: ServerInner<AttListener>,
+    mtu: u16,
+    eatt: bool,
 }
 
 impl Server {
@@ -604,6 +1061,20 @@ impl Server {
         let sock = AttListener::new()?;
         Ok(Self {
             inner: ServerInner { inner: sock },
+            mtu: DEFAULT_MAX_MTU,
+            eatt: false,
+        })
+    }
+
+    /// Listen for Enhanced ATT bearers on PSM 0x0027.
+    ///
+    /// Each accepted [`Connection`] is one bearer.
+    pub fn new_eatt() -> io::Result<Self> {
+        let sock = AttListener::new_eatt()?;
+        Ok(Self {
+            inner: ServerInner { inner: sock },
+            mtu: DEFAULT_MAX_MTU,
+            eatt: true,
         })
     }
 
@@ -619,17 +1090,36 @@ impl Server {
             .set_sockopt_bt_security(crate::sock::BT_SECURITY_HIGH, 0)
     }
 
+    /// Largest ATT_MTU offered to clients, at least 23. Defaults to 517.
+    ///
+    /// Also the L2CAP receive MTU of the channels accepted afterwards,
+    /// where the kernel supports `BT_RCVMTU`.
+    pub fn set_mtu(&mut self, mtu: u16) -> io::Result<()> {
+        let mtu = mtu.max(DEFAULT_MTU as u16);
+        match self.inner.inner.set_sockopt_bt_rcvmtu(mtu) {
+            Ok(()) => {}
+            Err(err) if err.raw_os_error() == Some(libc::ENOPROTOOPT) => {
+                log::debug!("BT_RCVMTU not supported: {}", err);
+            }
+            Err(err) => return Err(err),
+        }
+        self.mtu = mtu;
+        Ok(())
+    }
+
     pub async fn accept(&mut self) -> io::Result<Option<(Connection, crate::Address)>> {
-        if let Some((connection, addr)) = self.inner.accept().await? {
+        if let Some((sock, addr)) = self.inner.accept().await? {
             log::debug!("Connection accepted.");
             let addr = crate::sock::try_from(addr)?;
-            Ok(Some((
-                Connection {
-                    inner: connection,
-                    addr: addr.clone(),
-                },
-                addr,
-            )))
+            let security = sock.security_query();
+            let eatt_mtu = if self.eatt { Some(sock.mtu()?) } else { None };
+            let mut connection = Connection::new(sock, addr.clone());
+            connection.set_security_query(security);
+            connection.set_max_mtu(self.mtu);
+            if let Some(mtu) = eatt_mtu {
+                connection.set_eatt_mtu(mtu);
+            }
+            Ok(Some((connection, addr)))
         } else {
             Ok(None)
         }
@@ -639,41 +1129,287 @@ impl Server {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::convert::TryFrom;
     use tokio::io::AsyncWriteExt;
     use tokio_test::io::Builder;
 
     #[tokio::test]
-    async fn test_stream() {
+    async fn test_connection() {
+        struct H;
+        impl Handler for H {}
+
         let stream = Builder::new()
+            .write(&[0x1B, 0x01, 0x00, 0x6F, 0x6B])
             .read(&[0x02, 0x17, 0x00])
-            .write(&[0x03, 0x18, 0x00])
+            .write(&[0x03, 0x17, 0x00])
             .build();
-        let mut stream = PacketStream::new(stream);
-        let packet = stream.try_next().await.unwrap().unwrap();
-        let packet = pkt::ExchangeMtuRequest::try_from(packet).unwrap();
-        assert_eq!(*packet.client_rx_mtu(), 23);
+        let connection = ConnectionInner::new(stream);
 
-        let packet = pkt::ExchangeMtuResponse::new(0x0018);
-        stream.send(packet).await.unwrap();
+        let mut notification = connection.notification(Handle::new(1));
+        notification.write_all(b"ok").await.unwrap();
+        connection.run(H).await.unwrap();
     }
 
-    #[tokio::test]
-    async fn test_connection() {
+    #[tokio::test(start_paused = true)]
+    async fn test_indication_timeout() {
         struct H;
         impl Handler for H {}
 
         let stream = Builder::new()
-            .write(&[0x1B, 0x01, 0x00, 0x6F, 0x6B])
-            .read(&[0x02, 0x17, 0x00])
-            .write(&[0x03, 0x17, 0x00])
+            .write(&[0x1D, 0x01, 0x00, 0x6F, 0x6B])
+            .wait(Duration::from_secs(60))
             .build();
-        let connection = ConnectionInner {
-            inner: Arc::new(Mutex::new(Inner::new(stream))),
+        let connection = ConnectionInner::new(stream);
+        connection.timeout.set(Duration::from_secs(10));
+
+        let mut indication = connection.indication(Handle::new(1));
+        let mut notification = connection.notification(Handle::new(1));
+        let indicate = async move {
+            let err = indication.write_all(b"ok").await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+            let err = indication.write_all(b"ok").await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+            notification.write_all(b"ok").await.unwrap_err();
         };
+        // the bearer ends with the transaction
+        let (run, ()) = tokio::join!(connection.run(H), indicate);
+        assert!(matches!(run, Err(Error::TransactionTimeout)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cancelled_indication() {
+        struct H;
+        impl Handler for H {}
 
+        let stream = Builder::new()
+            .write(&[0x1D, 0x01, 0x00, 0x61])
+            .wait(Duration::from_secs(60))
+            .build();
+        let connection = ConnectionInner::new(stream);
+        connection.timeout.set(Duration::from_secs(10));
+        let mut first = connection.indication(Handle::new(1));
+        let mut second = connection.indication(Handle::new(2));
+
+        let indicate = async move {
+            // the caller gives up before the client confirms
+            let write = tokio::time::timeout(Duration::from_secs(1), first.write_all(b"a"));
+            write.await.unwrap_err();
+            drop(first);
+            // the bearer timed out instead of blocking the queue
+            let err = second.write_all(b"b").await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        };
+        let (run, ()) = tokio::join!(connection.run(H), indicate);
+        assert!(matches!(run, Err(Error::TransactionTimeout)));
+    }
+
+    #[tokio::test]
+    async fn test_indication_queue() {
+        struct H;
+        impl Handler for H {
+            fn handle_read_request(
+                &mut self,
+                _item: &pkt::ReadRequest,
+            ) -> std::result::Result<pkt::ReadResponse, ErrorResponse> {
+                Ok(pkt::ReadResponse::new(vec![0xAA].into()))
+            }
+        }
+
+        let stream = Builder::new()
+            .write(&[0x1D, 0x01, 0x00, 0x61])
+            // requests are served while the indication is outstanding
+            .read(&[0x0A, 0x01, 0x00])
+            .write(&[0x0B, 0xAA])
+            .read(&[0x1E])
+            .write(&[0x1D, 0x02, 0x00, 0x62])
+            .read(&[0x1E])
+            .build();
+        let connection = ConnectionInner::new(stream);
+        let mut first = connection.indication(Handle::new(1));
+        let mut second = connection.indication(Handle::new(2));
+
+        let (run, first, second) = tokio::join!(
+            connection.run(H),
+            first.write_all(b"a"),
+            second.write_all(b"b")
+        );
+        run.unwrap();
+        first.unwrap();
+        second.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_write_mode() {
+        struct H;
+        impl Handler for H {}
+
+        let payload = (0..30).collect::<Vec<u8>>();
+        let pdu = |opcode: u8, value: &[u8]| [&[opcode, 0x01, 0x00], value].concat();
+        let stream = Builder::new()
+            .write(&pdu(0x1B, &payload[..20]))
+            .write(&pdu(0x1B, &payload[20..]))
+            .write(&pdu(0x1D, &payload[..20]))
+            .read(&[0x1E])
+            .write(&pdu(0x1D, &payload[20..]))
+            .read(&[0x1E])
+            .build();
+        let connection = ConnectionInner::new(stream);
         let mut notification = connection.notification(Handle::new(1));
-        notification.write_all(b"ok").await.unwrap();
+        let mut indication = connection.indication(Handle::new(1));
+
+        let err = notification.write_all(&payload).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = err.into_inner().unwrap().downcast::<Error>().unwrap();
+        assert!(matches!(*err, Error::PayloadTooLarge { max: 20 }));
+        notification.mode = WriteMode::Stream;
+        notification.write_all(&payload).await.unwrap();
+
+        let err = indication.write_all(&payload).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        indication.mode = WriteMode::Stream;
+        let (run, written) = tokio::join!(connection.run(H), indication.write_all(&payload));
+        run.unwrap();
+        written.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unparsable() {
+        struct H;
+        impl Handler for H {
+            fn handle_read_request(
+                &mut self,
+                _item: &pkt::ReadRequest,
+            ) -> std::result::Result<pkt::ReadResponse, ErrorResponse> {
+                Ok(pkt::ReadResponse::new(vec![0xAA].into()))
+            }
+        }
+
+        let stream = Builder::new()
+            // unknown request
+            .read(&[0x3F, 0x01, 0x02])
+            .write(&[0x01, 0x3F, 0x00, 0x00, 0x06])
+            // unknown command
+            .read(&[0x7F, 0x01, 0x02])
+            // truncated request
+            .read(&[0x0A, 0x01])
+            .write(&[0x01, 0x0A, 0x00, 0x00, 0x04])
+            // truncated command
+            .read(&[0x52])
+            // not requests
+            .read(&[0x01, 0x0A])
+            .read(&[0x1B, 0x01, 0x00, 0xAA])
+            .read(&[0x0A, 0x01, 0x00])
+            .write(&[0x0B, 0xAA])
+            .build();
+        let connection = ConnectionInner::new(stream);
+        let diagnostics = connection.diagnostics.clone();
+        connection.run(H).await.unwrap();
+        assert_eq!(diagnostics.unsupported_requests(), 1);
+        assert_eq!(diagnostics.invalid_pdus(), 1);
+        assert_eq!(diagnostics.ignored_commands(), 2);
+        assert_eq!(diagnostics.ignored_pdus(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_mtu() {
+        struct H;
+        impl Handler for H {
+            fn handle_write_command(&mut self, item: &pkt::WriteCommand) {
+                assert_eq!(item.attribute_value().len(), 40);
+            }
+        }
+
+        let mut write = vec![0x52, 0x01, 0x00];
+        write.extend_from_slice(&[0xAA; 40]);
+        let stream = Builder::new()
+            // not truncated before the exchange
+            .read(&write)
+            .read(&[0x02, 0x00, 0x02])
+            .write(&[0x03, 0x64, 0x00])
+            .build();
+        let mut connection = ConnectionInner::new(stream);
+        connection.mtu.max = 100;
+        let mtu = connection.mtu.clone();
+        connection.run(H).await.unwrap();
+        assert_eq!(mtu.current.load(Ordering::SeqCst), 100);
+    }
+
+    #[tokio::test]
+    async fn test_eatt_mtu() {
+        struct H;
+        impl Handler for H {}
+
+        let mut notify = vec![0x1B, 0x01, 0x00];
+        notify.extend_from_slice(&[0xAA; 61]);
+        let stream = Builder::new()
+            .write(&notify)
+            .read(&[0x02, 0x00, 0x02])
+            .write(&[0x01, 0x02, 0x00, 0x00, 0x06])
+            .build();
+        let mut connection = Connection::new(stream, crate::Address::le_public_from([0; 6]));
+        connection.set_eatt_mtu(64);
+        assert_eq!(connection.mtu(), 64);
+
+        let mut notification = connection.notification(Handle::new(1));
+        notification.write_all(&[0xAA; 61]).await.unwrap();
+        let mtu = connection.inner.mtu.clone();
+        connection.run(H).await.unwrap();
+        assert_eq!(mtu.current.load(Ordering::SeqCst), 64);
+    }
+
+    #[tokio::test]
+    async fn test_read_multiple() {
+        struct H;
+        impl Handler for H {
+            fn handle_read_multiple_variable_request(
+                &mut self,
+                item: &pkt::ReadMultipleVariableRequest,
+            ) -> std::result::Result<pkt::ReadMultipleVariableResponse, ErrorResponse> {
+                Ok(item.into_iter().map(|_| vec![0xAA; 12].into()).collect())
+            }
+        }
+
+        let stream = Builder::new()
+            .read(&[0x0E])
+            .write(&[0x01, 0x0E, 0x00, 0x00, 0x06])
+            .read(&[0x20, 0x01, 0x00, 0x02, 0x00])
+            .write(&[
+                0x21, 0x0C, 0x00, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA,
+                0xAA, 0x0C, 0x00, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA,
+            ])
+            .build();
+        let connection = ConnectionInner::new(stream);
+        connection.run(H).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_multiple_notification() {
+        struct H;
+        impl Handler for H {}
+
+        let stream = Builder::new()
+            .write(&[
+                0x23, 0x01, 0x00, 0x02, 0x00, 0x6F, 0x6B, 0x02, 0x00, 0x03, 0x00, 0x61, 0x62, 0x63,
+                0x03, 0x00, 0x02, 0x00, 0xAA, 0xAA,
+            ])
+            .write(&[0x1B, 0x04, 0x00, 0x78])
+            .build();
+        let connection = ConnectionInner::new(stream);
+        let notification = connection.multiple_notification();
+
+        let values = [
+            (Handle::new(1), b"ok".as_ref()),
+            (Handle::new(2), b"abc".as_ref()),
+            (Handle::new(3), [0xAA; 2].as_ref()),
+            (Handle::new(4), b"x".as_ref()),
+        ];
+        assert_eq!(notification.send(&values).await.unwrap(), 3);
+        assert_eq!(notification.send(&values[3..]).await.unwrap(), 1);
+        assert_eq!(notification.send(&[]).await.unwrap(), 0);
+        let err = notification
+            .send(&[(Handle::new(5), [0; 21].as_ref())])
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::PayloadTooLarge { max: 20 }));
         connection.run(H).await.unwrap();
     }
 
@@ -686,9 +1422,7 @@ mod tests {
             .write(&[0x1D, 0x01, 0x00, 0x6F, 0x6B])
             .read(&[0x1E, 0x17, 0x00])
             .build();
-        let connection = ConnectionInner {
-            inner: Arc::new(Mutex::new(Inner::new(stream))),
-        };
+        let connection = ConnectionInner::new(stream);
 
         let mut indication = connection.indication(Handle::new(1));
         let task = tokio::spawn(connection.run(H));
@@ -697,4 +1431,40 @@ mod tests {
 
         task.await.unwrap().unwrap();
     }
+
+    #[tokio::test]
+    async fn test_async_handler() {
+        struct H(Option<oneshot::Receiver<()>>);
+        impl AsyncHandler for H {
+            fn handle_read_request(
+                &mut self,
+                _item: &pkt::ReadRequest,
+            ) -> impl Future<Output = std::result::Result<pkt::ReadResponse, ErrorResponse>>
+            {
+                let rx = self.0.take().unwrap();
+                async move {
+                    rx.await.unwrap();
+                    Ok(pkt::ReadResponse::new(Box::new(*b"ok")))
+                }
+            }
+        }
+
+        let stream = Builder::new()
+            .read(&[0x0A, 0x01, 0x00])
+            .write(&[0x1D, 0x01, 0x00, 0x6F, 0x6B])
+            .read(&[0x1E])
+            .write(&[0x0B, 0x6F, 0x6B])
+            .build();
+        let connection = ConnectionInner::new(stream);
+
+        let mut indication = connection.indication(Handle::new(1));
+        let (tx, rx) = oneshot::channel();
+        let task = tokio::spawn(connection.run(H(Some(rx))));
+
+        // confirmed while the read request is pending
+        indication.write_all(b"ok").await.unwrap();
+        tx.send(()).unwrap();
+
+        task.await.unwrap().unwrap();
+    }
 }
@@ -3,9 +3,10 @@ use std::mem;
 use std::net::Shutdown;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::pin::Pin;
+use std::sync::{Arc, Weak};
 use std::task::{Context, Poll};
 
-use bdaddr::BdAddr;
+use bdaddr::{AddressType, BdAddr};
 use futures_core::ready;
 use futures_core::stream::Stream;
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
@@ -19,6 +20,10 @@ const BDADDR_LE_PUBLIC: u8 = 0x01;
 const BDADDR_LE_RANDOM: u8 = 0x02;
 const SOL_BLUETOOTH: libc::c_int = 274;
 const BT_SECURITY: libc::c_int = 4;
+const BT_SNDMTU: libc::c_int = 12;
+const BT_RCVMTU: libc::c_int = 13;
+const BT_MODE: libc::c_int = 15;
+const BT_MODE_EXT_FLOWCTL: u8 = 0x04;
 //pub(crate) const BT_SECURITY_SDP: u8 = 0;
 //pub(crate) const BT_SECURITY_LOW: u8 = 1;
 pub(crate) const BT_SECURITY_MEDIUM: u8 = 2;
@@ -41,6 +46,9 @@ struct bt_security {
 }
 
 // <bluetooth/l2cap.h>
+const ATT_CID: libc::c_ushort = 0x0004;
+const EATT_PSM: libc::c_ushort = 0x0027;
+
 #[repr(C)]
 #[derive(Debug)]
 #[allow(non_camel_case_types)]
@@ -59,24 +67,40 @@ fn sock_open() -> io::Result<Socket> {
     Socket::new(domain, r#type, Some(proto))
 }
 
-fn sock_bind(sock: &Socket, cid: libc::c_ushort) -> io::Result<()> {
+fn sock_addr(
+    bdaddr: [u8; 6],
+    bdaddr_type: u8,
+    psm: libc::c_ushort,
+    cid: libc::c_ushort,
+) -> io::Result<SockAddr> {
     let (_, addr) = unsafe {
-        SockAddr::init(|addr, _| {
+        SockAddr::init(|addr, len| {
             let addr = &mut *(addr as *mut sockaddr_l2);
             *addr = sockaddr_l2 {
                 l2_family: (libc::AF_BLUETOOTH as libc::sa_family_t),
-                l2_psm: Default::default(),
+                l2_psm: psm.to_le(),
                 l2_cid: cid.to_le(),
-                l2_bdaddr: bdaddr_t { b: [0; 6] },
-                l2_bdaddr_type: BDADDR_LE_PUBLIC,
+                l2_bdaddr: bdaddr_t { b: bdaddr },
+                l2_bdaddr_type: bdaddr_type,
             };
+            *len = mem::size_of::<sockaddr_l2>() as libc::socklen_t;
             Ok(())
         })?
     };
+    Ok(addr)
+}
+
+fn sock_bind(sock: &Socket, psm: libc::c_ushort, cid: libc::c_ushort) -> io::Result<()> {
+    let addr = sock_addr([0; 6], BDADDR_LE_PUBLIC, psm, cid)?;
     sock.bind(&addr)?;
     Ok(())
 }
 
+fn async_fd(sock: Socket) -> io::Result<AsyncFd<Socket>> {
+    // SAFETY: the socket owns its fd, which stays open until the `AsyncFd` is dropped.
+    Ok(unsafe { AsyncFd::register(sock) }?)
+}
+
 fn set_sockopt_bt_security(fd: RawFd, level: u8, key_size: u8) -> io::Result<()> {
     let opt = bt_security { level, key_size };
     let len = mem::size_of::<bt_security>() as libc::socklen_t;
@@ -98,6 +122,91 @@ fn set_sockopt_bt_security(fd: RawFd, level: u8, key_size: u8) -> io::Result<()>
     }
 }
 
+fn get_sockopt_bt_security(fd: RawFd) -> io::Result<bt_security> {
+    let mut opt = bt_security {
+        level: 0,
+        key_size: 0,
+    };
+    let mut len = mem::size_of::<bt_security>() as libc::socklen_t;
+
+    let r = unsafe {
+        libc::getsockopt(
+            fd,
+            SOL_BLUETOOTH,
+            BT_SECURITY,
+            &mut opt as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    if r < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(opt)
+    }
+}
+
+fn set_sockopt_bt_rcvmtu(fd: RawFd, mtu: u16) -> io::Result<()> {
+    let len = mem::size_of::<u16>() as libc::socklen_t;
+
+    let r = unsafe {
+        libc::setsockopt(
+            fd,
+            SOL_BLUETOOTH,
+            BT_RCVMTU,
+            &mtu as *const _ as *const libc::c_void,
+            len,
+        )
+    };
+
+    if r < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn get_sockopt_bt_mtu(fd: RawFd, name: libc::c_int) -> io::Result<u16> {
+    let mut opt = 0u16;
+    let mut len = mem::size_of::<u16>() as libc::socklen_t;
+
+    let r = unsafe {
+        libc::getsockopt(
+            fd,
+            SOL_BLUETOOTH,
+            name,
+            &mut opt as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    if r < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(opt)
+    }
+}
+
+fn set_sockopt_bt_mode(fd: RawFd, mode: u8) -> io::Result<()> {
+    let len = mem::size_of::<u8>() as libc::socklen_t;
+
+    let r = unsafe {
+        libc::setsockopt(
+            fd,
+            SOL_BLUETOOTH,
+            BT_MODE,
+            &mode as *const _ as *const libc::c_void,
+            len,
+        )
+    };
+
+    if r < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
 pub(crate) fn try_from(addr: socket2::SockAddr) -> io::Result<crate::Address> {
     if addr.family() == libc::AF_BLUETOOTH as libc::sa_family_t {
         let addr = unsafe { &*(addr.as_ptr() as *const sockaddr_l2) };
@@ -106,22 +215,66 @@ pub(crate) fn try_from(addr: socket2::SockAddr) -> io::Result<crate::Address> {
             BDADDR_BREDR => Ok(bdaddr.to_br_edr_addr()),
             BDADDR_LE_PUBLIC => Ok(bdaddr.to_le_public_addr()),
             BDADDR_LE_RANDOM => Ok(bdaddr.to_le_random_addr()),
-            _ => Err(io::Error::new(
-                io::ErrorKind::Other,
-                "unexpected l2 address type.",
-            )),
+            _ => Err(io::Error::other("unexpected l2 address type.")),
         }
     } else {
-        Err(io::Error::new(
-            io::ErrorKind::Other,
-            "unexpected address family.",
-        ))
+        Err(io::Error::other("unexpected address family."))
     }
 }
 
+fn into_sock_addr(addr: &crate::Address, cid: libc::c_ushort) -> io::Result<SockAddr> {
+    let bdaddr_type = match addr.address_type() {
+        AddressType::BrEdr => BDADDR_BREDR,
+        AddressType::LePublic => BDADDR_LE_PUBLIC,
+        AddressType::LeRandom => BDADDR_LE_RANDOM,
+    };
+    sock_addr(addr.clone().into_bd_addr().into(), bdaddr_type, 0, cid)
+}
+
 #[derive(Debug)]
 pub(crate) struct AttStream {
-    inner: AsyncFd<Socket>,
+    inner: Arc<AsyncFd<Socket>>,
+}
+
+impl AttStream {
+    pub(crate) async fn connect(addr: &crate::Address) -> io::Result<Self> {
+        let sock = sock_open()?;
+        sock_bind(&sock, 0, ATT_CID)?;
+        let inner = async_fd(sock)?;
+
+        match inner.get_ref().connect(&into_sock_addr(addr, ATT_CID)?) {
+            Ok(()) => {}
+            Err(err) if err.raw_os_error() == Some(libc::EINPROGRESS) => {
+                drop(inner.writable().await?);
+                if let Some(err) = inner.get_ref().take_error()? {
+                    return Err(err);
+                }
+            }
+            Err(err) => return Err(err),
+        }
+        Ok(Self {
+            inner: Arc::new(inner),
+        })
+    }
+
+    /// The smaller of `BT_SNDMTU` and `BT_RCVMTU`.
+    pub(crate) fn mtu(&self) -> io::Result<u16> {
+        let fd = self.inner.as_raw_fd();
+        let snd = get_sockopt_bt_mtu(fd, BT_SNDMTU)?;
+        let rcv = get_sockopt_bt_mtu(fd, BT_RCVMTU)?;
+        Ok(snd.min(rcv))
+    }
+
+    /// Query `BT_SECURITY` while the socket is open.
+    pub(crate) fn security_query(&self) -> crate::SecurityQuery {
+        let sock = Arc::downgrade(&self.inner);
+        crate::SecurityQuery::new(move || {
+            let sock =
+                Weak::upgrade(&sock).ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+            let opt = get_sockopt_bt_security(sock.as_raw_fd())?;
+            Ok(crate::Security::from_level(opt.level, opt.key_size))
+        })
+    }
 }
 
 impl AsyncRead for AttStream {
@@ -182,16 +335,32 @@ pub(crate) struct AttListener {
 impl AttListener {
     pub(crate) fn new() -> io::Result<Self> {
         let sock = sock_open()?;
-        sock_bind(&sock, 0x0004)?;
+        sock_bind(&sock, 0, ATT_CID)?;
         sock.listen(1)?; // TODO backlog
         Ok(Self {
-            inner: AsyncFd::new(sock)?,
+            inner: async_fd(sock)?,
+        })
+    }
+
+    /// Listen for EATT bearers on L2CAP enhanced credit based channels.
+    pub(crate) fn new_eatt() -> io::Result<Self> {
+        let sock = sock_open()?;
+        set_sockopt_bt_mode(sock.as_raw_fd(), BT_MODE_EXT_FLOWCTL)?;
+        sock_bind(&sock, EATT_PSM, 0)?;
+        sock.listen(5)?;
+        Ok(Self {
+            inner: async_fd(sock)?,
         })
     }
 
     pub(crate) fn set_sockopt_bt_security(&self, level: u8, key_size: u8) -> io::Result<()> {
         set_sockopt_bt_security(self.inner.as_raw_fd(), level, key_size)
     }
+
+    /// Receive MTU of the L2CAP channels accepted afterwards.
+    pub(crate) fn set_sockopt_bt_rcvmtu(&self, mtu: u16) -> io::Result<()> {
+        set_sockopt_bt_rcvmtu(self.inner.as_raw_fd(), mtu)
+    }
 }
 
 impl Stream for AttListener {
@@ -205,7 +374,7 @@ impl Stream for AttListener {
                 let (sock, addr) = result?;
                 sock.set_nonblocking(true)?;
                 let sock = AttStream {
-                    inner: AsyncFd::new(sock)?,
+                    inner: Arc::new(async_fd(sock)?),
                 };
                 return Poll::Ready(Some(Ok((sock, addr))));
             }
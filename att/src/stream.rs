@@ -0,0 +1,255 @@
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use futures_core::ready;
+use futures_core::stream::Stream;
+use futures_sink::Sink;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::packet as pkt;
+use pkt::pack::{self, Unpack};
+
+pub(crate) const DEFAULT_MTU: usize = 23;
+
+/// Largest receive MTU a server offers unless configured otherwise.
+pub(crate) const DEFAULT_MAX_MTU: u16 = 517;
+
+/// ATT transaction timeout the spec requires.
+pub(crate) const DEFAULT_TRANSACTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    Pack(#[from] pack::Error),
+
+    /// Received PDU with the op code which could not be unpacked.
+    #[error("unparsable PDU {0:#04x}: {1}")]
+    Unparsable(u8, #[source] pack::Error),
+
+    /// A transaction timed out. No more PDUs are sent on the bearer.
+    #[error("transaction timed out")]
+    TransactionTimeout,
+
+    /// A value does not fit into a single notification or indication.
+    #[error("payload too large, at most {max} bytes fit into the current MTU")]
+    PayloadTooLarge { max: usize },
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::TransactionTimeout => io::Error::new(io::ErrorKind::TimedOut, err),
+            Error::PayloadTooLarge { .. } => io::Error::new(io::ErrorKind::InvalidInput, err),
+            err => io::Error::other(err),
+        }
+    }
+}
+
+pub(crate) type Result<R> = std::result::Result<R, Error>;
+
+/// Packet that may be sent on a stream receiving `P`.
+pub(crate) trait Outgoing<P> {
+    fn pack_with_code<W>(self, write: &mut W) -> pack::Result<()>
+    where
+        W: io::Write;
+}
+
+impl<S> Outgoing<pkt::DeviceRecv> for S
+where
+    S: pkt::DeviceSend,
+{
+    fn pack_with_code<W>(self, write: &mut W) -> pack::Result<()>
+    where
+        W: io::Write,
+    {
+        pkt::DeviceSend::pack_with_code(self, write)
+    }
+}
+
+impl<S> Outgoing<pkt::ClientRecv> for S
+where
+    S: pkt::ClientSend,
+{
+    fn pack_with_code<W>(self, write: &mut W) -> pack::Result<()>
+    where
+        W: io::Write,
+    {
+        pkt::ClientSend::pack_with_code(self, write)
+    }
+}
+
+pub(crate) struct PacketStream<R, P = pkt::DeviceRecv> {
+    inner: R,
+    rxbuf: Box<[u8]>,
+    txbuf: Box<[u8]>,
+    txlen: usize,
+    txwaker: Vec<Waker>,
+    timed_out: bool,
+    _phantom: PhantomData<fn() -> P>,
+}
+
+impl<R, P> PacketStream<R, P> {
+    pub(crate) fn new(inner: R) -> Self {
+        Self {
+            inner,
+            rxbuf: [0; DEFAULT_MTU].into(),
+            txbuf: [0; DEFAULT_MTU].into(),
+            txlen: 0,
+            txwaker: vec![],
+            timed_out: false,
+            _phantom: PhantomData,
+        }
+    }
+
+    pub(crate) fn txmtu(&self) -> usize {
+        self.txbuf.len()
+    }
+
+    /// Mark the bearer dead after a transaction timeout. Sending fails afterwards.
+    pub(crate) fn time_out(&mut self) {
+        self.timed_out = true;
+    }
+
+    pub(crate) fn set_txmtu(&mut self, mtu: usize) {
+        let mut buf = vec![0; mtu];
+        let len = mtu.min(self.txbuf.len());
+        buf[..len].copy_from_slice(&self.txbuf[..len]);
+        self.txbuf = buf.into();
+    }
+
+    pub(crate) fn set_rxmtu(&mut self, mtu: usize) {
+        let mut buf = vec![0; mtu];
+        let len = mtu.min(self.rxbuf.len());
+        buf[..len].copy_from_slice(&self.rxbuf[..len]);
+        self.rxbuf = buf.into();
+    }
+}
+
+impl<R, P> Stream for PacketStream<R, P>
+where
+    R: AsyncRead + Unpin,
+    P: Unpack + fmt::Debug,
+{
+    type Item = Result<P>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let Self { inner, rxbuf, .. } = self.get_mut();
+
+        let mut buf = ReadBuf::new(rxbuf);
+        ready!(Pin::new(inner).poll_read(cx, &mut buf))?;
+        let mut filled = buf.filled();
+        if filled.is_empty() {
+            Poll::Ready(None)
+        } else {
+            let opcode = filled[0];
+            let item = P::unpack(&mut filled).map_err(|err| Error::Unparsable(opcode, err))?;
+            log::trace!("packet recv {:?}", item);
+            Poll::Ready(Some(Ok(item)))
+        }
+    }
+}
+
+impl<W, P, S> Sink<S> for PacketStream<W, P>
+where
+    W: AsyncWrite + Unpin,
+    S: Outgoing<P> + fmt::Debug,
+{
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let Self {
+            txlen,
+            txwaker,
+            timed_out,
+            ..
+        } = self.get_mut();
+        if *timed_out {
+            Poll::Ready(Err(Error::TransactionTimeout))
+        } else if *txlen != 0 {
+            txwaker.push(cx.waker().clone());
+            Poll::Pending
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: S) -> Result<()> {
+        let Self { txlen, txbuf, .. } = self.get_mut();
+        log::trace!("packet send {:?}", item);
+
+        let mut write = txbuf.as_mut();
+        let len = write.len();
+        item.pack_with_code(&mut write)?;
+        *txlen = len - write.len();
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let Self {
+            inner,
+            txlen,
+            txbuf,
+            ..
+        } = self.get_mut();
+
+        while *txlen != 0 {
+            *txlen -= ready!(Pin::new(&mut *inner).poll_write(cx, &txbuf[..*txlen]))?;
+        }
+        ready!(Pin::new(&mut *inner).poll_flush(cx))?;
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        ready!(Sink::<S>::poll_flush(Pin::new(&mut *this), cx))?;
+        ready!(Pin::new(&mut this.inner).poll_shutdown(cx))?;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::sink::SinkExt;
+    use futures_util::stream::TryStreamExt;
+    use std::convert::TryFrom;
+    use tokio_test::io::Builder;
+
+    #[tokio::test]
+    async fn test_stream() {
+        let stream = Builder::new()
+            .read(&[0x02, 0x17, 0x00])
+            .write(&[0x03, 0x18, 0x00])
+            .build();
+        let mut stream = PacketStream::<_>::new(stream);
+        let packet = stream.try_next().await.unwrap().unwrap();
+        let packet = pkt::ExchangeMtuRequest::try_from(packet).unwrap();
+        assert_eq!(*packet.client_rx_mtu(), 23);
+
+        let packet = pkt::ExchangeMtuResponse::new(0x0018);
+        stream.send(packet).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_client_stream() {
+        let stream = Builder::new()
+            .write(&[0x0A, 0x03, 0x00])
+            .read(&[0x0B, 0x6F, 0x6B])
+            .build();
+        let mut stream = PacketStream::<_, pkt::ClientRecv>::new(stream);
+        stream
+            .send(pkt::ReadRequest::new(0x0003.into()))
+            .await
+            .unwrap();
+        let packet = stream.try_next().await.unwrap().unwrap();
+        let packet = pkt::ReadResponse::try_from(packet).unwrap();
+        assert_eq!(&**packet.attribute_value(), b"ok");
+    }
+}
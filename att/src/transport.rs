@@ -0,0 +1,11 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Packet-preserving transport for an ATT bearer.
+///
+/// Each read must yield exactly one PDU and each write must carry exactly one PDU,
+/// like L2CAP or `AF_UNIX` `SOCK_SEQPACKET` sockets.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T> Transport for T where T: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+pub(crate) type BoxTransport = Box<dyn Transport>;
@@ -9,7 +9,7 @@ macro_rules! parse_uuid {
     ($x:expr) => {
         use uuid::uuid as old_parse;
         old_parse!(x);
-    } 
+    };
 }
 
 use crate::packet::pack::{Error as PackError, Pack, Result as PackResult, Unpack};
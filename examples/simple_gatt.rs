@@ -22,12 +22,11 @@ fn new_registration() -> Registration<Token> {
     );
     registration.add_characteristic(
         ch::APPEARANCE,
-        0x03c0u16.to_le_bytes().to_vec(),
+        0x03c0u16.to_le_bytes(),
         CharacteristicProperties::READ,
     );
 
-    registration.add_primary_service(srv::GENERIC_ATTRIBUTE);
-    registration.add_characteristic(ch::SERVICE_CHANGED, "", CharacteristicProperties::INDICATE);
+    registration.add_generic_attribute_service();
 
     registration.add_primary_service(srv::DEVICE_INFORMATION);
     registration.add_characteristic(
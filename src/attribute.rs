@@ -1,5 +1,10 @@
 //use bytes::Buf;
 
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+
+use att::packet::ErrorCode;
 use att::uuid::Uuid16;
 use att::{Handle, Uuid};
 
@@ -41,16 +46,102 @@ pub(crate) enum Error {
     #[error("authentication required")]
     AuthenticationRequired,
 
+    #[error("encryption required")]
+    EncryptionRequired,
+
+    #[error("encryption key size too short")]
+    EncryptionKeySizeTooShort,
+
     #[error("invalid data length")]
     InvalidDataLength,
+
+    #[error("improperly configured")]
+    ImproperlyConfigured,
+
+    #[error("value not allowed")]
+    ValueNotAllowed,
+
+    #[error("application error {0:?}")]
+    Application(ErrorCode),
+}
+
+/// Value shared by every clone of the attribute.
+///
+/// Databases cloned for each connection share values but not configurations.
+#[derive(Debug, Clone)]
+pub(crate) struct Value(Arc<Mutex<Box<[u8]>>>);
+
+impl Value {
+    fn new(value: Box<[u8]>) -> Self {
+        Self(Arc::new(Mutex::new(value)))
+    }
+
+    fn get(&self) -> Box<[u8]> {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn set(&self, value: Box<[u8]>) {
+        *self.0.lock().unwrap() = value;
+    }
+}
+
+/// Computes a characteristic value at read time.
+#[derive(Clone)]
+pub(crate) struct Reader(Arc<dyn Fn() -> Result<Box<[u8]>, ErrorCode> + Send + Sync>);
+
+impl Reader {
+    pub(crate) fn new<F>(f: F) -> Self
+    where
+        F: Fn() -> Result<Box<[u8]>, ErrorCode> + Send + Sync + 'static,
+    {
+        Self(Arc::new(f))
+    }
+
+    fn read(&self) -> Result<Box<[u8]>, ErrorCode> {
+        (self.0)()
+    }
+}
+
+impl fmt::Debug for Reader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Reader").finish()
+    }
 }
 
 bitflags::bitflags! {
-    pub(crate) struct Permission: u8 {
+    pub(crate) struct Permission: u16 {
         const READABLE = 0b0000_0001;
         const WRITEABLE = 0b0000_0010;
-        const AUTHORIZATION_REQUIRED = 0b0000_0100;
-        const AUTHENTICATION_REQUIRED = 0b0000_1000;
+        const READ_AUTHORIZATION_REQUIRED = 0b0000_0100;
+        const READ_AUTHENTICATION_REQUIRED = 0b0000_1000;
+        const READ_ENCRYPTION_REQUIRED = 0b0001_0000;
+        const WRITE_AUTHORIZATION_REQUIRED = 0b0010_0000;
+        const WRITE_AUTHENTICATION_REQUIRED = 0b0100_0000;
+        const WRITE_ENCRYPTION_REQUIRED = 0b1000_0000;
+        const AUTHORIZATION_REQUIRED =
+            Self::READ_AUTHORIZATION_REQUIRED.bits | Self::WRITE_AUTHORIZATION_REQUIRED.bits;
+        const AUTHENTICATION_REQUIRED =
+            Self::READ_AUTHENTICATION_REQUIRED.bits | Self::WRITE_AUTHENTICATION_REQUIRED.bits;
+    }
+}
+
+impl Permission {
+    /// Authorization, authentication and encryption flags of the direction
+    /// `access` (`READABLE` or `WRITEABLE`).
+    fn requirements(access: Self) -> (Self, Self, Self) {
+        if access == Self::READABLE {
+            (
+                Self::READ_AUTHORIZATION_REQUIRED,
+                Self::READ_AUTHENTICATION_REQUIRED,
+                Self::READ_ENCRYPTION_REQUIRED,
+            )
+        } else {
+            (
+                Self::WRITE_AUTHORIZATION_REQUIRED,
+                Self::WRITE_AUTHENTICATION_REQUIRED,
+                Self::WRITE_ENCRYPTION_REQUIRED,
+            )
+        }
     }
 }
 
@@ -87,6 +178,14 @@ bitflags::bitflags! {
     }
 }
 
+bitflags::bitflags! {
+    pub(crate) struct ClientSupportedFeatures: u8 {
+        const ROBUST_CACHING = 0b0001;
+        const EATT = 0b0010;
+        const MULTIPLE_HANDLE_VALUE_NOTIFICATIONS = 0b0100;
+    }
+}
+
 const PRIMARY_SERVICE: Uuid = Uuid::Uuid16(Uuid16::new(0x2800));
 
 const SECONDARY_SERVICE: Uuid = Uuid::Uuid16(Uuid16::new(0x2801));
@@ -107,7 +206,7 @@ const CHARACTERISTIC_PRESENTATION_FORMAT: Uuid = Uuid::Uuid16(Uuid16::new(0x2904
 
 const CHARACTERISTIC_AGGREGATE_FORMAT: Uuid = Uuid::Uuid16(Uuid16::new(0x2905));
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) enum Attribute {
     Service {
         handle: Handle,
@@ -133,8 +232,10 @@ pub(crate) enum Attribute {
     CharacteristicValue {
         handle: Handle,
         attr_type: Uuid,
-        value: Box<[u8]>,
+        value: Value,
+        reader: Option<Reader>,
         permission: Permission,
+        key_size: u8,
     },
 
     CharacteristicExtendedProperties {
@@ -180,8 +281,15 @@ pub(crate) enum Attribute {
     Descriptor {
         handle: Handle,
         uuid: Uuid,
-        value: Box<[u8]>,
+        value: Value,
         permission: Permission,
+        key_size: u8,
+    },
+
+    /// Client Supported Features characteristic value. Kept per connection.
+    ClientSupportedFeatures {
+        handle: Handle,
+        features: ClientSupportedFeatures,
     },
 }
 
@@ -241,8 +349,26 @@ impl Attribute {
         Self::CharacteristicValue {
             handle,
             attr_type,
-            value,
+            value: Value::new(value),
+            reader: None,
+            permission,
+            key_size: 0,
+        }
+    }
+
+    pub(crate) fn new_dynamic_characteristic_value(
+        handle: Handle,
+        attr_type: Uuid,
+        reader: Reader,
+        permission: Permission,
+    ) -> Self {
+        Self::CharacteristicValue {
+            handle,
+            attr_type,
+            value: Value::new([].into()),
+            reader: Some(reader),
             permission,
+            key_size: 0,
         }
     }
 
@@ -310,6 +436,13 @@ impl Attribute {
         }
     }
 
+    pub(crate) fn new_client_supported_features(handle: Handle) -> Self {
+        Self::ClientSupportedFeatures {
+            handle,
+            features: ClientSupportedFeatures::empty(),
+        }
+    }
+
     pub(crate) fn new_descriptor(
         handle: Handle,
         uuid: Uuid,
@@ -319,8 +452,9 @@ impl Attribute {
         Self::Descriptor {
             handle,
             uuid,
-            value,
+            value: Value::new(value),
             permission,
+            key_size: 0,
         }
     }
 }
@@ -339,9 +473,63 @@ impl Attribute {
             Self::CharacteristicPresentationFormat { handle, .. } => handle,
             Self::CharacteristicAggregateFormat { handle, .. } => handle,
             Self::Descriptor { handle, .. } => handle,
+            Self::ClientSupportedFeatures { handle, .. } => handle,
+        }
+    }
+
+    /// Move the attribute `offset` handles up, together with the handles it
+    /// refers to within `moved`, the range all moved attributes come from.
+    pub(crate) fn relocate(&mut self, offset: u16, moved: &RangeInclusive<Handle>) {
+        let shift = |handle: &mut Handle| {
+            if moved.contains(handle) {
+                *handle = Handle::new(handle.as_u16() + offset)
+            }
+        };
+        match self {
+            Self::Service { handle, .. } => shift(handle),
+            Self::Include {
+                handle,
+                included_service_handle,
+                end_group_handle,
+                ..
+            } => {
+                shift(handle);
+                // an already present service stays where it is
+                if moved.contains(included_service_handle) {
+                    shift(included_service_handle);
+                    shift(end_group_handle);
+                }
+            }
+            Self::Characteristic {
+                handle,
+                value_handle,
+                ..
+            } => {
+                shift(handle);
+                shift(value_handle);
+            }
+            Self::CharacteristicValue { handle, .. } => shift(handle),
+            Self::CharacteristicExtendedProperties { handle, .. } => shift(handle),
+            Self::CharacteristicUserDescription { handle, .. } => shift(handle),
+            Self::ClientCharacteristicConfiguration { handle, .. } => shift(handle),
+            Self::ServerCharacteristicConfiguration { handle, .. } => shift(handle),
+            Self::CharacteristicPresentationFormat { handle, .. } => shift(handle),
+            Self::CharacteristicAggregateFormat {
+                handle,
+                attribute_handles,
+            } => {
+                shift(handle);
+                attribute_handles.iter_mut().for_each(shift);
+            }
+            Self::Descriptor { handle, .. } => shift(handle),
+            Self::ClientSupportedFeatures { handle, .. } => shift(handle),
         }
     }
 
+    pub(crate) fn is_service(&self) -> bool {
+        matches!(self, Self::Service { .. })
+    }
+
     pub(crate) fn attr_type(&self) -> &Uuid {
         match self {
             Self::Service { primary, .. } if *primary => &PRIMARY_SERVICE,
@@ -356,6 +544,9 @@ impl Attribute {
             Self::CharacteristicPresentationFormat { .. } => &CHARACTERISTIC_PRESENTATION_FORMAT,
             Self::CharacteristicAggregateFormat { .. } => &CHARACTERISTIC_AGGREGATE_FORMAT,
             Self::Descriptor { uuid, .. } => uuid,
+            Self::ClientSupportedFeatures { .. } => {
+                &crate::characteristics::CLIENT_SUPPORTED_FEATURES
+            }
         }
     }
 
@@ -372,31 +563,106 @@ impl Attribute {
             Self::CharacteristicPresentationFormat { .. } => Permission::READABLE,
             Self::CharacteristicAggregateFormat { .. } => Permission::READABLE,
             Self::Descriptor { permission, .. } => *permission,
+            Self::ClientSupportedFeatures { .. } => Permission::READABLE | Permission::WRITEABLE,
+        }
+    }
+
+    /// Minimum encryption key size where encryption is required. `0` for any.
+    fn key_size(&self) -> u8 {
+        match self {
+            Self::CharacteristicValue { key_size, .. } | Self::Descriptor { key_size, .. } => {
+                *key_size
+            }
+            _ => 0,
         }
     }
 
-    pub(crate) fn get(&self, authorized: bool, authenticated: bool) -> Result<Box<[u8]>, Error> {
-        if !self.permission().contains(Permission::READABLE) {
+    /// Add security requirements. Only characteristic values and descriptors
+    /// carry them; returns `false` for other attributes.
+    pub(crate) fn require_security(&mut self, required: Permission, min_key_size: u8) -> bool {
+        match self {
+            Self::CharacteristicValue {
+                permission,
+                key_size,
+                ..
+            }
+            | Self::Descriptor {
+                permission,
+                key_size,
+                ..
+            } => {
+                *permission |= required;
+                *key_size = min_key_size;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// `authorize` is called last, and only if the attribute requires authorization.
+    fn check_permission<A>(
+        &self,
+        required: Permission,
+        authorize: A,
+        security: &att::Security,
+    ) -> Result<(), Error>
+    where
+        A: FnOnce() -> bool,
+    {
+        let permission = self.permission();
+        if !permission.contains(required) {
             return Err(Error::PermissionDenied);
         }
 
-        if !authorized
-            && self
-                .permission()
-                .contains(Permission::AUTHORIZATION_REQUIRED)
+        let (authorization, authentication, encryption) = Permission::requirements(required);
+        if !security.authenticated && permission.contains(authentication) {
+            return Err(Error::AuthenticationRequired);
+        }
+
+        if !security.encrypted && permission.contains(encryption) {
+            return Err(Error::EncryptionRequired);
+        }
+
+        if permission.intersects(authentication | encryption) && security.key_size < self.key_size()
         {
+            return Err(Error::EncryptionKeySizeTooShort);
+        }
+
+        if permission.contains(authorization) && !authorize() {
             return Err(Error::AuthorizationRequired);
         }
 
-        if !authenticated
-            && self
-                .permission()
-                .contains(Permission::AUTHENTICATION_REQUIRED)
+        Ok(())
+    }
+
+    pub(crate) fn get<A>(&self, authorize: A, security: &att::Security) -> Result<Box<[u8]>, Error>
+    where
+        A: FnOnce() -> bool,
+    {
+        self.check_permission(Permission::READABLE, authorize, security)?;
+        if let Self::CharacteristicValue {
+            reader: Some(reader),
+            ..
+        } = self
         {
-            return Err(Error::AuthenticationRequired);
+            return reader.read().map_err(Error::Application);
+        }
+        Ok(self.value())
+    }
+
+    /// Length of the value, unless computed at read time.
+    pub(crate) fn stored_len(&self) -> Option<usize> {
+        match self {
+            Self::CharacteristicValue {
+                reader: Some(..), ..
+            } => None,
+            _ => Some(self.value().len()),
         }
+    }
 
-        Ok(match self {
+    /// Stored value regardless of permission.
+    pub(crate) fn value(&self) -> Box<[u8]> {
+        match self {
             Self::Service { uuid, .. } => match uuid {
                 Uuid::Uuid16(uuid) => uuid.as_u16().to_le_bytes().to_vec().into(),
                 Uuid::Uuid128(uuid) => uuid.as_u128().to_le_bytes().to_vec().into(),
@@ -434,14 +700,15 @@ impl Attribute {
                 result.into()
             }
 
-            Self::CharacteristicValue { value, .. } => value.clone(),
+            Self::CharacteristicValue { value, .. } => value.get(),
 
             Self::CharacteristicExtendedProperties {
                 extended_properties,
                 ..
             } => {
+                // a 16-bit field of which only the low octet is defined
                 let mut result = vec![];
-                result.extend_from_slice(&extended_properties.bits().to_le_bytes());
+                result.extend_from_slice(&u16::from(extended_properties.bits()).to_le_bytes());
                 result.into()
             }
 
@@ -488,36 +755,50 @@ impl Attribute {
                 result.into()
             }
 
-            Self::Descriptor { value, .. } => value.clone(),
-        })
-    }
+            Self::Descriptor { value, .. } => value.get(),
 
-    pub(crate) fn set(
-        &mut self,
-        mut val: &[u8],
-        authorized: bool,
-        authenticated: bool,
-    ) -> Result<(), Error> {
-        if !self.permission().contains(Permission::WRITEABLE) {
-            return Err(Error::PermissionDenied);
+            Self::ClientSupportedFeatures { features, .. } => vec![features.bits()].into(),
         }
+    }
 
-        if !authorized
-            && self
-                .permission()
-                .contains(Permission::AUTHORIZATION_REQUIRED)
-        {
-            return Err(Error::AuthorizationRequired);
+    /// Copy which no longer shares the value with `self`.
+    pub(crate) fn detached(&self) -> Self {
+        let mut attr = self.clone();
+        match &mut attr {
+            Self::CharacteristicValue { value, .. } | Self::Descriptor { value, .. } => {
+                *value = Value::new(value.get());
+            }
+            _ => {}
         }
+        attr
+    }
 
-        if !authenticated
-            && self
-                .permission()
-                .contains(Permission::AUTHENTICATION_REQUIRED)
-        {
-            return Err(Error::AuthenticationRequired);
-        }
+    pub(crate) fn check_writable<A>(
+        &self,
+        authorize: A,
+        security: &att::Security,
+    ) -> Result<(), Error>
+    where
+        A: FnOnce() -> bool,
+    {
+        self.check_permission(Permission::WRITEABLE, authorize, security)
+    }
 
+    pub(crate) fn set<A>(
+        &mut self,
+        val: &[u8],
+        authorize: A,
+        security: &att::Security,
+    ) -> Result<(), Error>
+    where
+        A: FnOnce() -> bool,
+    {
+        self.check_writable(authorize, security)?;
+        self.store(val)
+    }
+
+    /// Replace value regardless of permission.
+    pub(crate) fn store(&mut self, mut val: &[u8]) -> Result<(), Error> {
         match self {
             Self::Service { uuid, .. } => match val.len() {
                 2 => *uuid = Uuid::new_uuid16(val.get_u16_le()),
@@ -563,8 +844,13 @@ impl Attribute {
                 }
             }
 
+            // computed on every read, so a written value is not kept
+            Self::CharacteristicValue {
+                reader: Some(..), ..
+            } => {}
+
             Self::CharacteristicValue { value, .. } => {
-                *value = val.into();
+                value.set(val.into());
             }
 
             Self::CharacteristicExtendedProperties {
@@ -586,11 +872,11 @@ impl Attribute {
             }
 
             Self::ClientCharacteristicConfiguration { configuration, .. } => {
-                if val.len() < 2 {
+                if val.len() != 2 {
                     return Err(Error::InvalidDataLength);
                 }
-                *configuration =
-                    ClientCharacteristicConfiguration::from_bits_truncate(val.get_u16_le());
+                *configuration = ClientCharacteristicConfiguration::from_bits(val.get_u16_le())
+                    .ok_or(Error::ImproperlyConfigured)?;
             }
 
             Self::ServerCharacteristicConfiguration { configuration, .. } => {
@@ -632,7 +918,19 @@ impl Attribute {
                 *attribute_handles = v;
             }
 
-            Self::Descriptor { value, .. } => *value = val.into(),
+            Self::Descriptor { value, .. } => value.set(val.into()),
+
+            Self::ClientSupportedFeatures { features, .. } => {
+                if val.is_empty() {
+                    return Err(Error::InvalidDataLength);
+                }
+                // a client shall not clear a bit it has set
+                let new = ClientSupportedFeatures::from_bits_truncate(val.get_u8());
+                if !new.contains(*features) {
+                    return Err(Error::ValueNotAllowed);
+                }
+                *features = new;
+            }
         };
         Ok(())
     }
@@ -0,0 +1,538 @@
+//! GATT Protocol Client
+use std::convert::TryInto;
+use std::io;
+
+use att::client::{Client as AttClient, Error as AttError};
+use att::packet as pkt;
+use att::uuid::Uuid16;
+use att::{Address, Handle, Uuid};
+
+use crate::CharacteristicProperties;
+
+const PRIMARY_SERVICE: Uuid16 = Uuid16::new(0x2800);
+const INCLUDE: Uuid = Uuid::new_uuid16(0x2802);
+const CHARACTERISTIC: Uuid = Uuid::new_uuid16(0x2803);
+
+/// Error for [`Client`]
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Att(#[from] AttError),
+
+    #[error("invalid attribute value {0:?}")]
+    InvalidValue(Box<[u8]>),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Discovered Service
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Service {
+    handle: Handle,
+    end_group_handle: Handle,
+    uuid: Uuid,
+    includes: Vec<Include>,
+    characteristics: Vec<Characteristic>,
+}
+
+impl Service {
+    fn new(handle: Handle, end_group_handle: Handle, uuid: Uuid) -> Self {
+        Self {
+            handle,
+            end_group_handle,
+            uuid,
+            includes: vec![],
+            characteristics: vec![],
+        }
+    }
+
+    pub fn handle(&self) -> &Handle {
+        &self.handle
+    }
+
+    pub fn end_group_handle(&self) -> &Handle {
+        &self.end_group_handle
+    }
+
+    pub fn uuid(&self) -> &Uuid {
+        &self.uuid
+    }
+
+    /// Included services. Populated by [`Client::discover_all`].
+    pub fn includes(&self) -> &[Include] {
+        &self.includes
+    }
+
+    /// Characteristics. Populated by [`Client::discover_all`].
+    pub fn characteristics(&self) -> &[Characteristic] {
+        &self.characteristics
+    }
+}
+
+/// Discovered Include Declaration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Include {
+    handle: Handle,
+    included_service_handle: Handle,
+    end_group_handle: Handle,
+    uuid: Uuid,
+}
+
+impl Include {
+    pub fn handle(&self) -> &Handle {
+        &self.handle
+    }
+
+    pub fn included_service_handle(&self) -> &Handle {
+        &self.included_service_handle
+    }
+
+    pub fn end_group_handle(&self) -> &Handle {
+        &self.end_group_handle
+    }
+
+    pub fn uuid(&self) -> &Uuid {
+        &self.uuid
+    }
+}
+
+/// Discovered Characteristic
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Characteristic {
+    handle: Handle,
+    properties: CharacteristicProperties,
+    value_handle: Handle,
+    end_handle: Handle,
+    uuid: Uuid,
+    descriptors: Vec<Descriptor>,
+}
+
+impl Characteristic {
+    /// Characteristic declaration handle.
+    pub fn handle(&self) -> &Handle {
+        &self.handle
+    }
+
+    pub fn properties(&self) -> CharacteristicProperties {
+        self.properties
+    }
+
+    pub fn value_handle(&self) -> &Handle {
+        &self.value_handle
+    }
+
+    /// Last handle of this characteristic definition.
+    pub fn end_handle(&self) -> &Handle {
+        &self.end_handle
+    }
+
+    pub fn uuid(&self) -> &Uuid {
+        &self.uuid
+    }
+
+    /// Descriptors. Populated by [`Client::discover_all`].
+    pub fn descriptors(&self) -> &[Descriptor] {
+        &self.descriptors
+    }
+}
+
+/// Discovered Characteristic Descriptor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Descriptor {
+    handle: Handle,
+    uuid: Uuid,
+}
+
+impl Descriptor {
+    pub fn handle(&self) -> &Handle {
+        &self.handle
+    }
+
+    pub fn uuid(&self) -> &Uuid {
+        &self.uuid
+    }
+}
+
+fn uuid_from_bytes(b: &[u8]) -> Option<Uuid> {
+    match b.len() {
+        2 => Some(Uuid::new_uuid16(u16::from_le_bytes(b.try_into().ok()?))),
+        16 => Some(Uuid::new_uuid128(u128::from_le_bytes(b.try_into().ok()?))),
+        _ => None,
+    }
+}
+
+fn uuid_to_bytes(uuid: &Uuid) -> Box<[u8]> {
+    match uuid {
+        Uuid::Uuid16(uuid) => uuid.as_u16().to_le_bytes().into(),
+        Uuid::Uuid128(uuid) => uuid.as_u128().to_le_bytes().into(),
+    }
+}
+
+/// Parse Include Declaration value. UUID is absent for 128bit services.
+fn parse_include(value: &[u8]) -> Option<(Handle, Handle, Option<Uuid>)> {
+    if value.len() != 4 && value.len() != 6 {
+        return None;
+    }
+    let included_service_handle = u16::from_le_bytes(value[0..2].try_into().ok()?);
+    let end_group_handle = u16::from_le_bytes(value[2..4].try_into().ok()?);
+    let uuid = uuid_from_bytes(&value[4..]);
+    Some((
+        included_service_handle.into(),
+        end_group_handle.into(),
+        uuid,
+    ))
+}
+
+/// Parse Characteristic Declaration value.
+fn parse_characteristic(value: &[u8]) -> Option<(CharacteristicProperties, Handle, Uuid)> {
+    if value.len() < 3 {
+        return None;
+    }
+    let properties = CharacteristicProperties::from_bits_truncate(value[0] as u32);
+    let value_handle = u16::from_le_bytes(value[1..3].try_into().ok()?);
+    let uuid = uuid_from_bytes(&value[3..])?;
+    Some((properties, value_handle.into(), uuid))
+}
+
+/// Next start handle, or `None` when `last` reached `end`.
+fn next_handle(last: &Handle, end: &Handle) -> Option<Handle> {
+    if last >= end {
+        None
+    } else {
+        Some(Handle::new(last.as_u16() + 1))
+    }
+}
+
+fn is_attribute_not_found(err: &AttError) -> bool {
+    matches!(err, AttError::ErrorResponse(err) if err.error_code() == &pkt::ErrorCode::AttributeNotFound)
+}
+
+/// GATT Client
+pub struct Client {
+    inner: AttClient,
+}
+
+impl Client {
+    /// Connect to remote device.
+    pub async fn connect(address: &Address) -> io::Result<Self> {
+        let inner = AttClient::connect(address).await?;
+        Ok(Self { inner })
+    }
+
+    /// Run over an arbitrary packet-preserving transport.
+    pub fn new<IO>(io: IO, address: Address) -> Self
+    where
+        IO: att::Transport,
+    {
+        Self {
+            inner: AttClient::new(io, address),
+        }
+    }
+
+    pub fn address(&self) -> &Address {
+        self.inner.address()
+    }
+
+    /// Exchange MTU. Returns server receive MTU.
+    pub async fn exchange_mtu(&self, client_rx_mtu: u16) -> Result<u16> {
+        let response = self.inner.exchange_mtu(client_rx_mtu).await?;
+        Ok(*response.server_rx_mtu())
+    }
+
+    /// Discover All Primary Services
+    pub async fn discover_primary_services(&self) -> Result<Vec<Service>> {
+        let mut result = vec![];
+        let mut start = Some(Handle::new(0x0001));
+        while let Some(handle) = start.take() {
+            let response = match self
+                .inner
+                .read_by_group_type(handle, 0xFFFF.into(), PRIMARY_SERVICE.into())
+                .await
+            {
+                Ok(response) => response,
+                Err(err) if is_attribute_not_found(&err) => break,
+                Err(err) => return Err(err.into()),
+            };
+
+            for (handle, end_group_handle, value) in response {
+                let uuid = uuid_from_bytes(&value).ok_or(Error::InvalidValue(value))?;
+                start = next_handle(&end_group_handle, &0xFFFF.into());
+                result.push(Service::new(handle, end_group_handle, uuid));
+            }
+        }
+        Ok(result)
+    }
+
+    /// Discover Primary Service by Service UUID
+    pub async fn discover_primary_services_by_uuid(&self, uuid: &Uuid) -> Result<Vec<Service>> {
+        let value = uuid_to_bytes(uuid);
+        let mut result = vec![];
+        let mut start = Some(Handle::new(0x0001));
+        while let Some(handle) = start.take() {
+            let response = match self
+                .inner
+                .find_by_type_value(handle, 0xFFFF.into(), PRIMARY_SERVICE, &value)
+                .await
+            {
+                Ok(response) => response,
+                Err(err) if is_attribute_not_found(&err) => break,
+                Err(err) => return Err(err.into()),
+            };
+
+            for (handle, end_group_handle) in response {
+                start = next_handle(&end_group_handle, &0xFFFF.into());
+                result.push(Service::new(handle, end_group_handle, uuid.clone()));
+            }
+        }
+        Ok(result)
+    }
+
+    /// Find Included Services
+    pub async fn find_included_services(&self, service: &Service) -> Result<Vec<Include>> {
+        let mut result = vec![];
+        let mut start = Some(service.handle.clone());
+        while let Some(handle) = start.take() {
+            let response = match self
+                .inner
+                .read_by_type(handle, service.end_group_handle.clone(), INCLUDE)
+                .await
+            {
+                Ok(response) => response,
+                Err(err) if is_attribute_not_found(&err) => break,
+                Err(err) => return Err(err.into()),
+            };
+
+            for (handle, value) in response {
+                let (included_service_handle, end_group_handle, uuid) = match parse_include(&value)
+                {
+                    Some(v) => v,
+                    None => return Err(Error::InvalidValue(value)),
+                };
+                let uuid = match uuid {
+                    Some(uuid) => uuid,
+                    None => {
+                        let response = self.inner.read(included_service_handle.clone()).await?;
+                        let value = response.attribute_value();
+                        uuid_from_bytes(value).ok_or_else(|| Error::InvalidValue(value.clone()))?
+                    }
+                };
+                start = next_handle(&handle, &service.end_group_handle);
+                result.push(Include {
+                    handle,
+                    included_service_handle,
+                    end_group_handle,
+                    uuid,
+                });
+            }
+        }
+        Ok(result)
+    }
+
+    /// Discover All Characteristics of a Service
+    pub async fn discover_characteristics(&self, service: &Service) -> Result<Vec<Characteristic>> {
+        let mut result = vec![];
+        let mut start = Some(service.handle.clone());
+        while let Some(handle) = start.take() {
+            let response = match self
+                .inner
+                .read_by_type(handle, service.end_group_handle.clone(), CHARACTERISTIC)
+                .await
+            {
+                Ok(response) => response,
+                Err(err) if is_attribute_not_found(&err) => break,
+                Err(err) => return Err(err.into()),
+            };
+
+            for (handle, value) in response {
+                let (properties, value_handle, uuid) = match parse_characteristic(&value) {
+                    Some(v) => v,
+                    None => return Err(Error::InvalidValue(value)),
+                };
+                start = next_handle(&handle, &service.end_group_handle);
+                if let Some(last) = result.last_mut() {
+                    let last: &mut Characteristic = last;
+                    last.end_handle = Handle::new(handle.as_u16() - 1);
+                }
+                result.push(Characteristic {
+                    handle,
+                    properties,
+                    value_handle,
+                    end_handle: service.end_group_handle.clone(),
+                    uuid,
+                    descriptors: vec![],
+                });
+            }
+        }
+        Ok(result)
+    }
+
+    /// Discover All Characteristic Descriptors
+    pub async fn discover_descriptors(
+        &self,
+        characteristic: &Characteristic,
+    ) -> Result<Vec<Descriptor>> {
+        let mut result = vec![];
+        let mut start = next_handle(&characteristic.value_handle, &characteristic.end_handle);
+        while let Some(handle) = start.take() {
+            let response = match self
+                .inner
+                .find_information(handle, characteristic.end_handle.clone())
+                .await
+            {
+                Ok(response) => response,
+                Err(err) if is_attribute_not_found(&err) => break,
+                Err(err) => return Err(err.into()),
+            };
+
+            for (handle, uuid) in response {
+                start = next_handle(&handle, &characteristic.end_handle);
+                result.push(Descriptor { handle, uuid });
+            }
+        }
+        Ok(result)
+    }
+
+    /// Discover all primary services with their included services,
+    /// characteristics and descriptors.
+    pub async fn discover_all(&self) -> Result<Vec<Service>> {
+        let mut services = self.discover_primary_services().await?;
+        for service in &mut services {
+            service.includes = self.find_included_services(service).await?;
+            let mut characteristics = self.discover_characteristics(service).await?;
+            for characteristic in &mut characteristics {
+                characteristic.descriptors = self.discover_descriptors(characteristic).await?;
+            }
+            service.characteristics = characteristics;
+        }
+        Ok(services)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::characteristics as ch;
+    use crate::server::Connection;
+    use crate::services as srv;
+    use crate::testing::Packet;
+    use crate::Registration;
+
+    #[tokio::test]
+    async fn test_discover_all() {
+        let mut registration = Registration::<()>::new();
+        registration.add_primary_service(srv::BATTERY);
+        registration.add_characteristic(
+            ch::BATTERY_LEVEL,
+            vec![100],
+            CharacteristicProperties::READ | CharacteristicProperties::NOTIFY,
+        );
+        registration.add_primary_service(srv::DEVICE_INFORMATION);
+        registration.add_characteristic(
+            ch::MANUFACTURER_NAME_STRING,
+            "abc",
+            CharacteristicProperties::READ,
+        );
+
+        let (server, client) = Packet::pair();
+        let address = Address::le_public_from([0; 6]);
+        let connection = Connection::new(server, address.clone(), registration);
+        let task = tokio::spawn(connection.run());
+
+        let client = Client::new(client, address);
+        let services = client.discover_all().await.unwrap();
+        assert_eq!(
+            services,
+            vec![
+                Service {
+                    handle: 0x0001.into(),
+                    end_group_handle: 0x0004.into(),
+                    uuid: srv::BATTERY,
+                    includes: vec![],
+                    characteristics: vec![Characteristic {
+                        handle: 0x0002.into(),
+                        properties: CharacteristicProperties::READ
+                            | CharacteristicProperties::NOTIFY,
+                        value_handle: 0x0003.into(),
+                        end_handle: 0x0004.into(),
+                        uuid: ch::BATTERY_LEVEL,
+                        descriptors: vec![Descriptor {
+                            handle: 0x0004.into(),
+                            uuid: Uuid::new_uuid16(0x2902),
+                        }],
+                    }],
+                },
+                Service {
+                    handle: 0x0005.into(),
+                    end_group_handle: 0x0007.into(),
+                    uuid: srv::DEVICE_INFORMATION,
+                    includes: vec![],
+                    characteristics: vec![Characteristic {
+                        handle: 0x0006.into(),
+                        properties: CharacteristicProperties::READ,
+                        value_handle: 0x0007.into(),
+                        end_handle: 0x0007.into(),
+                        uuid: ch::MANUFACTURER_NAME_STRING,
+                        descriptors: vec![],
+                    }],
+                },
+            ]
+        );
+
+        let services = client
+            .discover_primary_services_by_uuid(&srv::DEVICE_INFORMATION)
+            .await
+            .unwrap();
+        assert_eq!(
+            services,
+            vec![Service::new(
+                0x0005.into(),
+                0x0007.into(),
+                srv::DEVICE_INFORMATION
+            )]
+        );
+
+        drop(client);
+        task.abort();
+    }
+
+    #[test]
+    fn test_parse_include() {
+        let (handle, end, uuid) = parse_include(&[0x10, 0x00, 0x16, 0x00, 0x0A, 0x18]).unwrap();
+        assert_eq!(handle, 0x0010.into());
+        assert_eq!(end, 0x0016.into());
+        assert_eq!(uuid, Some(Uuid::new_uuid16(0x180A)));
+
+        let (_, _, uuid) = parse_include(&[0x10, 0x00, 0x16, 0x00]).unwrap();
+        assert_eq!(uuid, None);
+
+        assert!(parse_include(&[0x10, 0x00, 0x16]).is_none());
+    }
+
+    #[test]
+    fn test_parse_characteristic() {
+        let (properties, value_handle, uuid) =
+            parse_characteristic(&[0x12, 0x25, 0x00, 0x19, 0x2A]).unwrap();
+        assert_eq!(
+            properties,
+            CharacteristicProperties::READ | CharacteristicProperties::NOTIFY
+        );
+        assert_eq!(value_handle, 0x0025.into());
+        assert_eq!(uuid, Uuid::new_uuid16(0x2A19));
+
+        let mut value = vec![0x02, 0x03, 0x00];
+        value.extend_from_slice(&0x1234u128.to_le_bytes());
+        let (_, _, uuid) = parse_characteristic(&value).unwrap();
+        assert_eq!(uuid, Uuid::new_uuid128(0x1234));
+
+        assert!(parse_characteristic(&[0x02, 0x03, 0x00, 0x19]).is_none());
+    }
+
+    #[test]
+    fn test_next_handle() {
+        assert_eq!(
+            next_handle(&0x0005.into(), &0xFFFF.into()),
+            Some(0x0006.into())
+        );
+        assert_eq!(next_handle(&0xFFFF.into(), &0xFFFF.into()), None);
+    }
+}
@@ -1,29 +1,51 @@
 use std::collections::BTreeMap;
 use std::iter::FromIterator;
+use std::ops::Bound;
 use std::ops::RangeInclusive;
 
 use att::packet::ErrorCode;
 use att::uuid::Uuid16;
 use att::{Handle, Uuid};
 
-use crate::attribute::{Attribute, Error as AttrError};
+use crate::attribute::{
+    Attribute, ClientCharacteristicConfiguration, ClientSupportedFeatures, Error as AttrError,
+};
 
 type Result<T> = std::result::Result<T, (Handle, ErrorCode)>;
 
-#[derive(Debug)]
+/// Client Characteristic Configuration Descriptor Improperly Configured
+const CCCD_IMPROPERLY_CONFIGURED: u8 = 0xFD;
+
+/// Change-aware state of the client.
+///
+/// ref BLUETOOTH CORE SPECIFICATION Version 5.1 | Vol 3, Part G | 2.5.2.1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChangeAwareness {
+    Aware,
+    Unaware,
+    OutOfSyncReported,
+}
+
+#[derive(Debug, Clone)]
 pub(crate) struct Database {
     attrs: BTreeMap<Handle, Attribute>,
+    awareness: ChangeAwareness,
 }
 
 impl Database {
+    /// `authorize` is asked about each attribute which requires authorization,
+    /// once it passed every other check and is about to be returned.
     #[allow(clippy::type_complexity)]
-    pub(crate) fn read_by_group_type(
+    pub(crate) fn read_by_group_type<A>(
         &self,
         range: RangeInclusive<Handle>,
         uuid: &Uuid,
-        authorized: bool,
-        authenticated: bool,
-    ) -> Result<Vec<(Handle, Handle, Box<[u8]>)>> {
+        mut authorize: A,
+        security: &att::Security,
+    ) -> Result<Vec<(Handle, Handle, Box<[u8]>)>>
+    where
+        A: FnMut(&Handle) -> bool,
+    {
         let start = range.start().clone();
 
         if range.start() == &Handle::from(0x0000) || range.start() > range.end() {
@@ -42,18 +64,16 @@ impl Database {
                     result.push((start.clone(), last.clone(), val))
                 }
 
-                let b = match val.get(authorized, authenticated) {
-                    Ok(b) => b,
-                    Err(AttrError::PermissionDenied) => {
-                        return Err((key.clone(), ErrorCode::ReadNotPermitted))
-                    }
-                    Err(AttrError::AuthorizationRequired) => {
-                        return Err((key.clone(), ErrorCode::InsufficientAuthorization))
-                    }
-                    Err(AttrError::AuthenticationRequired) => {
-                        return Err((key.clone(), ErrorCode::InsufficientAuthentication))
+                if let Some(len) = val_len {
+                    if val.stored_len().is_some_and(|l| l != len) {
+                        return Ok(result);
                     }
-                    _ => unreachable!(),
+                }
+                let b = match val.get(|| authorize(key), security) {
+                    Ok(b) => b,
+                    // the groups before one which cannot be read are returned
+                    Err(..) if !result.is_empty() => return Ok(result),
+                    Err(err) => return Err(read_error(key, err)),
                 };
                 if let Some(len) = val_len {
                     if len != b.len() {
@@ -74,18 +94,21 @@ impl Database {
         }
     }
 
-    pub(crate) fn find_by_type_value(
+    pub(crate) fn find_by_type_value<A>(
         &self,
         range: RangeInclusive<Handle>,
         uuid: &Uuid16,
         value: &[u8],
-        authorized: bool,
-        authenticated: bool,
-    ) -> Result<Vec<(Handle, Handle)>> {
+        authorize: A,
+        security: &att::Security,
+    ) -> Result<Vec<(Handle, Handle)>>
+    where
+        A: FnMut(&Handle) -> bool,
+    {
         let start = range.start().clone();
 
         let result = self
-            .read_by_group_type(range, &uuid.clone().into(), authorized, authenticated)?
+            .read_by_group_type(range, &uuid.clone().into(), authorize, security)?
             .into_iter()
             .filter_map(|(handle, end, v)| {
                 if &*v == value {
@@ -101,42 +124,47 @@ impl Database {
         Ok(result)
     }
 
-    pub(crate) fn read_by_type(
+    /// `authorize` is asked about each attribute which requires authorization,
+    /// once it passed every other check and is about to be returned.
+    pub(crate) fn read_by_type<A>(
         &self,
         range: RangeInclusive<Handle>,
         uuid: &Uuid,
-        authorized: bool,
-        authenticated: bool,
-    ) -> Result<Vec<(Handle, Box<[u8]>)>> {
+        mut authorize: A,
+        security: &att::Security,
+    ) -> Result<Vec<(Handle, Box<[u8]>)>>
+    where
+        A: FnMut(&Handle) -> bool,
+    {
         let start = range.start().clone();
 
         if range.start() == &Handle::from(0x0000) || range.start() > range.end() {
             return Err((start, ErrorCode::InvalidHandle));
         }
 
-        let result = self
-            .attrs
-            .range(range)
-            .filter_map(|(k, v)| {
-                if v.attr_type() == uuid {
-                    match v.get(authorized, authenticated) {
-                        Ok(b) => Some(Ok((k.clone(), b))),
-                        Err(AttrError::PermissionDenied) => {
-                            Some(Err((k.clone(), ErrorCode::ReadNotPermitted)))
-                        }
-                        Err(AttrError::AuthorizationRequired) => {
-                            Some(Err((k.clone(), ErrorCode::InsufficientAuthorization)))
-                        }
-                        Err(AttrError::AuthenticationRequired) => {
-                            Some(Err((k.clone(), ErrorCode::InsufficientAuthentication)))
-                        }
-                        _ => unreachable!(),
-                    }
-                } else {
-                    None
+        let mut result = Vec::<(Handle, Box<[u8]>)>::new();
+        for (k, v) in self.attrs.range(range) {
+            if v.attr_type() != uuid {
+                continue;
+            }
+            // every value in a response has the length of the first
+            let len = result.first().map(|(_, b)| b.len());
+            if let Some(len) = len {
+                if v.stored_len().is_some_and(|l| l != len) {
+                    break;
                 }
-            })
-            .collect::<Result<Vec<_>>>()?;
+            }
+            let b = match v.get(|| authorize(k), security) {
+                Ok(b) => b,
+                // the attributes before one which cannot be read are returned
+                Err(..) if !result.is_empty() => break,
+                Err(err) => return Err(read_error(k, err)),
+            };
+            if len.is_some_and(|len| len != b.len()) {
+                break;
+            }
+            result.push((k.clone(), b));
+        }
 
         if result.is_empty() {
             Err((start, ErrorCode::AttributeNotFound))
@@ -178,72 +206,343 @@ impl Database {
         }
     }
 
-    pub(crate) fn read(
+    /// `authorize` is asked only if the attribute requires authorization.
+    pub(crate) fn read<A>(
         &self,
         handle: &Handle,
-        authorized: bool,
-        authenticated: bool,
-    ) -> Result<Box<[u8]>> {
+        authorize: A,
+        security: &att::Security,
+    ) -> Result<Box<[u8]>>
+    where
+        A: FnOnce(&Handle) -> bool,
+    {
         if handle == &0x0000.into() {
             return Err((handle.clone(), ErrorCode::InvalidHandle));
         }
 
         if let Some(v) = self.attrs.get(handle) {
-            match v.get(authorized, authenticated) {
-                Ok(v) => Ok(v),
-                Err(AttrError::PermissionDenied) => {
-                    Err((handle.clone(), ErrorCode::ReadNotPermitted))
-                }
-                Err(AttrError::AuthorizationRequired) => {
-                    Err((handle.clone(), ErrorCode::InsufficientAuthorization))
-                }
-                Err(AttrError::AuthenticationRequired) => {
-                    Err((handle.clone(), ErrorCode::InsufficientAuthentication))
-                }
-                _ => unreachable!(),
-            }
+            v.get(|| authorize(handle), security)
+                .map_err(|err| read_error(handle, err))
         } else {
             Err((handle.clone(), ErrorCode::AttributeNotFound))
         }
     }
 
-    pub(crate) fn write(
+    /// `authorize` is asked only if the attribute requires authorization.
+    pub(crate) fn write<A>(
         &mut self,
         handle: &Handle,
         val: &[u8],
-        authorized: bool,
-        authenticated: bool,
-    ) -> Result<()> {
+        authorize: A,
+        security: &att::Security,
+    ) -> Result<()>
+    where
+        A: FnOnce(&Handle) -> bool,
+    {
         if handle == &0x0000.into() {
             return Err((handle.clone(), ErrorCode::InvalidHandle));
         }
 
         if let Some(v) = self.attrs.get_mut(handle) {
-            match v.set(val, authorized, authenticated) {
-                Ok(_) => Ok(()),
-                Err(AttrError::PermissionDenied) => {
-                    Err((handle.clone(), ErrorCode::WriteNotPermitted))
-                }
-                Err(AttrError::AuthorizationRequired) => {
-                    Err((handle.clone(), ErrorCode::InsufficientAuthorization))
-                }
-                Err(AttrError::AuthenticationRequired) => {
-                    Err((handle.clone(), ErrorCode::InsufficientAuthentication))
-                }
-                Err(AttrError::InvalidDataLength) => {
-                    Err((handle.clone(), ErrorCode::InvalidAttributeValueLength))
-                }
+            v.set(val, || authorize(handle), security)
+                .map_err(|err| write_error(handle, err))
+        } else {
+            Err((handle.clone(), ErrorCode::AttributeNotFound))
+        }
+    }
+
+    /// Replace a value from the server side. Permissions are not checked.
+    pub(crate) fn set_value(&mut self, handle: &Handle, val: &[u8]) -> Result<()> {
+        if let Some(v) = self.attrs.get_mut(handle) {
+            v.store(val).map_err(|err| write_error(handle, err))
+        } else {
+            Err((handle.clone(), ErrorCode::AttributeNotFound))
+        }
+    }
+
+    /// Client Characteristic Configuration of the characteristic at `value_handle`.
+    pub(crate) fn client_configuration(
+        &self,
+        value_handle: &Handle,
+    ) -> ClientCharacteristicConfiguration {
+        self.configuration_handle(value_handle)
+            .and_then(|handle| self.configuration_at(&handle))
+            .unwrap_or_else(ClientCharacteristicConfiguration::empty)
+    }
+
+    /// Handle of the Client Characteristic Configuration descriptor of the
+    /// characteristic at `value_handle`.
+    pub(crate) fn configuration_handle(&self, value_handle: &Handle) -> Option<Handle> {
+        let range = (Bound::Excluded(value_handle), Bound::Unbounded);
+        for (handle, attr) in self.attrs.range::<Handle, _>(range) {
+            match attr {
+                Attribute::Service { .. } | Attribute::Characteristic { .. } => break,
+                Attribute::ClientCharacteristicConfiguration { .. } => return Some(handle.clone()),
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// First handle of the lowest `len` consecutive free handles.
+    /// Ranges freed by removed services are reused.
+    pub(crate) fn find_free(&self, len: u16) -> Option<Handle> {
+        if len == 0 {
+            return None;
+        }
+        let len = u32::from(len);
+        let mut start = 0x0001;
+        for handle in self.attrs.keys() {
+            let handle = u32::from(handle.as_u16());
+            if handle - start >= len {
+                break;
+            }
+            start = handle + 1;
+        }
+        if start + len - 1 > 0xFFFF {
+            return None;
+        }
+        Some(Handle::new(start as u16))
+    }
+
+    /// Add attributes at handles not in use.
+    pub(crate) fn insert<I>(&mut self, attrs: I)
+    where
+        I: IntoIterator<Item = Attribute>,
+    {
+        for attr in attrs {
+            let old = self.attrs.insert(attr.handle().clone(), attr);
+            debug_assert!(old.is_none());
+        }
+    }
+
+    /// Remove the attributes in `range`, which must start with a service declaration.
+    pub(crate) fn remove(&mut self, range: &RangeInclusive<Handle>) -> Option<()> {
+        if !self.attrs.get(range.start())?.is_service() {
+            return None;
+        }
+        let handles = self
+            .attrs
+            .range(range.clone())
+            .map(|(handle, _)| handle.clone())
+            .collect::<Vec<_>>();
+        for handle in handles {
+            self.attrs.remove(&handle);
+        }
+        Some(())
+    }
+
+    /// Database Hash over the attributes which define the database structure.
+    ///
+    /// ref BLUETOOTH CORE SPECIFICATION Version 5.1 | Vol 3, Part G | 7.3.1
+    pub(crate) fn hash(&self) -> [u8; 16] {
+        let mut m = vec![];
+        for attr in self.attrs.values() {
+            let with_value = match attr.attr_type() {
+                Uuid::Uuid16(uuid) => match uuid.as_u16() {
+                    0x2800..=0x2803 | 0x2900 => true,
+                    0x2901..=0x2905 => false,
+                    _ => continue,
+                },
+                Uuid::Uuid128(..) => continue,
+            };
+
+            m.extend_from_slice(&attr.handle().as_u16().to_le_bytes());
+            if let Uuid::Uuid16(uuid) = attr.attr_type() {
+                m.extend_from_slice(&uuid.as_u16().to_le_bytes());
+            }
+            if with_value {
+                m.extend_from_slice(&attr.value());
+            }
+        }
+        aes_cmac(&[0; 16], &m)
+    }
+
+    /// Recompute the value of the Database Hash characteristic if present.
+    pub(crate) fn refresh_database_hash(&mut self) {
+        let hash = self.hash();
+        for attr in self.attrs.values_mut() {
+            if attr.attr_type() == &crate::characteristics::DATABASE_HASH {
+                attr.store(&hash).ok();
+            }
+        }
+    }
+
+    /// Whether the client enabled robust caching on this connection.
+    pub(crate) fn robust_caching(&self) -> bool {
+        self.client_supports(ClientSupportedFeatures::ROBUST_CACHING)
+    }
+
+    /// Whether the client wrote `features` to Client Supported Features on this connection.
+    pub(crate) fn client_supports(&self, features: ClientSupportedFeatures) -> bool {
+        self.attrs.values().any(|attr| {
+            matches!(attr, Attribute::ClientSupportedFeatures { features: f, .. }
+                if f.contains(features))
+        })
+    }
+
+    pub(crate) fn set_change_unaware(&mut self) {
+        self.awareness = ChangeAwareness::Unaware;
+    }
+
+    pub(crate) fn set_change_aware(&mut self) {
+        self.awareness = ChangeAwareness::Aware;
+    }
+
+    /// Check a request against the change-aware state of the client.
+    ///
+    /// A change-unaware client which enabled robust caching gets one
+    /// Database Out Of Sync error, and is change-aware from its next request
+    /// or from reading the Database Hash.
+    pub(crate) fn check_change_aware(&mut self, reads_hash: bool) -> Result<()> {
+        if self.awareness == ChangeAwareness::Aware || !self.robust_caching() {
+            return Ok(());
+        }
+        if reads_hash || self.awareness == ChangeAwareness::OutOfSyncReported {
+            self.awareness = ChangeAwareness::Aware;
+            return Ok(());
+        }
+        self.awareness = ChangeAwareness::OutOfSyncReported;
+        Err((0x0000.into(), ErrorCode::DatabaseOutOfSync))
+    }
+
+    /// Whether a command from the client should be processed.
+    pub(crate) fn accepts_command(&self) -> bool {
+        self.awareness == ChangeAwareness::Aware || !self.robust_caching()
+    }
+
+    /// Value of the Client Characteristic Configuration descriptor at `handle`.
+    pub(crate) fn configuration_at(
+        &self,
+        handle: &Handle,
+    ) -> Option<ClientCharacteristicConfiguration> {
+        match self.attrs.get(handle) {
+            Some(Attribute::ClientCharacteristicConfiguration { configuration, .. }) => {
+                Some(*configuration)
             }
+            _ => None,
+        }
+    }
+
+    /// Check a Prepare Write Request before queueing.
+    pub(crate) fn check_write<A>(
+        &self,
+        handle: &Handle,
+        authorize: A,
+        security: &att::Security,
+    ) -> Result<()>
+    where
+        A: FnOnce(&Handle) -> bool,
+    {
+        if handle == &0x0000.into() {
+            return Err((handle.clone(), ErrorCode::InvalidHandle));
+        }
+
+        if let Some(v) = self.attrs.get(handle) {
+            v.check_writable(|| authorize(handle), security)
+                .map_err(|err| write_error(handle, err))
         } else {
             Err((handle.clone(), ErrorCode::AttributeNotFound))
         }
     }
+
+    /// Assemble queued Prepare Write Requests and apply them all or nothing.
+    ///
+    /// Returns the assembled value for each written handle.
+    pub(crate) fn write_prepared(
+        &mut self,
+        queue: &[(Handle, u16, Box<[u8]>)],
+        authorized: bool,
+        security: &att::Security,
+    ) -> Result<Vec<(Handle, Box<[u8]>)>> {
+        let mut assembled = Vec::<(Handle, Vec<u8>)>::new();
+        for (handle, offset, part) in queue {
+            let index = match assembled.iter().position(|(h, _)| h == handle) {
+                Some(index) => index,
+                None => {
+                    let attr = match self.attrs.get(handle) {
+                        Some(attr) => attr,
+                        None => return Err((handle.clone(), ErrorCode::AttributeNotFound)),
+                    };
+                    assembled.push((handle.clone(), attr.value().into_vec()));
+                    assembled.len() - 1
+                }
+            };
+
+            let value = &mut assembled[index].1;
+            let offset = *offset as usize;
+            if offset > value.len() {
+                return Err((handle.clone(), ErrorCode::InvalidOffset));
+            }
+            value.truncate(offset);
+            value.extend_from_slice(part);
+        }
+
+        for (handle, value) in &assembled {
+            let mut attr = self.attrs[handle].detached();
+            attr.set(value, || authorized, security)
+                .map_err(|err| write_error(handle, err))?;
+        }
+
+        for (handle, value) in &assembled {
+            if let Some(attr) = self.attrs.get_mut(handle) {
+                attr.store(value).map_err(|err| write_error(handle, err))?;
+            }
+        }
+        Ok(assembled
+            .into_iter()
+            .map(|(handle, value)| (handle, value.into()))
+            .collect())
+    }
+}
+
+fn aes_cmac(key: &[u8; 16], m: &[u8]) -> [u8; 16] {
+    use cmac::{Cmac, Mac};
+
+    let mut mac = <Cmac<aes::Aes128> as Mac>::new_from_slice(key).unwrap();
+    mac.update(m);
+    mac.finalize().into_bytes().into()
+}
+
+fn read_error(handle: &Handle, err: AttrError) -> (Handle, ErrorCode) {
+    let code = match err {
+        AttrError::PermissionDenied => ErrorCode::ReadNotPermitted,
+        AttrError::AuthorizationRequired => ErrorCode::InsufficientAuthorization,
+        AttrError::AuthenticationRequired => ErrorCode::InsufficientAuthentication,
+        AttrError::EncryptionRequired => ErrorCode::InsufficientEncryption,
+        AttrError::EncryptionKeySizeTooShort => ErrorCode::InsufficientEncryptionKeySize,
+        AttrError::InvalidDataLength => ErrorCode::InvalidAttributeValueLength,
+        AttrError::ImproperlyConfigured => {
+            ErrorCode::CommonProfileAndServiceErrorCodes(CCCD_IMPROPERLY_CONFIGURED)
+        }
+        AttrError::ValueNotAllowed => ErrorCode::ValueNotAllowed,
+        AttrError::Application(code) => code,
+    };
+    (handle.clone(), code)
+}
+
+fn write_error(handle: &Handle, err: AttrError) -> (Handle, ErrorCode) {
+    let code = match err {
+        AttrError::PermissionDenied => ErrorCode::WriteNotPermitted,
+        AttrError::AuthorizationRequired => ErrorCode::InsufficientAuthorization,
+        AttrError::AuthenticationRequired => ErrorCode::InsufficientAuthentication,
+        AttrError::EncryptionRequired => ErrorCode::InsufficientEncryption,
+        AttrError::EncryptionKeySizeTooShort => ErrorCode::InsufficientEncryptionKeySize,
+        AttrError::InvalidDataLength => ErrorCode::InvalidAttributeValueLength,
+        AttrError::ImproperlyConfigured => {
+            ErrorCode::CommonProfileAndServiceErrorCodes(CCCD_IMPROPERLY_CONFIGURED)
+        }
+        AttrError::ValueNotAllowed => ErrorCode::ValueNotAllowed,
+        AttrError::Application(code) => code,
+    };
+    (handle.clone(), code)
 }
 
 impl FromIterator<Attribute> for Database {
     fn from_iter<T: IntoIterator<Item = Attribute>>(iter: T) -> Self {
         Self {
             attrs: iter.into_iter().map(|a| (a.handle().clone(), a)).collect(),
+            awareness: ChangeAwareness::Aware,
         }
     }
 }
@@ -252,7 +551,8 @@ impl FromIterator<Attribute> for Database {
 mod tests {
     use super::*;
     use crate::attribute::{
-        CharacteristicProperties, ClientCharacteristicConfiguration, Permission,
+        CharacteristicExtendedProperties, CharacteristicProperties,
+        ClientCharacteristicConfiguration, Permission,
     };
 
     #[test]
@@ -263,8 +563,8 @@ mod tests {
             .read_by_group_type(
                 0x0001.into()..=0xFFFF.into(),
                 &Uuid::new_uuid16(0x2800),
-                false,
-                false,
+                |_| false,
+                &att::Security::default(),
             )
             .unwrap();
         assert_eq!(
@@ -280,8 +580,8 @@ mod tests {
             .read_by_group_type(
                 0x0017.into()..=0xFFFF.into(),
                 &Uuid::new_uuid16(0x2800),
-                false,
-                false,
+                |_| false,
+                &att::Security::default(),
             )
             .unwrap();
         assert_eq!(
@@ -301,8 +601,8 @@ mod tests {
             .read_by_group_type(
                 0x0021.into()..=0xFFFF.into(),
                 &Uuid::new_uuid16(0x2800),
-                false,
-                false,
+                |_| false,
+                &att::Security::default(),
             )
             .unwrap();
         assert_eq!(
@@ -314,8 +614,8 @@ mod tests {
             .read_by_group_type(
                 0x0028.into()..=0xFFFF.into(),
                 &Uuid::new_uuid16(0x2800),
-                false,
-                false,
+                |_| false,
+                &att::Security::default(),
             )
             .unwrap_err();
         assert_eq!(result, (0x0028.into(), ErrorCode::AttributeNotFound));
@@ -324,8 +624,8 @@ mod tests {
             .read_by_group_type(
                 0x0002.into()..=0x0001.into(),
                 &Uuid::new_uuid16(0x2800),
-                false,
-                false,
+                |_| false,
+                &att::Security::default(),
             )
             .unwrap_err();
         assert_eq!(result, (0x0002.into(), ErrorCode::InvalidHandle));
@@ -334,8 +634,8 @@ mod tests {
             .read_by_group_type(
                 0x0000.into()..=0x0001.into(),
                 &Uuid::new_uuid16(0x2800),
-                false,
-                false,
+                |_| false,
+                &att::Security::default(),
             )
             .unwrap_err();
         assert_eq!(result, (0x0000.into(), ErrorCode::InvalidHandle));
@@ -349,9 +649,9 @@ mod tests {
             .find_by_type_value(
                 0x0001.into()..=0xFFFF.into(),
                 &Uuid16::new(0x2800),
-                &vec![0x01, 0x18],
-                false,
-                false,
+                &[0x01, 0x18],
+                |_| false,
+                &att::Security::default(),
             )
             .unwrap();
         assert_eq!(&result, &[(0x000C.into(), 0x000F.into())]);
@@ -359,9 +659,9 @@ mod tests {
             .find_by_type_value(
                 0x0010.into()..=0xFFFF.into(),
                 &Uuid16::new(0x2800),
-                &vec![0x01, 0x18],
-                false,
-                false,
+                &[0x01, 0x18],
+                |_| false,
+                &att::Security::default(),
             )
             .unwrap_err();
         assert_eq!(result, (0x0010.into(), ErrorCode::AttributeNotFound));
@@ -375,8 +675,8 @@ mod tests {
             .read_by_type(
                 0x0001.into()..=0x000b.into(),
                 &Uuid::new_uuid16(0x2802),
-                false,
-                false,
+                |_| false,
+                &att::Security::default(),
             )
             .unwrap_err();
         assert_eq!(result, (0x0001.into(), ErrorCode::AttributeNotFound));
@@ -385,8 +685,8 @@ mod tests {
             .read_by_type(
                 0x0001.into()..=0x000b.into(),
                 &Uuid::new_uuid16(0x2803),
-                false,
-                false,
+                |_| false,
+                &att::Security::default(),
             )
             .unwrap();
         assert_eq!(
@@ -401,8 +701,8 @@ mod tests {
             .read_by_type(
                 0x0005.into()..=0x000b.into(),
                 &Uuid::new_uuid16(0x2803),
-                false,
-                false,
+                |_| false,
+                &att::Security::default(),
             )
             .unwrap_err();
         assert_eq!(result, (0x0005.into(), ErrorCode::AttributeNotFound));
@@ -411,8 +711,8 @@ mod tests {
             .read_by_type(
                 0x0002.into()..=0x0001.into(),
                 &Uuid::new_uuid16(0x2802),
-                false,
-                false,
+                |_| false,
+                &att::Security::default(),
             )
             .unwrap_err();
         assert_eq!(result, (0x0002.into(), ErrorCode::InvalidHandle));
@@ -421,8 +721,8 @@ mod tests {
             .read_by_type(
                 0x0000.into()..=0x0001.into(),
                 &Uuid::new_uuid16(0x2802),
-                false,
-                false,
+                |_| false,
+                &att::Security::default(),
             )
             .unwrap_err();
         assert_eq!(result, (0x0000.into(), ErrorCode::InvalidHandle));
@@ -464,25 +764,269 @@ mod tests {
     fn test_read() {
         let db = example_db();
 
-        let result = db.read(&0x0005.into(), false, false).unwrap();
+        let result = db
+            .read(&0x0005.into(), |_| false, &att::Security::default())
+            .unwrap();
         assert_eq!(&*result, &b"abc"[..]);
 
-        let result = db.read(&0x0000.into(), false, false).unwrap_err();
+        let result = db
+            .read(&0x0000.into(), |_| false, &att::Security::default())
+            .unwrap_err();
         assert_eq!(result, (0x0000.into(), ErrorCode::InvalidHandle));
     }
 
+    #[test]
+    fn test_aes_cmac() {
+        // ref BLUETOOTH CORE SPECIFICATION Version 5.1 | Vol 3, Part H | D.1
+        let key = [
+            0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf,
+            0x4f, 0x3c,
+        ];
+        assert_eq!(
+            aes_cmac(&key, &[]),
+            [
+                0xbb, 0x1d, 0x69, 0x29, 0xe9, 0x59, 0x37, 0x28, 0x7f, 0xa3, 0x7d, 0x12, 0x9b, 0x75,
+                0x67, 0x46
+            ]
+        );
+        let m = [
+            0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93,
+            0x17, 0x2a,
+        ];
+        assert_eq!(
+            aes_cmac(&key, &m),
+            [
+                0x07, 0x0a, 0x16, 0xb4, 0x6b, 0x4d, 0x41, 0x44, 0xf7, 0x9b, 0xdd, 0x9d, 0xd0, 0x4a,
+                0x28, 0x7c
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hash() {
+        // ref BLUETOOTH CORE SPECIFICATION Version 5.1 | Vol 3, Part G | Appendix B
+        let uuid = Uuid::new_uuid16;
+        let characteristic = |handle: u16, properties, uuid16| {
+            Attribute::new_characteristic(
+                handle.into(),
+                properties,
+                (handle + 1).into(),
+                uuid(uuid16),
+            )
+        };
+        let value = |handle: u16, uuid16| {
+            Attribute::new_characteristic_value(
+                handle.into(),
+                uuid(uuid16),
+                vec![0].into(),
+                Permission::READABLE | Permission::WRITEABLE,
+            )
+        };
+        let cccd = |handle: u16| {
+            Attribute::new_client_characteristic_configuration(
+                handle.into(),
+                ClientCharacteristicConfiguration::empty(),
+                Permission::READABLE | Permission::WRITEABLE,
+            )
+        };
+        let read_write = CharacteristicProperties::READ | CharacteristicProperties::WRITE;
+        let mut db = vec![
+            Attribute::new_primary_service(0x0001.into(), uuid(0x1800)),
+            characteristic(0x0002, read_write, 0x2A00),
+            value(0x0003, 0x2A00),
+            characteristic(0x0004, CharacteristicProperties::READ, 0x2A01),
+            value(0x0005, 0x2A01),
+            Attribute::new_primary_service(0x0006.into(), uuid(0x1801)),
+            characteristic(0x0007, CharacteristicProperties::INDICATE, 0x2A05),
+            value(0x0008, 0x2A05),
+            cccd(0x0009),
+            characteristic(0x000A, read_write, 0x2B29),
+            Attribute::new_client_supported_features(0x000B.into()),
+            characteristic(0x000C, CharacteristicProperties::READ, 0x2B2A),
+            value(0x000D, 0x2B2A),
+            Attribute::new_primary_service(0x000E.into(), uuid(0x1808)),
+            Attribute::new_include(0x000F.into(), 0x0014.into(), 0x0016.into(), uuid(0x180F)),
+            characteristic(
+                0x0010,
+                CharacteristicProperties::READ
+                    | CharacteristicProperties::INDICATE
+                    | CharacteristicProperties::EXTENDED_PROPERTIES,
+                0x2A18,
+            ),
+            value(0x0011, 0x2A18),
+            cccd(0x0012),
+            Attribute::new_characteristic_extended_properties(
+                0x0013.into(),
+                CharacteristicExtendedProperties::empty(),
+            ),
+            Attribute::new_secondary_service(0x0014.into(), uuid(0x180F)),
+            characteristic(0x0015, CharacteristicProperties::READ, 0x2A19),
+            value(0x0016, 0x2A19),
+        ]
+        .into_iter()
+        .collect::<Database>();
+
+        let hash = [
+            0xF1, 0xCA, 0x2D, 0x48, 0xEC, 0xF5, 0x8B, 0xAC, 0x8A, 0x88, 0x30, 0xBB, 0xB9, 0xFB,
+            0xA9, 0x90,
+        ];
+        assert_eq!(db.hash(), hash);
+        db.refresh_database_hash();
+        assert_eq!(
+            &*db.read(&0x000D.into(), |_| false, &att::Security::default())
+                .unwrap(),
+            &hash
+        );
+
+        // values do not take part
+        db.set_value(&0x0003.into(), b"name").unwrap();
+        db.set_value(&0x0009.into(), &[0x02, 0x00]).unwrap();
+        assert_eq!(db.hash(), hash);
+    }
+
+    #[test]
+    fn test_find_free() {
+        let service =
+            |handle: u16| Attribute::new_primary_service(handle.into(), Uuid::new_uuid16(0x180F));
+        let mut db = vec![
+            service(0x0001),
+            service(0x0002),
+            service(0x0006),
+            service(0x0009),
+        ]
+        .into_iter()
+        .collect::<Database>();
+
+        assert_eq!(db.find_free(0), None);
+        assert_eq!(db.find_free(2), Some(0x0003.into()));
+        assert_eq!(db.find_free(3), Some(0x0003.into()));
+        assert_eq!(db.find_free(4), Some(0x000A.into()));
+
+        db.remove(&(0x0006.into()..=0x0008.into())).unwrap();
+        assert_eq!(db.find_free(5), Some(0x0003.into()));
+        assert_eq!(db.find_free(0xFFF6), Some(0x000A.into()));
+        assert_eq!(db.find_free(0xFFF7), None);
+        assert!(db.remove(&(0x0003.into()..=0x0004.into())).is_none());
+    }
+
+    #[test]
+    fn test_read_dynamic() {
+        use crate::attribute::Reader;
+        use std::sync::atomic::{AtomicU8, Ordering};
+
+        let counter = AtomicU8::new(0);
+        let db = vec![
+            Attribute::new_dynamic_characteristic_value(
+                0x0001.into(),
+                Uuid::new_uuid16(0x2A19),
+                Reader::new(move || Ok([counter.fetch_add(1, Ordering::SeqCst)].into())),
+                Permission::READABLE,
+            ),
+            Attribute::new_dynamic_characteristic_value(
+                0x0002.into(),
+                Uuid::new_uuid16(0x2A2B),
+                Reader::new(|| Err(ErrorCode::ApplicationError(0x80))),
+                Permission::READABLE,
+            ),
+        ]
+        .into_iter()
+        .collect::<Database>();
+
+        assert_eq!(
+            &*db.read(&0x0001.into(), |_| false, &att::Security::default())
+                .unwrap(),
+            &[0]
+        );
+        assert_eq!(
+            &*db.read(&0x0001.into(), |_| false, &att::Security::default())
+                .unwrap(),
+            &[1]
+        );
+
+        let result = db
+            .read_by_type(
+                0x0001.into()..=0xFFFF.into(),
+                &Uuid::new_uuid16(0x2A19),
+                |_| false,
+                &att::Security::default(),
+            )
+            .unwrap();
+        assert_eq!(&result, &[(0x0001.into(), vec![2].into())]);
+
+        let result = db
+            .read(&0x0002.into(), |_| false, &att::Security::default())
+            .unwrap_err();
+        assert_eq!(result, (0x0002.into(), ErrorCode::ApplicationError(0x80)));
+    }
+
     #[test]
     fn test_write() {
         let mut db = example_db();
 
-        let _result = db
-            .write(&0x000F.into(), &vec![0x00, 0x00], false, false)
-            .unwrap();
+        db.write(
+            &0x000F.into(),
+            &[0x00, 0x00],
+            |_| false,
+            &att::Security::default(),
+        )
+        .unwrap();
 
-        let result = db.write(&0x0000.into(), &vec![], false, false).unwrap_err();
+        let result = db
+            .write(&0x0000.into(), &[], |_| false, &att::Security::default())
+            .unwrap_err();
         assert_eq!(result, (0x0000.into(), ErrorCode::InvalidHandle));
     }
 
+    #[test]
+    fn test_write_prepared() {
+        let mut db = example_db();
+
+        db.check_write(&0x0003.into(), |_| false, &att::Security::default())
+            .unwrap();
+        let result = db
+            .check_write(&0x0005.into(), |_| false, &att::Security::default())
+            .unwrap_err();
+        assert_eq!(result, (0x0005.into(), ErrorCode::WriteNotPermitted));
+
+        let result = db
+            .write_prepared(
+                &[
+                    (0x0003.into(), 0, b"abc".as_ref().into()),
+                    (0x0003.into(), 3, b"def".as_ref().into()),
+                ],
+                false,
+                &att::Security::default(),
+            )
+            .unwrap();
+        assert_eq!(result, vec![(0x0003.into(), b"abcdef".as_ref().into())]);
+        assert_eq!(db.attrs[&0x0003.into()].value(), b"abcdef".as_ref().into());
+
+        let result = db
+            .write_prepared(
+                &[(0x0003.into(), 7, b"x".as_ref().into())],
+                false,
+                &att::Security::default(),
+            )
+            .unwrap_err();
+        assert_eq!(result, (0x0003.into(), ErrorCode::InvalidOffset));
+
+        let result = db
+            .write_prepared(
+                &[
+                    (0x0003.into(), 0, b"xyz".as_ref().into()),
+                    (0x000F.into(), 0, [0x01].as_ref().into()),
+                ],
+                false,
+                &att::Security::default(),
+            )
+            .unwrap_err();
+        assert_eq!(
+            result,
+            (0x000F.into(), ErrorCode::InvalidAttributeValueLength)
+        );
+        assert_eq!(db.attrs[&0x0003.into()].value(), b"abcdef".as_ref().into());
+    }
+
     fn example_db() -> Database {
         vec![
             Attribute::new_primary_service(0x0001.into(), Uuid::new_uuid16(0x1800)),
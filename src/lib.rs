@@ -33,12 +33,7 @@
 //!         CharacteristicProperties::READ,
 //!     );
 //!
-//!     registration.add_primary_service(srv::GENERIC_ATTRIBUTE);
-//!     registration.add_characteristic(
-//!         ch::SERVICE_CHANGED,
-//!         "",
-//!         CharacteristicProperties::INDICATE,
-//!     );
+//!     registration.add_generic_attribute_service();
 //!
 //!     registration.add_primary_service(srv::DEVICE_INFORMATION);
 //!     registration.add_characteristic(
@@ -120,6 +115,7 @@
 //!   ([LICENSE-APACHE](LICENSE-APACHE) or http://www.apache.org/licenses/LICENSE-2.0)
 //! * MIT license
 //!   ([LICENSE-MIT](LICENSE-MIT) or http://opensource.org/licenses/MIT)
+//!
 //! at your option.
 //!
 //! ## Contribution
@@ -127,14 +123,24 @@
 //! Unless you explicitly state otherwise, any contribution intentionally submitted
 //! for inclusion in the work by you, as defined in the Apache-2.0 license, shall be
 //! dual licensed as above, without any additional terms or conditions.!
-pub use crate::registration::{CharacteristicProperties, Registration};
-pub use crate::server::Server;
+pub use crate::client::Client;
+pub use crate::registration::{
+    AttributeSecurity, CharacteristicProperties, Registration, SecurityLevel,
+    ServerSupportedFeatures,
+};
+pub use crate::server::{Server, SharedDatabase};
+pub use crate::subscription::{FileSubscriptionStore, MemorySubscriptionStore, SubscriptionStore};
 
-pub use att::{Uuid, parse_uuid};
+pub use att::packet::ErrorCode;
+pub use att::{parse_uuid, Transport, Uuid};
 
 mod attribute;
 pub mod characteristics;
+pub mod client;
 mod database;
 mod registration;
 pub mod server;
 pub mod services;
+mod subscription;
+#[cfg(test)]
+mod testing;
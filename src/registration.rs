@@ -1,12 +1,14 @@
 use std::collections::HashMap;
 use std::hash::Hash;
+use std::ops::RangeInclusive;
 
+use att::packet::ErrorCode;
 use att::{Handle, Uuid};
 
 use crate::attribute::{
     Attribute, CharacteristicExtendedProperties as AttExProperties,
     CharacteristicProperties as AttProperties, ClientCharacteristicConfiguration, Permission,
-    ServerCharacteristicConfiguration,
+    Reader, ServerCharacteristicConfiguration,
 };
 use crate::database::Database;
 
@@ -26,6 +28,54 @@ bitflags::bitflags! {
     }
 }
 
+bitflags::bitflags! {
+    /// Server Supported Features
+    pub struct ServerSupportedFeatures: u8 {
+        const EATT = 0x01;
+    }
+}
+
+/// Security of the link required to read or write an attribute.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SecurityLevel {
+    #[default]
+    Open,
+    /// Encrypted link.
+    Encrypted,
+    /// Encrypted link whose key was generated with MITM protection.
+    Authenticated,
+}
+
+/// Security required to access an attribute, set with [`Registration::set_security`].
+///
+/// A link that falls short gets Insufficient Authentication, Insufficient
+/// Encryption or Insufficient Encryption Key Size.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AttributeSecurity {
+    pub read: SecurityLevel,
+    pub write: SecurityLevel,
+    /// Minimum encryption key size in octets for the directions that are not
+    /// [`SecurityLevel::Open`]. `0` for any.
+    pub min_key_size: u8,
+}
+
+impl AttributeSecurity {
+    fn perm(&self) -> Permission {
+        let mut perm = Permission::empty();
+        match self.read {
+            SecurityLevel::Open => {}
+            SecurityLevel::Encrypted => perm |= Permission::READ_ENCRYPTION_REQUIRED,
+            SecurityLevel::Authenticated => perm |= Permission::READ_AUTHENTICATION_REQUIRED,
+        }
+        match self.write {
+            SecurityLevel::Open => {}
+            SecurityLevel::Encrypted => perm |= Permission::WRITE_ENCRYPTION_REQUIRED,
+            SecurityLevel::Authenticated => perm |= Permission::WRITE_AUTHENTICATION_REQUIRED,
+        }
+        perm
+    }
+}
+
 impl CharacteristicProperties {
     fn perm(&self) -> Permission {
         let mut perm = Permission::empty();
@@ -60,8 +110,8 @@ impl From<CharacteristicProperties> for (AttProperties, AttExProperties) {
 pub struct Registration<T> {
     next_handle: u16,
     attrs: Vec<Attribute>,
-    write_handles: HashMap<Handle, T>,
-    notify_or_indicate_handles: HashMap<T, Handle>,
+    tokens: Tokens<T>,
+    service_changed: Option<Handle>,
 }
 
 impl<T> Default for Registration<T> {
@@ -75,8 +125,8 @@ impl<T> Registration<T> {
         Self {
             next_handle: 0x0001,
             attrs: vec![],
-            write_handles: HashMap::new(),
-            notify_or_indicate_handles: HashMap::new(),
+            tokens: Tokens::new(),
+            service_changed: None,
         }
     }
 }
@@ -110,7 +160,7 @@ where
         U: Into<Uuid>,
         B: AsRef<[u8]>,
     {
-        self.add_characteristic_internal(None, uuid, val.as_ref(), properties)
+        self.add_characteristic_internal(None, uuid, val.as_ref(), None, properties)
     }
 
     pub fn add_characteristic_with_token<U, B>(
@@ -124,7 +174,109 @@ where
         T: Hash + Eq + Clone,
         B: AsRef<[u8]>,
     {
-        self.add_characteristic_internal(Some(token), uuid, val.as_ref(), properties)
+        self.add_characteristic_internal(Some(token), uuid, val.as_ref(), None, properties)
+    }
+
+    /// Add characteristic whose value is computed by `reader` on every read.
+    ///
+    /// An `Err` from `reader` is sent to the client as the Error Response.
+    /// Writes permitted by `properties` are not stored; register with
+    /// [`Registration::add_dynamic_characteristic_with_token`] to receive them
+    /// as [`Event::Write`](crate::server::Event::Write).
+    pub fn add_dynamic_characteristic<U, F, B>(
+        &mut self,
+        uuid: U,
+        reader: F,
+        properties: CharacteristicProperties,
+    ) where
+        U: Into<Uuid>,
+        F: Fn() -> Result<B, ErrorCode> + Send + Sync + 'static,
+        B: AsRef<[u8]>,
+    {
+        let reader = Reader::new(move || reader().map(|v| v.as_ref().into()));
+        self.add_characteristic_internal(None, uuid, &[], Some(reader), properties)
+    }
+
+    pub fn add_dynamic_characteristic_with_token<U, F, B>(
+        &mut self,
+        token: T,
+        uuid: U,
+        reader: F,
+        properties: CharacteristicProperties,
+    ) where
+        U: Into<Uuid>,
+        F: Fn() -> Result<B, ErrorCode> + Send + Sync + 'static,
+        B: AsRef<[u8]>,
+    {
+        let reader = Reader::new(move || reader().map(|v| v.as_ref().into()));
+        self.add_characteristic_internal(Some(token), uuid, &[], Some(reader), properties)
+    }
+
+    /// Add the Generic Attribute service managed by the server.
+    ///
+    /// It has Service Changed, which is indicated to clients when services
+    /// are added or removed at runtime, Client Supported Features and Database Hash.
+    pub fn add_generic_attribute_service(&mut self) {
+        self.add_generic_attribute_service_with_features(ServerSupportedFeatures::empty())
+    }
+
+    /// Add the Generic Attribute service with a Server Supported Features
+    /// characteristic holding `features`, unless empty.
+    ///
+    /// Announce [`ServerSupportedFeatures::EATT`] when also listening with
+    /// [`Server::bind_eatt`](crate::Server::bind_eatt).
+    pub fn add_generic_attribute_service_with_features(
+        &mut self,
+        features: ServerSupportedFeatures,
+    ) {
+        self.add_primary_service(crate::services::GENERIC_ATTRIBUTE);
+        let value_handle = Handle::new(self.next_handle + 1);
+        self.add_characteristic(
+            crate::characteristics::SERVICE_CHANGED,
+            [],
+            CharacteristicProperties::INDICATE,
+        );
+        self.service_changed = Some(value_handle);
+        self.add_client_supported_features();
+        self.add_database_hash();
+        if !features.is_empty() {
+            self.add_characteristic(
+                crate::characteristics::SERVER_SUPPORTED_FEATURE,
+                [features.bits()],
+                CharacteristicProperties::READ,
+            );
+        }
+    }
+
+    /// Add Database Hash characteristic.
+    /// This belongs to the Generic Attribute service.
+    ///
+    /// The value is computed from the database when the registration is built.
+    pub fn add_database_hash(&mut self) {
+        self.add_characteristic(
+            crate::characteristics::DATABASE_HASH,
+            [0; 16],
+            CharacteristicProperties::READ,
+        )
+    }
+
+    /// Add Client Supported Features characteristic.
+    /// This belongs to the Generic Attribute service.
+    ///
+    /// Each client has its own value. A client which enables robust caching
+    /// is told its database is out of sync until it reads the Database Hash again.
+    pub fn add_client_supported_features(&mut self) {
+        let decl_handle = self.next_handle();
+        let val_handle = self.next_handle();
+        let (prop, _) = (CharacteristicProperties::READ | CharacteristicProperties::WRITE).into();
+        self.attrs.push(Attribute::new_characteristic(
+            decl_handle,
+            prop,
+            val_handle.clone(),
+            crate::characteristics::CLIENT_SUPPORTED_FEATURES,
+        ));
+        self.attrs
+            .push(Attribute::new_client_supported_features(val_handle));
     }
 
     fn add_characteristic_internal<U>(
@@ -132,6 +284,7 @@ where
         token: Option<T>,
         uuid: U,
         val: &[u8],
+        reader: Option<Reader>,
         properties: CharacteristicProperties,
     ) where
         U: Into<Uuid>,
@@ -154,12 +307,12 @@ where
             val_handle.clone(),
             uuid.clone(),
         ));
-        self.attrs.push(Attribute::new_characteristic_value(
-            val_handle.clone(),
-            uuid,
-            val,
-            perm,
-        ));
+        self.attrs.push(match reader {
+            Some(reader) => {
+                Attribute::new_dynamic_characteristic_value(val_handle.clone(), uuid, reader, perm)
+            }
+            None => Attribute::new_characteristic_value(val_handle.clone(), uuid, val, perm),
+        });
         if !exprop.is_empty() {
             let handle = self.next_handle();
             self.attrs
@@ -170,8 +323,12 @@ where
         if notify || indicate {
             let handle = self.next_handle();
             if let Some(token) = &token {
-                self.notify_or_indicate_handles
+                self.tokens
+                    .notify_or_indicate_handles
                     .insert(token.clone(), val_handle.clone());
+                self.tokens
+                    .configuration_handles
+                    .insert(handle.clone(), (token.clone(), val_handle.clone()));
             }
             self.attrs
                 .push(Attribute::new_client_characteristic_configuration(
@@ -192,9 +349,14 @@ where
 
         if writable {
             if let Some(token) = &token {
-                self.write_handles.insert(val_handle, token.clone());
+                self.tokens
+                    .write_handles
+                    .insert(val_handle.clone(), token.clone());
             }
         }
+        if let Some(token) = token {
+            self.tokens.value_handles.insert(token, val_handle);
+        }
     }
 
     pub fn add_descriptor<U, B>(&mut self, uuid: U, val: B, writable: bool)
@@ -217,16 +379,132 @@ where
         ));
     }
 
-    pub(crate) fn build(self) -> (Database, HashMap<Handle, T>, HashMap<T, Handle>) {
+    /// Require `security` to access the characteristic value or descriptor
+    /// added last in the current service. Does nothing if there is none.
+    pub fn set_security(&mut self, security: AttributeSecurity) {
+        let required = security.perm();
+        self.attrs
+            .iter_mut()
+            .rev()
+            .take_while(|attr| !attr.is_service())
+            .any(|attr| attr.require_security(required, security.min_key_size));
+    }
+
+    pub(crate) fn build(self) -> Built<T> {
         let Self {
             attrs,
-            write_handles,
-            notify_or_indicate_handles,
+            tokens,
+            service_changed,
             ..
         } = self;
-        let db = attrs.into_iter().collect();
-        (db, write_handles, notify_or_indicate_handles)
+        let mut db = attrs.into_iter().collect::<Database>();
+        db.refresh_database_hash();
+        Built {
+            db,
+            tokens,
+            service_changed,
+        }
     }
+
+    /// Attributes and tokens to be placed from `start` into a live database.
+    pub(crate) fn into_parts_at(self, start: &Handle) -> (Vec<Attribute>, Tokens<T>) {
+        let offset = start.as_u16() - 0x0001;
+        let moved = Handle::new(0x0001)..=Handle::new(self.len());
+        let mut attrs = self.attrs;
+        for attr in &mut attrs {
+            attr.relocate(offset, &moved);
+        }
+        (attrs, self.tokens.relocate(offset))
+    }
+
+    /// Number of handles in use.
+    pub(crate) fn len(&self) -> u16 {
+        self.next_handle - 0x0001
+    }
+}
+
+/// Handles of token-registered characteristics.
+#[derive(Debug, Clone)]
+pub(crate) struct Tokens<T> {
+    pub(crate) write_handles: HashMap<Handle, T>,
+    pub(crate) notify_or_indicate_handles: HashMap<T, Handle>,
+    pub(crate) value_handles: HashMap<T, Handle>,
+    /// Client Characteristic Configuration handle to token and value handle.
+    pub(crate) configuration_handles: HashMap<Handle, (T, Handle)>,
+}
+
+impl<T> Tokens<T> {
+    fn new() -> Self {
+        Self {
+            write_handles: HashMap::new(),
+            notify_or_indicate_handles: HashMap::new(),
+            value_handles: HashMap::new(),
+            configuration_handles: HashMap::new(),
+        }
+    }
+}
+
+impl<T> Tokens<T>
+where
+    T: Hash + Eq,
+{
+    fn relocate(self, offset: u16) -> Self {
+        let shift = |handle: Handle| Handle::new(handle.as_u16() + offset);
+        Self {
+            write_handles: self
+                .write_handles
+                .into_iter()
+                .map(|(handle, token)| (shift(handle), token))
+                .collect(),
+            notify_or_indicate_handles: self
+                .notify_or_indicate_handles
+                .into_iter()
+                .map(|(token, handle)| (token, shift(handle)))
+                .collect(),
+            value_handles: self
+                .value_handles
+                .into_iter()
+                .map(|(token, handle)| (token, shift(handle)))
+                .collect(),
+            configuration_handles: self
+                .configuration_handles
+                .into_iter()
+                .map(|(handle, (token, value_handle))| {
+                    (shift(handle), (token, shift(value_handle)))
+                })
+                .collect(),
+        }
+    }
+
+    pub(crate) fn extend(&mut self, other: Self) {
+        self.write_handles.extend(other.write_handles);
+        self.notify_or_indicate_handles
+            .extend(other.notify_or_indicate_handles);
+        self.value_handles.extend(other.value_handles);
+        self.configuration_handles
+            .extend(other.configuration_handles);
+    }
+
+    /// Forget every characteristic whose value handle is in `range`.
+    pub(crate) fn remove(&mut self, range: &RangeInclusive<Handle>) {
+        self.write_handles
+            .retain(|handle, _| !range.contains(handle));
+        self.notify_or_indicate_handles
+            .retain(|_, handle| !range.contains(handle));
+        self.value_handles
+            .retain(|_, handle| !range.contains(handle));
+        self.configuration_handles
+            .retain(|handle, _| !range.contains(handle));
+    }
+}
+
+/// Result of [`Registration::build`].
+#[derive(Debug, Clone)]
+pub(crate) struct Built<T> {
+    pub(crate) db: Database,
+    pub(crate) tokens: Tokens<T>,
+    /// Value handle of the managed Service Changed characteristic.
+    pub(crate) service_changed: Option<Handle>,
 }
 
 #[cfg(test)]
@@ -285,4 +563,45 @@ mod tests {
 
         println!("{:#?}", registration.build());
     }
+
+    #[test]
+    fn test_relocate_include() {
+        let mut registration = Registration::<()>::new();
+        registration.add_primary_service(Uuid::new_uuid16(0x180F));
+        registration.add_primary_service(Uuid::new_uuid16(0x180A));
+        // one service of the registration, one already in the database
+        let included = [
+            (Handle::new(0x0001), Handle::new(0x0001)),
+            (Handle::new(0x0010), Handle::new(0x0012)),
+        ];
+        for (service, end) in included.iter() {
+            let handle = registration.next_handle();
+            registration.attrs.push(Attribute::new_include(
+                handle,
+                service.clone(),
+                end.clone(),
+                Uuid::new_uuid16(0x180F),
+            ));
+        }
+
+        let (attrs, _) = registration.into_parts_at(&Handle::new(0x0020));
+        let includes = attrs
+            .iter()
+            .filter(|attr| matches!(attr, Attribute::Include { .. }))
+            .map(|attr| (attr.handle().clone(), attr.value()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            includes,
+            vec![
+                (
+                    Handle::new(0x0022),
+                    vec![0x20, 0x00, 0x20, 0x00, 0x0F, 0x18].into()
+                ),
+                (
+                    Handle::new(0x0023),
+                    vec![0x10, 0x00, 0x12, 0x00, 0x0F, 0x18].into()
+                ),
+            ]
+        );
+    }
 }
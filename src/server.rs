@@ -1,47 +1,289 @@
 //! GATT Protocol Server
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::hash::Hash;
 use std::io;
+use std::ops::RangeInclusive;
+use std::pin::Pin;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
 
 use att::packet as pkt;
+pub use att::server::Connection as Bearer;
+pub use att::server::WriteMode;
 use att::server::{
-    Connection as AttConnection, Error as AttError, ErrorResponse, Handler, Server as AttServer,
+    Connection as AttConnection, Error as AttError, ErrorResponse, Handler,
+    Indication as AttIndication, MultipleNotification as AttMultipleNotification,
+    Notification as AttNotification, Notifier, Server as AttServer,
 };
-pub use att::server::{Indication, Notification};
 use att::Handle;
 use futures_channel::mpsc;
+use futures_util::future::{self, Either};
+use futures_util::lock::Mutex as AsyncMutex;
+use futures_util::pin_mut;
 use futures_util::stream::StreamExt;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 
+use crate::attribute::{ClientCharacteristicConfiguration, ClientSupportedFeatures};
 use crate::database::Database;
+use crate::registration::{Built, Tokens};
+use crate::subscription::{PeerSubscriptions, SubscriptionStore};
 use crate::Registration;
 
+/// Default number of queued Prepare Write Requests per connection.
+pub const DEFAULT_PREPARE_QUEUE_LIMIT: usize = 32;
+
+/// Kind of access to an attribute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    Read,
+    Write,
+}
+
+/// Access to an attribute registered with
+/// [`CharacteristicProperties::AUTHORIZATION_REQUIRED`](crate::CharacteristicProperties::AUTHORIZATION_REQUIRED).
 #[derive(Debug)]
-struct GattHandler<T> {
-    db: Database,
-    write_tokens: HashMap<Handle, T>,
-    events_txs: Vec<mpsc::UnboundedSender<Event<T>>>,
+pub struct AuthorizationRequest<'a, T> {
+    pub peer: &'a att::Address,
+    /// Token of the characteristic, if registered with one.
+    pub token: Option<&'a T>,
+    pub handle: &'a Handle,
+    pub operation: Operation,
+}
+
+type Authorizer<T> = Arc<dyn Fn(&AuthorizationRequest<'_, T>) -> bool + Send + Sync>;
+
+/// Authorization decisions for the client of a connection.
+struct Authorization<T> {
+    peer: att::Address,
+    authorizer: Option<Authorizer<T>>,
+    /// Accesses granted so far, if grants are cached.
+    granted: Option<HashSet<(Handle, Operation)>>,
+}
+
+impl<T> fmt::Debug for Authorization<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Authorization")
+            .field("peer", &self.peer)
+            .field("granted", &self.granted)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Security of the link, as reported by the bearer or marked with [`Authenticator`].
+#[derive(Debug, Clone)]
+struct LinkSecurity {
+    query: att::SecurityQuery,
     authenticated: Arc<AtomicBool>,
 }
 
+impl LinkSecurity {
+    fn get(&self) -> att::Security {
+        let mut security = self.query.security().unwrap_or_else(|err| {
+            log::debug!("failed to query link security: {}", err);
+            att::Security::default()
+        });
+        // the key size is only ever what the bearer reports
+        if self.authenticated.load(Ordering::SeqCst) {
+            security.encrypted = true;
+            security.authenticated = true;
+        }
+        security
+    }
+}
+
+#[derive(Debug)]
+struct GattHandler<T> {
+    db: Arc<Mutex<Database>>,
+    tokens: Arc<Mutex<Tokens<T>>>,
+    events: EventSenders<T>,
+    security: LinkSecurity,
+    authorization: Authorization<T>,
+    prepared: Vec<(Handle, u16, Box<[u8]>)>,
+    prepare_queue_limit: usize,
+    subscriptions: Option<PeerSubscriptions>,
+    /// Largest ATT_MTU offered.
+    max_mtu: u16,
+}
+
 impl<T> GattHandler<T> {
     fn new(
-        db: Database,
-        write_tokens: HashMap<Handle, T>,
-        events_txs: Vec<mpsc::UnboundedSender<Event<T>>>,
-        authenticated: Arc<AtomicBool>,
+        db: Arc<Mutex<Database>>,
+        tokens: Arc<Mutex<Tokens<T>>>,
+        events: EventSenders<T>,
+        security: LinkSecurity,
+        authorization: Authorization<T>,
+        prepare_queue_limit: usize,
+        subscriptions: Option<PeerSubscriptions>,
     ) -> Self {
         Self {
             db,
-            write_tokens,
-            events_txs,
-            authenticated,
+            tokens,
+            events,
+            security,
+            authorization,
+            prepared: vec![],
+            prepare_queue_limit,
+            subscriptions,
+            max_mtu: 23,
+        }
+    }
+
+    fn security(&self) -> att::Security {
+        self.security.get()
+    }
+
+    fn check_change_aware(&self, reads_hash: bool) -> Result<(), ErrorResponse> {
+        let result = self.db.lock().unwrap().check_change_aware(reads_hash);
+        result.map_err(|(h, e)| ErrorResponse::new(h, e))
+    }
+
+    fn accepts_command(&self) -> bool {
+        self.db.lock().unwrap().accepts_command()
+    }
+}
+
+impl<T> GattHandler<T>
+where
+    T: Clone,
+{
+    /// Whether the client may access `handle`, which requires authorization.
+    /// Asks the authorizer unless the access was granted before and grants are cached.
+    fn grant(&mut self, handle: &Handle, operation: Operation) -> bool {
+        let key = (handle.clone(), operation);
+        if let Some(granted) = &self.authorization.granted {
+            if granted.contains(&key) {
+                return true;
+            }
+        }
+        let authorizer = match &self.authorization.authorizer {
+            Some(authorizer) => authorizer.clone(),
+            None => return false,
+        };
+
+        let token = {
+            let tokens = self.tokens.lock().unwrap();
+            tokens
+                .value_handles
+                .iter()
+                .find(|(_, h)| *h == handle)
+                .map(|(token, _)| token.clone())
+        };
+        let granted = authorizer(&AuthorizationRequest {
+            peer: &self.authorization.peer,
+            token: token.as_ref(),
+            handle,
+            operation,
+        });
+        if granted {
+            if let Some(cache) = &mut self.authorization.granted {
+                cache.insert(key);
+            }
+        }
+        granted
+    }
+
+    fn read_multiple<'a, I>(&mut self, handles: I) -> Result<Vec<Box<[u8]>>, ErrorResponse>
+    where
+        I: IntoIterator<Item = &'a Handle>,
+    {
+        let security = self.security();
+        let db = self.db.clone();
+        let mut values = vec![];
+        // the first handle which cannot be read fails the request,
+        // so the authorizer is not asked about the handles after it
+        for handle in handles {
+            let result =
+                db.lock()
+                    .unwrap()
+                    .read(handle, |h| self.grant(h, Operation::Read), &security);
+            match result {
+                Ok(v) => values.push(v),
+                Err((h, e)) => return Err(ErrorResponse::new(h, e)),
+            }
+        }
+        if values.is_empty() {
+            return Err(ErrorResponse::new(
+                0x0000.into(),
+                pkt::ErrorCode::InvalidPDU,
+            ));
         }
+        Ok(values)
     }
 
-    fn authenticated(&self) -> bool {
-        self.authenticated.load(Ordering::SeqCst)
+    fn emit_write(&self, handle: &Handle, value: &[u8]) {
+        let token = self
+            .tokens
+            .lock()
+            .unwrap()
+            .write_handles
+            .get(handle)
+            .cloned();
+        if let Some(token) = token {
+            self.events.emit(Event::Write(token, value.into()));
+        }
+    }
+
+    /// Current configuration if `handle` is the CCCD of a token-registered characteristic.
+    fn subscription(&self, handle: &Handle) -> Option<ClientCharacteristicConfiguration> {
+        let (_, value_handle) = self.configuration_token(handle)?;
+        Some(self.db.lock().unwrap().client_configuration(&value_handle))
+    }
+
+    fn configuration_token(&self, handle: &Handle) -> Option<(T, Handle)> {
+        let tokens = self.tokens.lock().unwrap();
+        tokens.configuration_handles.get(handle).cloned()
+    }
+
+    fn save_subscription(&self, handle: &Handle) {
+        if let Some(subscriptions) = &self.subscriptions {
+            let configuration = self.db.lock().unwrap().configuration_at(handle);
+            if let Some(configuration) = configuration {
+                subscriptions.save(handle, configuration.bits());
+            }
+        }
+    }
+
+    fn emit_subscription(&self, handle: &Handle, old: ClientCharacteristicConfiguration) {
+        if let Some((token, value_handle)) = self.configuration_token(handle) {
+            let new = self.db.lock().unwrap().client_configuration(&value_handle);
+            if new == old {
+                return;
+            }
+            let event = if new.is_empty() {
+                Event::Unsubscribe(token)
+            } else {
+                Event::Subscribe {
+                    token,
+                    notify: new.contains(ClientCharacteristicConfiguration::NOTIFICATION),
+                    indicate: new.contains(ClientCharacteristicConfiguration::INDICATION),
+                }
+            };
+            self.events.emit(event);
+        }
+    }
+
+    fn write(
+        &mut self,
+        handle: &Handle,
+        value: &[u8],
+        security: &att::Security,
+    ) -> Result<(), (Handle, pkt::ErrorCode)> {
+        let old = self.subscription(handle);
+        let db = self.db.clone();
+        let result =
+            db.lock()
+                .unwrap()
+                .write(handle, value, |h| self.grant(h, Operation::Write), security);
+        result?;
+        self.emit_write(handle, value);
+        self.save_subscription(handle);
+        if let Some(old) = old {
+            self.emit_subscription(handle, old);
+        }
+        Ok(())
     }
 }
 
@@ -53,64 +295,81 @@ where
         &mut self,
         item: &pkt::ExchangeMtuRequest,
     ) -> Result<pkt::ExchangeMtuResponse, ErrorResponse> {
-        Ok(pkt::ExchangeMtuResponse::new(*item.client_rx_mtu()))
+        let mtu = (*item.client_rx_mtu()).min(self.max_mtu).max(23);
+        self.events.emit(Event::MtuChanged(mtu));
+        Ok(pkt::ExchangeMtuResponse::new(self.max_mtu))
     }
 
     fn handle_find_information_request(
         &mut self,
         item: &pkt::FindInformationRequest,
     ) -> Result<pkt::FindInformationResponse, ErrorResponse> {
+        self.check_change_aware(false)?;
         let r = match self
             .db
+            .lock()
+            .unwrap()
             .find_information(item.starting_handle().clone()..=item.ending_handle().clone())
         {
             Ok(v) => v,
             Err((h, e)) => return Err(ErrorResponse::new(h, e)),
         };
-        Ok(r.into_iter().map(Into::into).collect())
+        Ok(r.into_iter().collect())
     }
 
     fn handle_find_by_type_value_request(
         &mut self,
         item: &pkt::FindByTypeValueRequest,
     ) -> Result<pkt::FindByTypeValueResponse, ErrorResponse> {
-        let r = match self.db.find_by_type_value(
-            item.starting_handle().clone()..=item.ending_handle().clone(),
+        self.check_change_aware(false)?;
+        let range = item.starting_handle().clone()..=item.ending_handle().clone();
+        let security = self.security();
+        let db = self.db.clone();
+        let r = match db.lock().unwrap().find_by_type_value(
+            range,
             item.attribute_type(),
             item.attribute_value(),
-            false,
-            self.authenticated(),
+            |h| self.grant(h, Operation::Read),
+            &security,
         ) {
             Ok(v) => v,
             Err((h, e)) => return Err(ErrorResponse::new(h, e)),
         };
-        Ok(r.into_iter().map(Into::into).collect())
+        Ok(r.into_iter().collect())
     }
 
     fn handle_read_by_type_request(
         &mut self,
         item: &pkt::ReadByTypeRequest,
     ) -> Result<pkt::ReadByTypeResponse, ErrorResponse> {
-        let r = match self.db.read_by_type(
-            item.starting_handle().clone()..=item.ending_handle().clone(),
+        self.check_change_aware(item.attribute_type() == &crate::characteristics::DATABASE_HASH)?;
+        let range = item.starting_handle().clone()..=item.ending_handle().clone();
+        let security = self.security();
+        let db = self.db.clone();
+        let r = match db.lock().unwrap().read_by_type(
+            range,
             item.attribute_type(),
-            false,
-            self.authenticated(),
+            |h| self.grant(h, Operation::Read),
+            &security,
         ) {
             Ok(v) => v,
             Err((h, e)) => return Err(ErrorResponse::new(h, e)),
         };
-        Ok(r.into_iter().map(Into::into).collect())
+        Ok(r.into_iter().collect())
     }
 
     fn handle_read_request(
         &mut self,
         item: &pkt::ReadRequest,
     ) -> Result<pkt::ReadResponse, ErrorResponse> {
-        let r = match self
-            .db
-            .read(item.attribute_handle(), false, self.authenticated())
-        {
+        self.check_change_aware(false)?;
+        let security = self.security();
+        let db = self.db.clone();
+        let r = match db.lock().unwrap().read(
+            item.attribute_handle(),
+            |h| self.grant(h, Operation::Read),
+            &security,
+        ) {
             Ok(v) => v,
             Err((h, e)) => return Err(ErrorResponse::new(h, e)),
         };
@@ -121,85 +380,224 @@ where
         &mut self,
         item: &pkt::ReadBlobRequest,
     ) -> Result<pkt::ReadBlobResponse, ErrorResponse> {
-        let r = match self
-            .db
-            .read(item.attribute_handle(), false, self.authenticated())
-        {
+        self.check_change_aware(false)?;
+        let security = self.security();
+        let db = self.db.clone();
+        let r = match db.lock().unwrap().read(
+            item.attribute_handle(),
+            |h| self.grant(h, Operation::Read),
+            &security,
+        ) {
             Ok(v) => v,
             Err((h, e)) => return Err(ErrorResponse::new(h, e)),
         };
+        // a computed value may have shrunk since the previous part was read
         let offset = *item.attribute_offset() as usize;
+        if offset > r.len() {
+            return Err(ErrorResponse::new(
+                item.attribute_handle().clone(),
+                pkt::ErrorCode::InvalidOffset,
+            ));
+        }
         Ok(pkt::ReadBlobResponse::new(r[offset..].into()))
     }
 
+    fn handle_read_multiple_request(
+        &mut self,
+        item: &pkt::ReadMultipleRequest,
+    ) -> Result<pkt::ReadMultipleResponse, ErrorResponse> {
+        self.check_change_aware(false)?;
+        let values = self.read_multiple(item)?;
+        Ok(pkt::ReadMultipleResponse::new(values.concat().into()))
+    }
+
+    fn handle_read_multiple_variable_request(
+        &mut self,
+        item: &pkt::ReadMultipleVariableRequest,
+    ) -> Result<pkt::ReadMultipleVariableResponse, ErrorResponse> {
+        self.check_change_aware(false)?;
+        let values = self.read_multiple(item)?;
+        Ok(values.into_iter().collect())
+    }
+
     fn handle_read_by_group_type_request(
         &mut self,
         item: &pkt::ReadByGroupTypeRequest,
     ) -> Result<pkt::ReadByGroupTypeResponse, ErrorResponse> {
-        let r = match self.db.read_by_group_type(
-            item.starting_handle().clone()..=item.ending_handle().clone(),
+        self.check_change_aware(false)?;
+        let range = item.starting_handle().clone()..=item.ending_handle().clone();
+        let security = self.security();
+        let db = self.db.clone();
+        let r = match db.lock().unwrap().read_by_group_type(
+            range,
             item.attribute_group_type(),
-            false,
-            self.authenticated(),
+            |h| self.grant(h, Operation::Read),
+            &security,
         ) {
             Ok(v) => v,
             Err((h, e)) => return Err(ErrorResponse::new(h, e)),
         };
-        Ok(r.into_iter().map(Into::into).collect())
+        Ok(r.into_iter().collect())
     }
 
     fn handle_write_request(
         &mut self,
         item: &pkt::WriteRequest,
     ) -> Result<pkt::WriteResponse, ErrorResponse> {
-        let value = item.attribute_value();
-        if let Some(token) = self.write_tokens.get(item.attribute_handle()) {
-            for tx in &self.events_txs {
-                tx.unbounded_send(Event::Write(token.clone(), value.to_vec().into()))
-                    .ok();
-            }
-        }
-
-        match self.db.write(item.attribute_handle(), value, false, false) {
+        self.check_change_aware(false)?;
+        let security = self.security();
+        match self.write(item.attribute_handle(), item.attribute_value(), &security) {
             Ok(_) => Ok(pkt::WriteResponse::new()),
             Err((h, e)) => Err(ErrorResponse::new(h, e)),
         }
     }
 
-    fn handle_write_command(&mut self, item: &pkt::WriteCommand) {
-        let value = item.attribute_value();
-        if let Some(token) = self.write_tokens.get(item.attribute_handle()) {
-            for tx in &self.events_txs {
-                tx.unbounded_send(Event::Write(token.clone(), value.to_vec().into()))
-                    .ok();
+    fn handle_prepare_write_request(
+        &mut self,
+        item: &pkt::PrepareWriteRequest,
+    ) -> Result<pkt::PrepareWriteResponse, ErrorResponse> {
+        self.check_change_aware(false)?;
+        let handle = item.attribute_handle();
+        let security = self.security();
+        let db = self.db.clone();
+        let result =
+            db.lock()
+                .unwrap()
+                .check_write(handle, |h| self.grant(h, Operation::Write), &security);
+        if let Err((h, e)) = result {
+            return Err(ErrorResponse::new(h, e));
+        }
+        if self.prepared.len() >= self.prepare_queue_limit {
+            return Err(ErrorResponse::new(
+                handle.clone(),
+                pkt::ErrorCode::PrepareQueueFull,
+            ));
+        }
+
+        let offset = *item.value_offset();
+        let part = item.part_attribute_value().clone();
+        self.prepared.push((handle.clone(), offset, part.clone()));
+        Ok(pkt::PrepareWriteResponse::new(handle.clone(), offset, part))
+    }
+
+    fn handle_execute_write_request(
+        &mut self,
+        item: &pkt::ExecuteWriteRequest,
+    ) -> Result<pkt::ExecuteWriteResponse, ErrorResponse> {
+        self.check_change_aware(false)?;
+        let prepared = std::mem::take(&mut self.prepared);
+        if !*item.flags() {
+            return Ok(pkt::ExecuteWriteResponse::new());
+        }
+
+        let security = self.security();
+        let subscriptions = prepared
+            .iter()
+            .filter_map(|(handle, ..)| Some((handle.clone(), self.subscription(handle)?)))
+            .collect::<HashMap<_, _>>();
+        let result = self
+            .db
+            .lock()
+            .unwrap()
+            // every queued write was authorized when prepared
+            .write_prepared(&prepared, true, &security);
+        match result {
+            Ok(written) => {
+                for (handle, value) in written {
+                    self.emit_write(&handle, &value);
+                    self.save_subscription(&handle);
+                    if let Some(old) = subscriptions.get(&handle) {
+                        self.emit_subscription(&handle, *old);
+                    }
+                }
+                Ok(pkt::ExecuteWriteResponse::new())
             }
+            Err((h, e)) => Err(ErrorResponse::new(h, e)),
         }
+    }
 
-        if let Err(err) = self.db.write(
-            item.attribute_handle(),
-            item.attribute_value(),
-            false,
-            false,
-        ) {
+    fn handle_write_command(&mut self, item: &pkt::WriteCommand) {
+        if !self.accepts_command() {
+            return;
+        }
+        let security = self.security();
+        if let Err(err) = self.write(item.attribute_handle(), item.attribute_value(), &security) {
             log::warn!("{:?}", err);
         };
     }
 
     fn handle_signed_write_command(&mut self, item: &pkt::SignedWriteCommand) {
-        let value = item.attribute_value();
-        if let Some(token) = self.write_tokens.get(item.attribute_handle()) {
-            for tx in &self.events_txs {
-                tx.unbounded_send(Event::Write(token.clone(), value.to_vec().into()))
-                    .ok();
-            }
-        }
+        // the signature cannot be verified without the peer's CSRK
+        log::debug!(
+            "ignored Signed Write Command to {:?}",
+            item.attribute_handle()
+        );
+    }
 
-        if let Err(err) =
-            self.db
-                .write(item.attribute_handle(), item.attribute_value(), false, true)
-        {
-            log::warn!("{:?}", err);
+    fn handle_confirmation(&mut self, handle: &Handle) {
+        let token = {
+            let tokens = self.tokens.lock().unwrap();
+            tokens
+                .notify_or_indicate_handles
+                .iter()
+                .find(|(_, h)| *h == handle)
+                .map(|(token, _)| token.clone())
         };
+        if let Some(token) = token {
+            self.events.emit(Event::IndicationConfirmed(token));
+        }
+    }
+}
+
+macro_rules! delegate {
+    ($($name:ident($item:ty) -> $ret:ty;)*) => {
+        $(
+            fn $name(&mut self, item: &$item) -> $ret {
+                self.0.lock().unwrap().$name(item)
+            }
+        )*
+    };
+}
+
+/// Handler shared by all bearers of a connection.
+struct SharedHandler<T>(Arc<Mutex<GattHandler<T>>>);
+
+impl<T> Clone for SharedHandler<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> Handler for SharedHandler<T>
+where
+    T: Clone,
+{
+    delegate! {
+        handle_exchange_mtu_request(pkt::ExchangeMtuRequest)
+            -> Result<pkt::ExchangeMtuResponse, ErrorResponse>;
+        handle_find_information_request(pkt::FindInformationRequest)
+            -> Result<pkt::FindInformationResponse, ErrorResponse>;
+        handle_find_by_type_value_request(pkt::FindByTypeValueRequest)
+            -> Result<pkt::FindByTypeValueResponse, ErrorResponse>;
+        handle_read_by_type_request(pkt::ReadByTypeRequest)
+            -> Result<pkt::ReadByTypeResponse, ErrorResponse>;
+        handle_read_request(pkt::ReadRequest) -> Result<pkt::ReadResponse, ErrorResponse>;
+        handle_read_blob_request(pkt::ReadBlobRequest)
+            -> Result<pkt::ReadBlobResponse, ErrorResponse>;
+        handle_read_multiple_request(pkt::ReadMultipleRequest)
+            -> Result<pkt::ReadMultipleResponse, ErrorResponse>;
+        handle_read_multiple_variable_request(pkt::ReadMultipleVariableRequest)
+            -> Result<pkt::ReadMultipleVariableResponse, ErrorResponse>;
+        handle_read_by_group_type_request(pkt::ReadByGroupTypeRequest)
+            -> Result<pkt::ReadByGroupTypeResponse, ErrorResponse>;
+        handle_write_request(pkt::WriteRequest) -> Result<pkt::WriteResponse, ErrorResponse>;
+        handle_write_command(pkt::WriteCommand) -> ();
+        handle_prepare_write_request(pkt::PrepareWriteRequest)
+            -> Result<pkt::PrepareWriteResponse, ErrorResponse>;
+        handle_execute_write_request(pkt::ExecuteWriteRequest)
+            -> Result<pkt::ExecuteWriteResponse, ErrorResponse>;
+        handle_signed_write_command(pkt::SignedWriteCommand) -> ();
+        handle_confirmation(Handle) -> ();
     }
 }
 
@@ -208,7 +606,10 @@ where
 #[error("channel error")]
 pub struct ChannelError;
 
-/// GATT Server control.
+/// Marks the link encrypted and authenticated regardless of what the bearer
+/// reports. The encryption key size is still the one reported.
+///
+/// Links on L2CAP sockets report their security themselves.
 #[derive(Debug)]
 pub struct Authenticator {
     authenticated: Arc<AtomicBool>,
@@ -221,9 +622,58 @@ impl Authenticator {
 }
 
 /// GATT Event
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Event<T> {
     Write(T, Box<[u8]>),
+
+    /// Client Characteristic Configuration changed to a non-zero value.
+    Subscribe {
+        token: T,
+        notify: bool,
+        indicate: bool,
+    },
+
+    /// Client Characteristic Configuration cleared.
+    Unsubscribe(T),
+
+    /// Handle Value Confirmation received for an indication of the characteristic.
+    IndicationConfirmed(T),
+
+    /// ATT_MTU negotiated with Exchange MTU.
+    MtuChanged(u16),
+}
+
+/// Senders of every [`Events`] stream of a connection.
+#[derive(Debug)]
+struct EventSenders<T>(Arc<Mutex<Vec<mpsc::UnboundedSender<Event<T>>>>>);
+
+impl<T> Clone for EventSenders<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> EventSenders<T> {
+    fn new() -> Self {
+        Self(Arc::new(Mutex::new(vec![])))
+    }
+
+    fn subscribe(&self) -> Events<T> {
+        let (tx, rx) = mpsc::unbounded();
+        self.0.lock().unwrap().push(tx);
+        Events(rx)
+    }
+}
+
+impl<T> EventSenders<T>
+where
+    T: Clone,
+{
+    fn emit(&self, event: Event<T>) {
+        for tx in &*self.0.lock().unwrap() {
+            tx.unbounded_send(event.clone()).ok();
+        }
+    }
 }
 
 /// GATT Event Stream
@@ -240,125 +690,1806 @@ impl<T> Events<T> {
 #[error("handle not found.")]
 pub struct HandleNotFound;
 
-/// Run [`Connection::run`]
+/// The client has not enabled notifications or indications for the characteristic.
 #[derive(Debug, thiserror::Error)]
-#[error(transparent)]
-pub struct RunError(#[from] AttError);
+#[error("not subscribed.")]
+pub struct NotSubscribed;
 
-/// GATT Connection
-pub struct Connection<T> {
-    inner: AttConnection,
-    event_txs: Vec<mpsc::UnboundedSender<Event<T>>>,
-    db: Database,
-    write_tokens: HashMap<Handle, T>,
-    notify_or_indicate_handles: HashMap<T, Handle>,
-    authenticated: Arc<AtomicBool>, // TODO
+/// Writer which refuses to start a write unless `required` is configured by the client.
+struct Subscribed<W> {
+    inner: W,
+    db: Arc<Mutex<Database>>,
+    handle: Handle,
+    required: ClientCharacteristicConfiguration,
+    writing: bool,
 }
 
-impl<T> Connection<T>
+impl<W> AsyncWrite for Subscribed<W>
 where
-    T: Eq + Hash + Clone,
+    W: AsyncWrite + Unpin,
 {
-    fn new(inner: AttConnection, registration: Registration<T>) -> Self {
-        let (db, write_tokens, notify_or_indicate_handles) = registration.build();
-
-        Self {
-            inner,
-            event_txs: vec![],
-            db,
-            write_tokens,
-            notify_or_indicate_handles,
-            authenticated: Arc::new(AtomicBool::from(false)),
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if !self.writing {
+            let configuration = self.db.lock().unwrap().client_configuration(&self.handle);
+            if !configuration.contains(self.required) {
+                return Poll::Ready(Err(io::Error::other(NotSubscribed)));
+            }
+            self.writing = true;
+        }
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if result.is_ready() {
+            self.writing = false;
         }
+        result
     }
 
-    pub fn authenticator(&self) -> Authenticator {
-        Authenticator {
-            authenticated: self.authenticated.clone(),
-        }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
     }
 
-    pub fn events(&mut self) -> Events<T> {
-        let (tx, rx) = mpsc::unbounded();
-        self.event_txs.push(tx);
-        Events(rx)
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
     }
+}
 
-    pub fn notification(&self, token: &T) -> Result<Notification, HandleNotFound> {
-        if let Some(handle) = self.notify_or_indicate_handles.get(token) {
-            let notification = self.inner.notification(handle.clone());
-            Ok(notification)
-        } else {
-            Err(HandleNotFound)
-        }
+/// Handle Value Notification writer.
+///
+/// Writes fail with [`NotSubscribed`] until the client enables notifications.
+pub struct Notification(Subscribed<AttNotification>);
+
+impl Notification {
+    pub fn write_mode(&self) -> WriteMode {
+        self.0.inner.write_mode()
     }
 
-    pub fn indication(&self, token: &T) -> Result<Indication, HandleNotFound> {
-        if let Some(handle) = self.notify_or_indicate_handles.get(token) {
-            let indication = self.inner.indication(handle.clone());
-            Ok(indication)
-        } else {
-            Err(HandleNotFound)
-        }
+    /// Choose how values larger than ATT_MTU - 3 are written. Defaults to [`WriteMode::Strict`].
+    pub fn set_write_mode(&mut self, mode: WriteMode) {
+        self.0.inner.set_write_mode(mode);
     }
+}
 
-    pub fn address(&self) -> &att::Address {
-        self.inner.address()
+impl AsyncWrite for Notification {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
     }
 
-    pub async fn run(self) -> Result<(), RunError> {
-        let Self {
-            db,
-            write_tokens,
-            event_txs,
-            authenticated,
-            ..
-        } = self;
-        self.inner
-            .run(GattHandler::<T>::new(
-                db,
-                write_tokens,
-                event_txs,
-                authenticated,
-            ))
-            .await?;
-        Ok(())
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
     }
 }
 
-/// GATT Protocol Server
-pub struct Server {
-    inner: AttServer,
+/// Handle Value Indication writer. Each write waits for the confirmation.
+///
+/// Writes fail with [`NotSubscribed`] until the client enables indications.
+pub struct Indication(Subscribed<AttIndication>);
+
+impl Indication {
+    pub fn write_mode(&self) -> WriteMode {
+        self.0.inner.write_mode()
+    }
+
+    /// Choose how values larger than ATT_MTU - 3 are written. Defaults to [`WriteMode::Strict`].
+    /// In [`WriteMode::Stream`] every value waits for its own confirmation.
+    pub fn set_write_mode(&mut self, mode: WriteMode) {
+        self.0.inner.set_write_mode(mode);
+    }
 }
 
-impl Server {
-    pub fn bind() -> io::Result<Self> {
-        let server = AttServer::new()?;
-        Ok(Self { inner: server })
+impl AsyncWrite for Indication {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
     }
 
-    /// Accept [`Connection`]
-    pub async fn accept<T>(
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+/// Error for [`MultipleNotification::notify`]
+#[derive(Debug, thiserror::Error)]
+pub enum MultipleNotificationError {
+    #[error(transparent)]
+    HandleNotFound(#[from] HandleNotFound),
+
+    #[error(transparent)]
+    NotSubscribed(#[from] NotSubscribed),
+
+    #[error(transparent)]
+    Att(#[from] AttError),
+}
+
+/// Multiple Handle Value Notification sender
+pub struct MultipleNotification<T> {
+    inner: AttMultipleNotification,
+    db: Arc<Mutex<Database>>,
+    handles: HashMap<T, Handle>,
+}
+
+impl<T> MultipleNotification<T>
+where
+    T: Eq + Hash,
+{
+    /// Notify as many values as fit into the current tx MTU in a single PDU.
+    /// Returns the number of values sent. Send the rest again.
+    ///
+    /// Unless the client supports Multiple Handle Value Notifications, each
+    /// PDU holds one value. A value larger than the MTU allows fails with
+    /// [`AttError::PayloadTooLarge`].
+    pub async fn notify(
         &mut self,
-        registration: Registration<T>,
-    ) -> io::Result<Option<Connection<T>>>
-    where
-        T: Eq + Hash + Clone,
-    {
-        if let Some((connection, _)) = self.inner.accept().await? {
-            Ok(Some(Connection::new(connection, registration)))
+        values: &[(T, &[u8])],
+    ) -> Result<usize, MultipleNotificationError> {
+        let values = values
+            .iter()
+            .map(|(token, value)| match self.handles.get(token) {
+                Some(handle) => Ok((handle.clone(), *value)),
+                None => Err(HandleNotFound),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let multiple = {
+            let db = self.db.lock().unwrap();
+            for (handle, _) in &values {
+                if !db
+                    .client_configuration(handle)
+                    .contains(ClientCharacteristicConfiguration::NOTIFICATION)
+                {
+                    return Err(NotSubscribed.into());
+                }
+            }
+            db.client_supports(ClientSupportedFeatures::MULTIPLE_HANDLE_VALUE_NOTIFICATIONS)
+        };
+        let values = if multiple {
+            &values[..]
         } else {
-            Ok(None)
-        }
+            &values[..values.len().min(1)]
+        };
+        Ok(self.inner.send(values).await?)
     }
+}
 
-    pub fn needs_bond(&self) -> io::Result<()> {
-        self.inner.needs_bond()?;
+/// No bearer has the given index.
+#[derive(Debug, thiserror::Error)]
+#[error("bearer not found.")]
+pub struct BearerNotFound;
+
+/// Error for [`Connection::notification_on`] and [`Connection::indication_on`]
+#[derive(Debug, thiserror::Error)]
+pub enum WriterError {
+    #[error(transparent)]
+    HandleNotFound(#[from] HandleNotFound),
+
+    #[error(transparent)]
+    BearerNotFound(#[from] BearerNotFound),
+}
+
+/// Error for [`SharedDatabase::notify`]
+#[derive(Debug, thiserror::Error)]
+pub enum NotifyError {
+    #[error(transparent)]
+    HandleNotFound(#[from] HandleNotFound),
+
+    /// The value does not fit into a notification of some connection.
+    #[error("payload too large, at most {max} bytes fit into the current MTU")]
+    PayloadTooLarge { max: usize },
+}
+
+/// Error for [`ValueUpdater::set_value`]
+#[derive(Debug, thiserror::Error)]
+pub enum SetValueError {
+    #[error(transparent)]
+    HandleNotFound(#[from] HandleNotFound),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Updates stored characteristic values and pushes them to the client.
+pub struct ValueUpdater<T> {
+    db: Arc<Mutex<Database>>,
+    handles: HashMap<T, Handle>,
+    writers: HashMap<T, (Notification, Indication)>,
+}
+
+impl<T> ValueUpdater<T>
+where
+    T: Eq + Hash + Clone + Unpin,
+{
+    /// Store `value`, then notify or indicate it as configured by the client.
+    /// Notification is preferred when both are enabled.
+    /// Indication waits for the confirmation.
+    pub async fn set_value(&mut self, token: &T, value: &[u8]) -> Result<(), SetValueError> {
+        let handle = self.handles.get(token).ok_or(HandleNotFound)?;
+        let configuration = {
+            let mut db = self.db.lock().unwrap();
+            db.set_value(handle, value).map_err(|_| HandleNotFound)?;
+            db.client_configuration(handle)
+        };
+
+        if let Some((notification, indication)) = self.writers.get_mut(token) {
+            if configuration.contains(ClientCharacteristicConfiguration::NOTIFICATION) {
+                notification.write_all(value).await?;
+            } else if configuration.contains(ClientCharacteristicConfiguration::INDICATION) {
+                indication.write_all(value).await?;
+            }
+        }
         Ok(())
     }
+}
 
-    pub fn needs_bond_mitm(&self) -> io::Result<()> {
-        self.inner.needs_bond_mitm()?;
-        Ok(())
+/// Indicates Service Changed to the client of a connection.
+struct ServiceChanged {
+    handle: Handle,
+    indication: AsyncMutex<Subscribed<AttIndication>>,
+    db: Arc<Mutex<Database>>,
+    subscriptions: Mutex<Option<PeerSubscriptions>>,
+}
+
+impl ServiceChanged {
+    /// Indicate the changed `range` and wait for the confirmation,
+    /// which makes the client change-aware.
+    async fn indicate(&self, range: &RangeInclusive<Handle>) -> io::Result<()> {
+        let mut value = [0; 4];
+        value[..2].copy_from_slice(&range.start().as_u16().to_le_bytes());
+        value[2..].copy_from_slice(&range.end().as_u16().to_le_bytes());
+        self.indication.lock().await.write_all(&value).await?;
+
+        let hash = {
+            let mut db = self.db.lock().unwrap();
+            db.set_change_aware();
+            db.hash()
+        };
+        if let Some(subscriptions) = &*self.subscriptions.lock().unwrap() {
+            subscriptions.save_database_hash(&hash);
+        }
+        Ok(())
+    }
+}
+
+/// Run [`Connection::run`]
+#[derive(Debug, thiserror::Error)]
+#[error(transparent)]
+pub struct RunError(#[from] AttError);
+
+/// GATT Connection
+pub struct Connection<T> {
+    bearers: Vec<AttConnection>,
+    events: EventSenders<T>,
+    db: Arc<Mutex<Database>>,
+    tokens: Arc<Mutex<Tokens<T>>>,
+    authenticated: Arc<AtomicBool>,
+    authorizer: Option<Authorizer<T>>,
+    cache_authorization: bool,
+    prepare_queue_limit: usize,
+    subscriptions: Option<PeerSubscriptions>,
+    service_changed: Option<Arc<ServiceChanged>>,
+    /// The database changed since the bonded client last knew it.
+    service_changed_pending: bool,
+    registered: Option<Registered>,
+}
+
+impl<T> Connection<T>
+where
+    T: Eq + Hash + Clone,
+{
+    /// Serve `registration` over an arbitrary packet-preserving transport.
+    pub fn new<IO>(io: IO, address: att::Address, registration: Registration<T>) -> Self
+    where
+        IO: att::Transport,
+    {
+        Self::with_connection(AttConnection::new(io, address), registration.build())
+    }
+
+    fn with_connection(inner: AttConnection, built: Built<T>) -> Self {
+        let tokens = Arc::new(Mutex::new(built.tokens));
+        Self::with_tokens(inner, built.db, tokens, built.service_changed)
+    }
+
+    fn with_tokens(
+        inner: AttConnection,
+        db: Database,
+        tokens: Arc<Mutex<Tokens<T>>>,
+        service_changed: Option<Handle>,
+    ) -> Self {
+        let db = Arc::new(Mutex::new(db));
+        let service_changed = service_changed.map(|handle| {
+            Arc::new(ServiceChanged {
+                handle: handle.clone(),
+                indication: AsyncMutex::new(Subscribed {
+                    inner: inner.indication(handle.clone()),
+                    db: db.clone(),
+                    handle,
+                    required: ClientCharacteristicConfiguration::INDICATION,
+                    writing: false,
+                }),
+                db: db.clone(),
+                subscriptions: Mutex::new(None),
+            })
+        });
+        Self {
+            bearers: vec![inner],
+            events: EventSenders::new(),
+            db,
+            tokens,
+            authenticated: Arc::new(AtomicBool::from(false)),
+            authorizer: None,
+            cache_authorization: false,
+            prepare_queue_limit: DEFAULT_PREPARE_QUEUE_LIMIT,
+            subscriptions: None,
+            service_changed,
+            service_changed_pending: false,
+            registered: None,
+        }
+    }
+
+    /// Limit the number of queued Prepare Write Requests.
+    /// Requests beyond the limit fail with Prepare Queue Full.
+    pub fn set_prepare_queue_limit(&mut self, limit: usize) {
+        self.prepare_queue_limit = limit;
+    }
+
+    /// Decide each access to attributes registered with
+    /// [`CharacteristicProperties::AUTHORIZATION_REQUIRED`](crate::CharacteristicProperties::AUTHORIZATION_REQUIRED).
+    /// Such access fails with Insufficient Authorization unless `authorizer` returns `true`.
+    ///
+    /// Without an authorizer every such access fails.
+    pub fn set_authorizer<F>(&mut self, authorizer: F)
+    where
+        F: Fn(&AuthorizationRequest<'_, T>) -> bool + Send + Sync + 'static,
+    {
+        self.authorizer = Some(Arc::new(authorizer));
+    }
+
+    /// Remember granted accesses until the connection is closed,
+    /// instead of asking the authorizer on every request.
+    pub fn set_authorization_cache(&mut self, cache: bool) {
+        self.cache_authorization = cache;
+    }
+
+    /// Restore Client Characteristic Configurations of the peer from `store`
+    /// and keep `store` updated as the peer writes them.
+    ///
+    /// If the database changed since the peer last knew it, the peer is
+    /// change-unaware, and told with a Service Changed indication once running.
+    /// Its configurations are then discarded, as their handles may belong to other
+    /// characteristics now, except the one of Service Changed.
+    ///
+    /// Use only for bonded peers.
+    pub fn set_subscription_store(&mut self, store: Arc<dyn SubscriptionStore>) -> io::Result<()> {
+        let subscriptions = PeerSubscriptions::new(store, self.address().clone());
+        let mut values = subscriptions.load()?;
+        let known_hash = subscriptions.load_database_hash()?;
+        let mut db = self.db.lock().unwrap();
+        let hash = db.hash();
+        match known_hash {
+            Some(known_hash) if known_hash != hash => {
+                let kept = self
+                    .service_changed
+                    .as_ref()
+                    .and_then(|service_changed| db.configuration_handle(&service_changed.handle));
+                values.retain(|handle, _| {
+                    let keep = Some(handle) == kept.as_ref();
+                    if !keep {
+                        subscriptions.save(handle, 0);
+                    }
+                    keep
+                });
+                db.set_change_unaware();
+                self.service_changed_pending = self.service_changed.is_some();
+                // otherwise saved once Service Changed is confirmed
+                if self.service_changed.is_none() {
+                    subscriptions.save_database_hash(&hash);
+                }
+            }
+            Some(..) => {}
+            None => subscriptions.save_database_hash(&hash),
+        }
+        for (handle, value) in values {
+            if db.configuration_at(&handle).is_none() {
+                continue;
+            }
+            if let Err(err) = db.set_value(&handle, &value.to_le_bytes()) {
+                log::warn!("failed to restore subscription: {:?}", err);
+            }
+        }
+        drop(db);
+        if let Some(service_changed) = &self.service_changed {
+            *service_changed.subscriptions.lock().unwrap() = Some(subscriptions.clone());
+        }
+        self.subscriptions = Some(subscriptions);
+        Ok(())
+    }
+
+    /// Mark the client change-unaware, e.g. a bonded client
+    /// whose database changed since the last connection.
+    ///
+    /// If the client enabled robust caching, its next request fails with
+    /// Database Out Of Sync and its commands are ignored until it is back in sync.
+    pub fn set_change_unaware(&self) {
+        self.db.lock().unwrap().set_change_unaware();
+    }
+
+    pub fn authenticator(&self) -> Authenticator {
+        Authenticator {
+            authenticated: self.authenticated.clone(),
+        }
+    }
+
+    pub fn events(&mut self) -> Events<T> {
+        self.events.subscribe()
+    }
+
+    /// Time the client has to confirm an indication. Defaults to 30 seconds.
+    ///
+    /// When it expires, the indication fails with a [`io::ErrorKind::TimedOut`]
+    /// error and no more PDUs are sent on the bearer. Applies to the bearers
+    /// added so far.
+    pub fn set_transaction_timeout(&mut self, timeout: Duration) {
+        for bearer in &mut self.bearers {
+            bearer.set_transaction_timeout(timeout);
+        }
+    }
+
+    /// Attach an additional Enhanced ATT bearer, e.g. from [`Server::accept_bearer`],
+    /// which takes its MTU from the L2CAP channel.
+    /// All bearers share the database and client configurations.
+    ///
+    /// Returns the bearer index. The bearer the connection was created with is `0`.
+    pub fn add_bearer(&mut self, bearer: Bearer) -> usize {
+        self.bearers.push(bearer);
+        self.bearers.len() - 1
+    }
+
+    pub fn notification(&self, token: &T) -> Result<Notification, HandleNotFound> {
+        self.notification_with(&self.bearers[0], token)
+    }
+
+    pub fn indication(&self, token: &T) -> Result<Indication, HandleNotFound> {
+        self.indication_with(&self.bearers[0], token)
+    }
+
+    /// Notification writer on the bearer returned by [`Connection::add_bearer`].
+    pub fn notification_on(&self, bearer: usize, token: &T) -> Result<Notification, WriterError> {
+        let bearer = self.bearers.get(bearer).ok_or(BearerNotFound)?;
+        Ok(self.notification_with(bearer, token)?)
+    }
+
+    /// Indication writer on the bearer returned by [`Connection::add_bearer`].
+    /// Each bearer has its own outstanding indication.
+    pub fn indication_on(&self, bearer: usize, token: &T) -> Result<Indication, WriterError> {
+        let bearer = self.bearers.get(bearer).ok_or(BearerNotFound)?;
+        Ok(self.indication_with(bearer, token)?)
+    }
+
+    fn notification_with(
+        &self,
+        bearer: &AttConnection,
+        token: &T,
+    ) -> Result<Notification, HandleNotFound> {
+        let handle = self.notify_or_indicate_handle(token);
+        if let Some(handle) = handle {
+            let notification = bearer.notification(handle.clone());
+            Ok(Notification(Subscribed {
+                inner: notification,
+                db: self.db.clone(),
+                handle,
+                required: ClientCharacteristicConfiguration::NOTIFICATION,
+                writing: false,
+            }))
+        } else {
+            Err(HandleNotFound)
+        }
+    }
+
+    fn indication_with(
+        &self,
+        bearer: &AttConnection,
+        token: &T,
+    ) -> Result<Indication, HandleNotFound> {
+        let handle = self.notify_or_indicate_handle(token);
+        if let Some(handle) = handle {
+            let indication = bearer.indication(handle.clone());
+            Ok(Indication(Subscribed {
+                inner: indication,
+                db: self.db.clone(),
+                handle,
+                required: ClientCharacteristicConfiguration::INDICATION,
+                writing: false,
+            }))
+        } else {
+            Err(HandleNotFound)
+        }
+    }
+
+    fn notify_or_indicate_handle(&self, token: &T) -> Option<Handle> {
+        let tokens = self.tokens.lock().unwrap();
+        tokens.notify_or_indicate_handles.get(token).cloned()
+    }
+
+    pub fn multiple_notification(&self) -> MultipleNotification<T> {
+        MultipleNotification {
+            inner: self.bearers[0].multiple_notification(),
+            db: self.db.clone(),
+            handles: self
+                .tokens
+                .lock()
+                .unwrap()
+                .notify_or_indicate_handles
+                .clone(),
+        }
+    }
+
+    /// Handle to update characteristic values while the connection is running.
+    pub fn value_updater(&self) -> ValueUpdater<T> {
+        let tokens = self.tokens.lock().unwrap().clone();
+        let writers = tokens
+            .notify_or_indicate_handles
+            .keys()
+            .filter_map(|token| {
+                let notification = self.notification(token).ok()?;
+                let indication = self.indication(token).ok()?;
+                Some((token.clone(), (notification, indication)))
+            })
+            .collect();
+        ValueUpdater {
+            db: self.db.clone(),
+            handles: tokens.value_handles,
+            writers,
+        }
+    }
+
+    pub fn address(&self) -> &att::Address {
+        self.bearers[0].address()
+    }
+
+    /// Current ATT_MTU of the first bearer. [`Event::MtuChanged`] tells
+    /// when it is negotiated.
+    pub fn mtu(&self) -> u16 {
+        self.bearers[0].mtu()
+    }
+
+    /// Serve all bearers until every one of them is closed.
+    ///
+    /// An error of an added bearer is logged and ends only that bearer.
+    /// An error of the first bearer ends the connection.
+    pub async fn run(self) -> Result<(), RunError> {
+        let Self {
+            bearers,
+            db,
+            tokens,
+            events,
+            authenticated,
+            authorizer,
+            cache_authorization,
+            prepare_queue_limit,
+            subscriptions,
+            service_changed,
+            service_changed_pending,
+            registered,
+        } = self;
+        let authorization = Authorization {
+            peer: bearers[0].address().clone(),
+            authorizer,
+            granted: if cache_authorization {
+                Some(HashSet::new())
+            } else {
+                None
+            },
+        };
+        let security = LinkSecurity {
+            query: bearers[0].security_query(),
+            authenticated,
+        };
+        let handler = GattHandler {
+            max_mtu: bearers[0].max_mtu(),
+            ..GattHandler::<T>::new(
+                db,
+                tokens,
+                events,
+                security,
+                authorization,
+                prepare_queue_limit,
+                subscriptions,
+            )
+        };
+        let handler = SharedHandler(Arc::new(Mutex::new(handler)));
+        let mut runs = bearers
+            .into_iter()
+            .map(|bearer| bearer.run(handler.clone()));
+        let base = runs.next().expect("the first bearer is never removed");
+        // an Enhanced ATT bearer failing leaves the others running
+        let enhanced = future::join_all(runs.enumerate().map(|(index, run)| async move {
+            if let Err(err) = run.await {
+                log::debug!("bearer {} failed: {}", index + 1, err);
+            }
+        }));
+        let runs = async move {
+            pin_mut!(base, enhanced);
+            match future::select(base, enhanced).await {
+                Either::Left((result, enhanced)) => {
+                    result?;
+                    enhanced.await;
+                }
+                Either::Right((_, base)) => base.await?,
+            }
+            Ok::<_, AttError>(())
+        };
+
+        let result = match service_changed.filter(|_| service_changed_pending) {
+            Some(service_changed) => {
+                // the whole range, as which services changed is not known
+                let indicate = async move {
+                    let range = Handle::new(0x0001)..=Handle::new(0xFFFF);
+                    if let Err(err) = service_changed.indicate(&range).await {
+                        log::debug!("failed to indicate service changed: {}", err);
+                    }
+                };
+                pin_mut!(runs, indicate);
+                match future::select(runs, indicate).await {
+                    Either::Left((result, _)) => result,
+                    Either::Right(((), runs)) => runs.await,
+                }
+            }
+            None => runs.await,
+        };
+        drop(registered);
+        result?;
+        Ok(())
+    }
+}
+
+/// Shared entry of a connection of a [`SharedDatabase`].
+struct Entry {
+    db: Arc<Mutex<Database>>,
+    notifier: Notifier,
+    service_changed: Option<Arc<ServiceChanged>>,
+}
+
+struct Connections {
+    next_id: usize,
+    entries: HashMap<usize, Entry>,
+}
+
+/// Removes the connection from its [`SharedDatabase`] when dropped.
+struct Registered {
+    id: usize,
+    connections: Arc<Mutex<Connections>>,
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        self.connections.lock().unwrap().entries.remove(&self.id);
+    }
+}
+
+/// Services added with [`SharedDatabase::add_services`].
+///
+/// Their handles do not change until they are removed.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ServiceGroup {
+    range: RangeInclusive<Handle>,
+}
+
+impl ServiceGroup {
+    /// Handles of the services.
+    pub fn range(&self) -> &RangeInclusive<Handle> {
+        &self.range
+    }
+}
+
+/// No free handle range is large enough.
+#[derive(Debug, thiserror::Error)]
+#[error("handles exhausted.")]
+pub struct HandlesExhausted;
+
+/// Attribute database shared by every connection accepted with it.
+///
+/// Values are shared. Client Characteristic Configurations are per connection.
+///
+/// Services may be added and removed while connections are running.
+/// Use [`Registration::add_generic_attribute_service`] to tell clients with
+/// Service Changed indications.
+pub struct SharedDatabase<T> {
+    /// Shares values with every connection.
+    db: Mutex<Database>,
+    tokens: Arc<Mutex<Tokens<T>>>,
+    service_changed: Option<Handle>,
+    connections: Arc<Mutex<Connections>>,
+}
+
+impl<T> SharedDatabase<T>
+where
+    T: Eq + Hash + Clone,
+{
+    pub fn new(registration: Registration<T>) -> Self {
+        let built = registration.build();
+        Self {
+            db: Mutex::new(built.db),
+            tokens: Arc::new(Mutex::new(built.tokens)),
+            service_changed: built.service_changed,
+            connections: Arc::new(Mutex::new(Connections {
+                next_id: 0,
+                entries: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Serve this database over an arbitrary packet-preserving transport.
+    pub fn connection<IO>(&self, io: IO, address: att::Address) -> Connection<T>
+    where
+        IO: att::Transport,
+    {
+        self.with_connection(AttConnection::new(io, address))
+    }
+
+    fn with_connection(&self, inner: AttConnection) -> Connection<T> {
+        // holds the lock so that no change is missed by the new connection
+        let mut connections = self.connections.lock().unwrap();
+        let db = self.db.lock().unwrap().clone();
+        let mut connection =
+            Connection::with_tokens(inner, db, self.tokens.clone(), self.service_changed.clone());
+
+        let id = connections.next_id;
+        connections.next_id += 1;
+        connections.entries.insert(
+            id,
+            Entry {
+                db: connection.db.clone(),
+                notifier: connection.bearers[0].notifier(),
+                service_changed: connection.service_changed.clone(),
+            },
+        );
+        connection.registered = Some(Registered {
+            id,
+            connections: self.connections.clone(),
+        });
+        connection
+    }
+
+    /// Number of connections not yet closed.
+    pub fn connections(&self) -> usize {
+        self.connections.lock().unwrap().entries.len()
+    }
+
+    /// Store `value` and notify it to every connection that enabled notifications,
+    /// to all of them at once.
+    ///
+    /// Returns the number of connections notified. Fails with
+    /// [`NotifyError::PayloadTooLarge`] if `value` does not fit into the MTU
+    /// of some connection; the others are notified nonetheless.
+    pub async fn notify(&self, token: &T, value: &[u8]) -> Result<usize, NotifyError> {
+        let handle = {
+            let tokens = self.tokens.lock().unwrap();
+            tokens
+                .value_handles
+                .get(token)
+                .cloned()
+                .ok_or(HandleNotFound)?
+        };
+        self.db
+            .lock()
+            .unwrap()
+            .set_value(&handle, value)
+            .map_err(|_| HandleNotFound)?;
+
+        let notifications = {
+            let connections = self.connections.lock().unwrap();
+            connections
+                .entries
+                .values()
+                .filter(|entry| {
+                    entry
+                        .db
+                        .lock()
+                        .unwrap()
+                        .client_configuration(&handle)
+                        .contains(ClientCharacteristicConfiguration::NOTIFICATION)
+                })
+                .map(|entry| entry.notifier.notification(handle.clone()))
+                .collect::<Vec<_>>()
+        };
+
+        let results = future::join_all(notifications.into_iter().map(|mut notification| {
+            notification.set_write_mode(WriteMode::Strict);
+            async move { notification.write_all(value).await }
+        }))
+        .await;
+
+        let mut n = 0;
+        let mut too_large = None;
+        for result in results {
+            match result {
+                Ok(()) => n += 1,
+                Err(err) => match err.get_ref().and_then(|e| e.downcast_ref::<AttError>()) {
+                    Some(AttError::PayloadTooLarge { max }) => {
+                        too_large = Some(too_large.map_or(*max, |m: usize| m.min(*max)))
+                    }
+                    _ => log::debug!("failed to notify: {}", err),
+                },
+            }
+        }
+        match too_large {
+            Some(max) => Err(NotifyError::PayloadTooLarge { max }),
+            None => Ok(n),
+        }
+    }
+
+    /// Add the services of `registration` at the lowest free handles,
+    /// reusing the handles of removed services.
+    ///
+    /// Clients see the services at once. Waits for every client
+    /// subscribed to Service Changed to confirm the change.
+    pub async fn add_services(
+        &self,
+        registration: Registration<T>,
+    ) -> Result<ServiceGroup, HandlesExhausted> {
+        let range = {
+            let mut connections = self.connections.lock().unwrap();
+            let mut db = self.db.lock().unwrap();
+            let len = registration.len();
+            let start = db.find_free(len).ok_or(HandlesExhausted)?;
+            let end = Handle::new(start.as_u16() + (len - 1));
+            let (attrs, tokens) = registration.into_parts_at(&start);
+
+            db.insert(attrs.clone());
+            db.refresh_database_hash();
+            for entry in connections.entries.values_mut() {
+                let mut db = entry.db.lock().unwrap();
+                db.insert(attrs.clone());
+                db.set_change_unaware();
+            }
+            self.tokens.lock().unwrap().extend(tokens);
+            start..=end
+        };
+        self.indicate_service_changed(&range).await;
+        Ok(ServiceGroup { range })
+    }
+
+    /// Remove services added with [`SharedDatabase::add_services`].
+    /// Their handles are free for the services added next.
+    ///
+    /// Waits for every client subscribed to Service Changed to confirm the change.
+    pub async fn remove_services(&self, group: ServiceGroup) -> Result<(), HandleNotFound> {
+        let ServiceGroup { range } = group;
+        {
+            let mut connections = self.connections.lock().unwrap();
+            let mut db = self.db.lock().unwrap();
+            db.remove(&range).ok_or(HandleNotFound)?;
+            db.refresh_database_hash();
+            for entry in connections.entries.values_mut() {
+                let mut db = entry.db.lock().unwrap();
+                db.remove(&range);
+                db.set_change_unaware();
+            }
+            self.tokens.lock().unwrap().remove(&range);
+        }
+        self.indicate_service_changed(&range).await;
+        Ok(())
+    }
+
+    async fn indicate_service_changed(&self, range: &RangeInclusive<Handle>) {
+        let service_changed = {
+            let connections = self.connections.lock().unwrap();
+            connections
+                .entries
+                .values()
+                .filter_map(|entry| entry.service_changed.clone())
+                .collect::<Vec<_>>()
+        };
+        let indications = service_changed.iter().map(|service_changed| async move {
+            if let Err(err) = service_changed.indicate(range).await {
+                log::debug!("failed to indicate service changed: {}", err);
+            }
+        });
+        future::join_all(indications).await;
+    }
+}
+
+/// GATT Protocol Server
+pub struct Server {
+    inner: AttServer,
+}
+
+impl Server {
+    pub fn bind() -> io::Result<Self> {
+        let server = AttServer::new()?;
+        Ok(Self { inner: server })
+    }
+
+    /// Listen for Enhanced ATT bearers.
+    /// Use [`Server::accept_bearer`] and [`Connection::add_bearer`].
+    pub fn bind_eatt() -> io::Result<Self> {
+        let server = AttServer::new_eatt()?;
+        Ok(Self { inner: server })
+    }
+
+    /// Accept [`Connection`]
+    pub async fn accept<T>(
+        &mut self,
+        registration: Registration<T>,
+    ) -> io::Result<Option<Connection<T>>>
+    where
+        T: Eq + Hash + Clone,
+    {
+        if let Some((connection, _)) = self.inner.accept().await? {
+            Ok(Some(Connection::with_connection(
+                connection,
+                registration.build(),
+            )))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Accept [`Connection`] serving the shared `db`.
+    pub async fn accept_shared<T>(
+        &mut self,
+        db: &SharedDatabase<T>,
+    ) -> io::Result<Option<Connection<T>>>
+    where
+        T: Eq + Hash + Clone,
+    {
+        if let Some((connection, _)) = self.inner.accept().await? {
+            Ok(Some(db.with_connection(connection)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Accept [`Bearer`] with the address of the remote device.
+    pub async fn accept_bearer(&mut self) -> io::Result<Option<(Bearer, att::Address)>> {
+        self.inner.accept().await
+    }
+
+    pub fn needs_bond(&self) -> io::Result<()> {
+        self.inner.needs_bond()?;
+        Ok(())
+    }
+
+    pub fn needs_bond_mitm(&self) -> io::Result<()> {
+        self.inner.needs_bond_mitm()?;
+        Ok(())
+    }
+
+    /// Largest ATT_MTU offered to clients. Defaults to 517.
+    pub fn set_mtu(&mut self, mtu: u16) -> io::Result<()> {
+        self.inner.set_mtu(mtu)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use att::client::Event as AttEvent;
+    use att::Client as AttClient;
+    use tokio::io::AsyncWriteExt;
+
+    use crate::characteristics as ch;
+    use crate::services as srv;
+    use crate::testing::Packet;
+    use crate::CharacteristicProperties;
+
+    fn address() -> att::Address {
+        att::Address::le_public_from([0; 6])
+    }
+
+    /// Battery Level with token `()` and value `[0]` at 0x0003, its CCCD at 0x0004.
+    fn battery_level(properties: CharacteristicProperties) -> Registration<()> {
+        let mut registration = Registration::new();
+        registration.add_primary_service(srv::BATTERY);
+        registration.add_characteristic_with_token((), ch::BATTERY_LEVEL, [0], properties);
+        registration
+    }
+
+    fn is_error(err: att::client::Error, code: pkt::ErrorCode) -> bool {
+        matches!(err, att::client::Error::ErrorResponse(e) if *e.error_code() == code)
+    }
+
+    /// Aborts the served connection when dropped.
+    struct Served(tokio::task::JoinHandle<Result<(), RunError>>);
+
+    impl Drop for Served {
+        fn drop(&mut self) {
+            self.0.abort();
+        }
+    }
+
+    /// Serve `registration` in the background to a client on an in-memory bearer.
+    /// `setup` configures the connection before it runs.
+    fn serve<F, R>(registration: Registration<()>, setup: F) -> (AttClient, R, Served)
+    where
+        F: FnOnce(&mut Connection<()>) -> R,
+    {
+        let (server, client) = Packet::pair();
+        let mut connection = Connection::new(server, address(), registration);
+        let r = setup(&mut connection);
+        let served = Served(tokio::spawn(connection.run()));
+        (AttClient::new(client, address()), r, served)
+    }
+
+    #[tokio::test]
+    async fn test_bearers() {
+        let registration = battery_level(
+            CharacteristicProperties::READ
+                | CharacteristicProperties::WRITE
+                | CharacteristicProperties::INDICATE,
+        );
+        let (client0, (client1, mut indication, mut events), _served) =
+            serve(registration, |connection| {
+                let (server, client) = Packet::pair();
+                let mut bearer = Bearer::new(server, address());
+                bearer.set_eatt_mtu(64);
+                let bearer = connection.add_bearer(bearer);
+                assert_eq!(bearer, 1);
+                let indication = connection.indication_on(bearer, &()).unwrap();
+                assert!(matches!(
+                    connection.notification_on(2, &()),
+                    Err(WriterError::BearerNotFound(_))
+                ));
+                (
+                    AttClient::new(client, address()),
+                    indication,
+                    connection.events(),
+                )
+            });
+
+        // EATT bearers take the MTU of the L2CAP channel
+        let err = client1.exchange_mtu(100).await.unwrap_err();
+        assert!(is_error(err, pkt::ErrorCode::RequestNotSupported));
+
+        client1.write(0x0003.into(), &[50]).await.unwrap();
+        let response = client0.read(0x0003.into()).await.unwrap();
+        assert_eq!(&**response.attribute_value(), &[50]);
+        assert!(matches!(events.next().await, Some(Event::Write((), v)) if *v == [50]));
+
+        client1.write(0x0004.into(), &[0x02, 0x00]).await.unwrap();
+        let mut client_events = client1.events();
+        let (sent, received) = tokio::join!(indication.write_all(b"ok"), client_events.next());
+        sent.unwrap();
+        assert_eq!(
+            received.unwrap(),
+            Some(AttEvent::Indication(0x0003.into(), b"ok".as_ref().into()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_set_value() {
+        let registration =
+            battery_level(CharacteristicProperties::READ | CharacteristicProperties::NOTIFY);
+        let (client, mut updater, _served) =
+            serve(registration, |connection| connection.value_updater());
+        let mut client_events = client.events();
+
+        // not subscribed yet
+        updater.set_value(&(), &[10]).await.unwrap();
+        let response = client.read(0x0003.into()).await.unwrap();
+        assert_eq!(&**response.attribute_value(), &[10]);
+
+        client.write(0x0004.into(), &[0x01, 0x00]).await.unwrap();
+        updater.set_value(&(), &[20]).await.unwrap();
+        assert_eq!(
+            client_events.next().await.unwrap(),
+            Some(AttEvent::Notification(0x0003.into(), [20].as_ref().into()))
+        );
+        let response = client.read(0x0003.into()).await.unwrap();
+        assert_eq!(&**response.attribute_value(), &[20]);
+    }
+
+    fn robust_caching_registration() -> Registration<()> {
+        let mut registration = Registration::new();
+        registration.add_primary_service(srv::GENERIC_ATTRIBUTE);
+        registration.add_client_supported_features();
+        registration.add_database_hash();
+        registration.add_primary_service(srv::BATTERY);
+        registration.add_characteristic_with_token(
+            (),
+            ch::BATTERY_LEVEL,
+            [0],
+            CharacteristicProperties::READ | CharacteristicProperties::WRITE_WITHOUT_RESPONSE,
+        );
+        registration
+    }
+
+    #[tokio::test]
+    async fn test_prepare_write() {
+        let registration =
+            battery_level(CharacteristicProperties::READ | CharacteristicProperties::WRITE);
+        let (client, mut events, _served) = serve(registration, |connection| {
+            connection.set_prepare_queue_limit(2);
+            connection.events()
+        });
+        client.prepare_write(0x0003.into(), 0, &[1]).await.unwrap();
+        client.prepare_write(0x0003.into(), 1, &[2]).await.unwrap();
+        let err = client
+            .prepare_write(0x0003.into(), 2, &[3])
+            .await
+            .unwrap_err();
+        assert!(is_error(err, pkt::ErrorCode::PrepareQueueFull));
+
+        // cancel
+        client.execute_write(false).await.unwrap();
+        let response = client.read(0x0003.into()).await.unwrap();
+        assert_eq!(&**response.attribute_value(), &[0]);
+        assert!(events.0.try_recv().is_err());
+
+        client.prepare_write(0x0003.into(), 0, &[1]).await.unwrap();
+        client.prepare_write(0x0003.into(), 5, &[2]).await.unwrap();
+        let err = client.execute_write(true).await.unwrap_err();
+        assert!(is_error(err, pkt::ErrorCode::InvalidOffset));
+        let response = client.read(0x0003.into()).await.unwrap();
+        assert_eq!(&**response.attribute_value(), &[0]);
+        assert!(events.0.try_recv().is_err());
+
+        // the queue was emptied
+        client.prepare_write(0x0003.into(), 0, &[1]).await.unwrap();
+        client.prepare_write(0x0003.into(), 1, &[2]).await.unwrap();
+        client.execute_write(true).await.unwrap();
+        let response = client.read(0x0003.into()).await.unwrap();
+        assert_eq!(&**response.attribute_value(), &[1, 2]);
+        assert!(matches!(events.next().await, Some(Event::Write((), v)) if *v == [1, 2]));
+    }
+
+    #[tokio::test]
+    async fn test_dynamic_value() {
+        use std::sync::atomic::AtomicUsize;
+
+        let len = Arc::new(AtomicUsize::new(30));
+        let mut registration = Registration::new();
+        registration.add_primary_service(srv::BATTERY);
+        let reader_len = len.clone();
+        registration.add_dynamic_characteristic_with_token(
+            (),
+            ch::MODEL_NUMBER_STRING,
+            move || Ok(vec![0xAA; reader_len.load(Ordering::SeqCst)]),
+            CharacteristicProperties::READ | CharacteristicProperties::WRITE,
+        );
+        let (client, mut events, _served) = serve(registration, |connection| connection.events());
+
+        let response = client.read(0x0003.into()).await.unwrap();
+        assert_eq!(response.attribute_value().len(), 22);
+        len.store(10, Ordering::SeqCst);
+        let err = client.read_blob(0x0003.into(), 22).await.unwrap_err();
+        assert!(matches!(
+            err,
+            att::client::Error::ErrorResponse(e)
+                if *e.error_code() == pkt::ErrorCode::InvalidOffset
+        ));
+        let response = client.read_blob(0x0003.into(), 10).await.unwrap();
+        assert!(response.attribute_value().is_empty());
+
+        // passed to the application, reads stay computed
+        client.write(0x0003.into(), &[1]).await.unwrap();
+        assert!(matches!(events.next().await, Some(Event::Write((), v)) if *v == [1]));
+        let response = client.read(0x0003.into()).await.unwrap();
+        assert_eq!(&**response.attribute_value(), &[0xAA; 10]);
+    }
+
+    #[tokio::test]
+    async fn test_robust_caching() {
+        let (client, (), _served) = serve(robust_caching_registration(), |connection| {
+            connection.set_change_unaware()
+        });
+
+        // not enabled robust caching yet
+        client.write(0x0003.into(), &[0x01]).await.unwrap();
+        let err = client.read(0x0003.into()).await.unwrap_err();
+        assert!(matches!(
+            err,
+            att::client::Error::ErrorResponse(e)
+                if *e.error_code() == pkt::ErrorCode::DatabaseOutOfSync
+        ));
+        // ignored until back in sync
+        client.write_command(0x0008.into(), &[1]).await.unwrap();
+
+        let response = client.read(0x0003.into()).await.unwrap();
+        assert_eq!(&**response.attribute_value(), &[0x01]);
+        let response = client.read(0x0008.into()).await.unwrap();
+        assert_eq!(&**response.attribute_value(), &[0]);
+        client.write_command(0x0008.into(), &[2]).await.unwrap();
+        let response = client.read(0x0008.into()).await.unwrap();
+        assert_eq!(&**response.attribute_value(), &[2]);
+
+        let err = client.write(0x0003.into(), &[0x00]).await.unwrap_err();
+        assert!(matches!(
+            err,
+            att::client::Error::ErrorResponse(e)
+                if *e.error_code() == pkt::ErrorCode::ValueNotAllowed
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_robust_caching_read_hash() {
+        let (client, hash, _served) = serve(robust_caching_registration(), |connection| {
+            connection.set_change_unaware();
+            connection.db.lock().unwrap().hash()
+        });
+
+        client.write(0x0003.into(), &[0x01]).await.unwrap();
+        let response = client
+            .read_by_type(0x0001.into(), 0xFFFF.into(), ch::DATABASE_HASH)
+            .await
+            .unwrap();
+        let values = response.into_iter().collect::<Vec<_>>();
+        assert_eq!(values, vec![(0x0005.into(), hash.as_ref().into())]);
+
+        client.read(0x0008.into()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_authorization() {
+        use std::sync::atomic::AtomicUsize;
+
+        fn registration() -> Registration<()> {
+            let mut registration = battery_level(
+                CharacteristicProperties::READ
+                    | CharacteristicProperties::WRITE
+                    | CharacteristicProperties::AUTHORIZATION_REQUIRED,
+            );
+            registration.add_characteristic(
+                ch::MODEL_NUMBER_STRING,
+                [1],
+                CharacteristicProperties::READ,
+            );
+            registration
+        }
+
+        fn set_authorizer(connection: &mut Connection<()>, asked: Arc<AtomicUsize>) {
+            connection.set_authorizer(move |request| {
+                asked.fetch_add(1, Ordering::SeqCst);
+                assert_eq!(request.peer, &address());
+                assert_eq!(request.token, Some(&()));
+                assert_eq!(request.handle, &0x0003.into());
+                request.operation == Operation::Read
+            });
+        }
+
+        let asked = Arc::new(AtomicUsize::new(0));
+        let (client, mut events, served) = serve(registration(), |connection| {
+            set_authorizer(connection, asked.clone());
+            connection.events()
+        });
+
+        let response = client.read(0x0003.into()).await.unwrap();
+        assert_eq!(&**response.attribute_value(), &[0]);
+        let err = client.write(0x0003.into(), &[60]).await.unwrap_err();
+        assert!(matches!(
+            err,
+            att::client::Error::ErrorResponse(e)
+                if *e.error_code() == pkt::ErrorCode::InsufficientAuthorization
+        ));
+        // denied writes are not reported
+        assert!(events.0.try_recv().is_err());
+        client.read(0x0005.into()).await.unwrap();
+        client.read(0x0003.into()).await.unwrap();
+        assert_eq!(asked.load(Ordering::SeqCst), 3);
+        drop(served);
+
+        let asked = Arc::new(AtomicUsize::new(0));
+        let (client, (), _served) = serve(registration(), |connection| {
+            set_authorizer(connection, asked.clone());
+            connection.set_authorization_cache(true);
+        });
+
+        client.read(0x0003.into()).await.unwrap();
+        client.read(0x0003.into()).await.unwrap();
+        client.write(0x0003.into(), &[60]).await.unwrap_err();
+        client.write(0x0003.into(), &[60]).await.unwrap_err();
+        assert_eq!(asked.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_authorization_per_attribute() {
+        let mut registration = Registration::new();
+        registration.add_primary_service(srv::BATTERY);
+        registration.add_characteristic(ch::BATTERY_LEVEL, [1], CharacteristicProperties::READ);
+        for _ in 0..2 {
+            registration.add_characteristic(
+                ch::BATTERY_LEVEL,
+                [2],
+                CharacteristicProperties::READ | CharacteristicProperties::AUTHORIZATION_REQUIRED,
+            );
+        }
+        let asked = Arc::new(Mutex::new(vec![]));
+        let (client, (), _served) = serve(registration, |connection| {
+            let asked = asked.clone();
+            connection.set_authorizer(move |request| {
+                asked.lock().unwrap().push(request.handle.clone());
+                false
+            });
+        });
+
+        // the attributes before a denied one are returned
+        let response = client
+            .read_by_type(0x0001.into(), 0xFFFF.into(), ch::BATTERY_LEVEL)
+            .await
+            .unwrap();
+        let values = response.into_iter().collect::<Vec<_>>();
+        assert_eq!(values, vec![(0x0003.into(), [1].as_ref().into())]);
+        let err = client
+            .read_by_type(0x0004.into(), 0xFFFF.into(), ch::BATTERY_LEVEL)
+            .await
+            .unwrap_err();
+        assert!(is_error(err, pkt::ErrorCode::InsufficientAuthorization));
+        let err = client
+            .read_multiple(vec![0x0003.into(), 0x0005.into(), 0x0007.into()])
+            .await
+            .unwrap_err();
+        assert!(is_error(err, pkt::ErrorCode::InsufficientAuthorization));
+        // only about attributes which would have been returned
+        assert_eq!(*asked.lock().unwrap(), vec![Handle::from(0x0005); 3]);
+    }
+
+    /// Link security reported by the returned value instead of the bearer.
+    fn set_security_query(connection: &mut Connection<()>) -> Arc<Mutex<att::Security>> {
+        let security = Arc::new(Mutex::new(att::Security::default()));
+        let query = security.clone();
+        connection.bearers[0]
+            .set_security_query(att::SecurityQuery::new(move || Ok(*query.lock().unwrap())));
+        security
+    }
+
+    #[tokio::test]
+    async fn test_link_security() {
+        let registration = battery_level(
+            CharacteristicProperties::READ
+                | CharacteristicProperties::WRITE
+                | CharacteristicProperties::AUTHENTICATED_SIGNED_WRITES,
+        );
+        let (client, security, _served) = serve(registration, set_security_query);
+
+        let err = client.write(0x0003.into(), &[60]).await.unwrap_err();
+        assert!(matches!(
+            err,
+            att::client::Error::ErrorResponse(e)
+                if *e.error_code() == pkt::ErrorCode::InsufficientAuthentication
+        ));
+        client.read(0x0003.into()).await.unwrap_err();
+
+        *security.lock().unwrap() = att::Security {
+            encrypted: true,
+            authenticated: true,
+            key_size: 16,
+        };
+        client.write(0x0003.into(), &[60]).await.unwrap();
+        let response = client.read(0x0003.into()).await.unwrap();
+        assert_eq!(&**response.attribute_value(), &[60]);
+    }
+
+    #[tokio::test]
+    async fn test_multiple_notification() {
+        let mut registration =
+            battery_level(CharacteristicProperties::READ | CharacteristicProperties::NOTIFY);
+        registration.add_primary_service(srv::GENERIC_ATTRIBUTE);
+        registration.add_client_supported_features();
+        let (client, mut notification, _served) = serve(registration, |connection| {
+            connection.multiple_notification()
+        });
+        let mut client_events = client.events();
+        client.write(0x0004.into(), &[0x01, 0x00]).await.unwrap();
+
+        let values = [((), [1].as_ref()), ((), [2].as_ref())];
+        assert_eq!(notification.notify(&values).await.unwrap(), 1);
+        assert_eq!(
+            client_events.next().await.unwrap(),
+            Some(AttEvent::Notification(0x0003.into(), [1].as_ref().into()))
+        );
+
+        client.write(0x0007.into(), &[0x04]).await.unwrap();
+        assert_eq!(notification.notify(&values).await.unwrap(), 2);
+        for value in [1, 2] {
+            assert_eq!(
+                client_events.next().await.unwrap(),
+                Some(AttEvent::Notification(
+                    0x0003.into(),
+                    [value].as_ref().into()
+                ))
+            );
+        }
+
+        // never truncated
+        let err = notification.notify(&[((), &[0; 21])]).await.unwrap_err();
+        assert!(matches!(
+            err,
+            MultipleNotificationError::Att(AttError::PayloadTooLarge { max: 20 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_attribute_security() {
+        use crate::{AttributeSecurity, SecurityLevel};
+
+        let mut registration =
+            battery_level(CharacteristicProperties::READ | CharacteristicProperties::WRITE);
+        registration.set_security(AttributeSecurity {
+            read: SecurityLevel::Open,
+            write: SecurityLevel::Authenticated,
+            min_key_size: 16,
+        });
+        registration.add_descriptor(att::Uuid::new_uuid16(0x2901), "level", false);
+        registration.set_security(AttributeSecurity {
+            read: SecurityLevel::Encrypted,
+            ..Default::default()
+        });
+        let (client, security, _served) = serve(registration, set_security_query);
+
+        client.read(0x0003.into()).await.unwrap();
+        let err = client.write(0x0003.into(), &[60]).await.unwrap_err();
+        assert!(is_error(err, pkt::ErrorCode::InsufficientAuthentication));
+        let err = client.read(0x0004.into()).await.unwrap_err();
+        assert!(is_error(err, pkt::ErrorCode::InsufficientEncryption));
+
+        *security.lock().unwrap() = att::Security {
+            encrypted: true,
+            authenticated: false,
+            key_size: 7,
+        };
+        client.read(0x0004.into()).await.unwrap();
+        let err = client.write(0x0003.into(), &[60]).await.unwrap_err();
+        assert!(is_error(err, pkt::ErrorCode::InsufficientAuthentication));
+
+        security.lock().unwrap().authenticated = true;
+        let err = client.write(0x0003.into(), &[60]).await.unwrap_err();
+        assert!(is_error(err, pkt::ErrorCode::InsufficientEncryptionKeySize));
+
+        security.lock().unwrap().key_size = 16;
+        client.write(0x0003.into(), &[60]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_mtu() {
+        let (client, mut events, _served) = serve(
+            battery_level(CharacteristicProperties::READ),
+            |connection| {
+                connection.bearers[0].set_max_mtu(100);
+                assert_eq!(connection.mtu(), 23);
+                connection.events()
+            },
+        );
+
+        let response = client.exchange_mtu(200).await.unwrap();
+        assert_eq!(*response.server_rx_mtu(), 100);
+        assert!(matches!(events.next().await, Some(Event::MtuChanged(100))));
+    }
+
+    #[tokio::test]
+    async fn test_subscription() {
+        let (client, mut notification, _served) = serve(
+            battery_level(CharacteristicProperties::NOTIFY),
+            |connection| connection.notification(&()).unwrap(),
+        );
+        let mut client_events = client.events();
+
+        let err = notification.write_all(&[1]).await.unwrap_err();
+        assert!(err.into_inner().unwrap().is::<NotSubscribed>());
+
+        let err = client.write(0x0004.into(), &[0x01]).await.unwrap_err();
+        assert!(matches!(
+            err,
+            att::client::Error::ErrorResponse(e)
+                if *e.error_code() == pkt::ErrorCode::InvalidAttributeValueLength
+        ));
+        let err = client
+            .write(0x0004.into(), &[0x04, 0x00])
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            att::client::Error::ErrorResponse(e)
+                if *e.error_code() == pkt::ErrorCode::CommonProfileAndServiceErrorCodes(0xFD)
+        ));
+
+        client.write(0x0004.into(), &[0x01, 0x00]).await.unwrap();
+        notification.write_all(&[2]).await.unwrap();
+        assert_eq!(
+            client_events.next().await.unwrap(),
+            Some(AttEvent::Notification(0x0003.into(), [2].as_ref().into()))
+        );
+
+        client.write(0x0004.into(), &[0x00, 0x00]).await.unwrap();
+        assert!(notification.write_all(&[3]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_subscription_events() {
+        let registration =
+            battery_level(CharacteristicProperties::NOTIFY | CharacteristicProperties::INDICATE);
+        let (client, (mut events, mut indication), _served) = serve(registration, |connection| {
+            (connection.events(), connection.indication(&()).unwrap())
+        });
+        let mut client_events = client.events();
+
+        client.write(0x0004.into(), &[0x03, 0x00]).await.unwrap();
+        client.write(0x0004.into(), &[0x03, 0x00]).await.unwrap();
+        client.write(0x0004.into(), &[0x00, 0x00]).await.unwrap();
+        client.write(0x0004.into(), &[0x02, 0x00]).await.unwrap();
+        let (sent, _) = tokio::join!(indication.write_all(b"ok"), client_events.next());
+        sent.unwrap();
+
+        assert!(matches!(
+            events.next().await,
+            Some(Event::Subscribe {
+                token: (),
+                notify: true,
+                indicate: true
+            })
+        ));
+        assert!(matches!(events.next().await, Some(Event::Unsubscribe(()))));
+        assert!(matches!(
+            events.next().await,
+            Some(Event::Subscribe {
+                token: (),
+                notify: false,
+                indicate: true
+            })
+        ));
+        assert!(matches!(
+            events.next().await,
+            Some(Event::IndicationConfirmed(()))
+        ));
+
+        // confirmed after the writer is gone
+        let write = indication.write_all(b"ok");
+        tokio::time::timeout(Duration::from_millis(50), write)
+            .await
+            .unwrap_err();
+        client_events.next().await.unwrap();
+        assert!(matches!(
+            events.next().await,
+            Some(Event::IndicationConfirmed(()))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_subscription_store() {
+        let store = Arc::new(crate::MemorySubscriptionStore::new());
+
+        let (client, (), served) = serve(
+            battery_level(CharacteristicProperties::NOTIFY),
+            |connection| connection.set_subscription_store(store.clone()).unwrap(),
+        );
+        client.write(0x0004.into(), &[0x01, 0x00]).await.unwrap();
+        drop(served);
+
+        // reconnect
+        let (client, mut notification, _served) = serve(
+            battery_level(CharacteristicProperties::NOTIFY),
+            |connection| {
+                connection.set_subscription_store(store).unwrap();
+                connection.notification(&()).unwrap()
+            },
+        );
+        let mut client_events = client.events();
+
+        let response = client.read(0x0004.into()).await.unwrap();
+        assert_eq!(&**response.attribute_value(), &[0x01, 0x00]);
+        notification.write_all(&[1]).await.unwrap();
+        assert_eq!(
+            client_events.next().await.unwrap(),
+            Some(AttEvent::Notification(0x0003.into(), [1].as_ref().into()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shared_database() {
+        let mut registration = Registration::new();
+        registration.add_primary_service(srv::BATTERY);
+        registration.add_characteristic_with_token(
+            (),
+            ch::BATTERY_LEVEL,
+            [0],
+            CharacteristicProperties::READ
+                | CharacteristicProperties::WRITE
+                | CharacteristicProperties::NOTIFY,
+        );
+        let db = SharedDatabase::new(registration);
+
+        let address = address();
+        let (server0, client0) = Packet::pair();
+        let (server1, client1) = Packet::pair();
+        let task0 = tokio::spawn(db.connection(server0, address.clone()).run());
+        let task1 = tokio::spawn(db.connection(server1, address.clone()).run());
+        assert_eq!(db.connections(), 2);
+
+        let client0 = AttClient::new(client0, address.clone());
+        let client1 = AttClient::new(client1, address);
+        let mut client_events1 = client1.events();
+
+        client0.write(0x0003.into(), &[50]).await.unwrap();
+        let response = client1.read(0x0003.into()).await.unwrap();
+        assert_eq!(&**response.attribute_value(), &[50]);
+
+        // configurations are per connection
+        client1.write(0x0004.into(), &[0x01, 0x00]).await.unwrap();
+        let response = client0.read(0x0004.into()).await.unwrap();
+        assert_eq!(&**response.attribute_value(), &[0x00, 0x00]);
+
+        assert_eq!(db.notify(&(), &[60]).await.unwrap(), 1);
+        assert_eq!(
+            client_events1.next().await.unwrap(),
+            Some(AttEvent::Notification(0x0003.into(), [60].as_ref().into()))
+        );
+        let response = client0.read(0x0003.into()).await.unwrap();
+        assert_eq!(&**response.attribute_value(), &[60]);
+
+        // values are never truncated
+        let err = db.notify(&(), &[70; 30]).await.unwrap_err();
+        assert!(matches!(err, NotifyError::PayloadTooLarge { max: 20 }));
+
+        // closing the connection forgets it
+        task0.abort();
+        assert!(task0.await.unwrap_err().is_cancelled());
+        assert_eq!(db.connections(), 1);
+
+        task1.abort();
+    }
+
+    #[tokio::test]
+    async fn test_service_changed() {
+        let mut registration = Registration::new();
+        registration.add_generic_attribute_service();
+        registration.add_primary_service(srv::BATTERY);
+        registration.add_characteristic(ch::BATTERY_LEVEL, [0], CharacteristicProperties::READ);
+        let db = SharedDatabase::new(registration);
+        let store = Arc::new(crate::MemorySubscriptionStore::new());
+        let address = address();
+
+        let (server, client) = Packet::pair();
+        let mut connection = db.connection(server, address.clone());
+        connection.set_subscription_store(store.clone()).unwrap();
+        let task = tokio::spawn(connection.run());
+        let client = AttClient::new(client, address.clone());
+        let mut client_events = client.events();
+        client.write(0x0004.into(), &[0x02, 0x00]).await.unwrap();
+
+        let mut services = Registration::new();
+        services.add_primary_service(srv::DEVICE_INFORMATION);
+        services.add_characteristic_with_token(
+            (),
+            ch::MODEL_NUMBER_STRING,
+            "model",
+            CharacteristicProperties::READ
+                | CharacteristicProperties::WRITE
+                | CharacteristicProperties::NOTIFY,
+        );
+        let (group, event) = tokio::join!(db.add_services(services), client_events.next());
+        let group = group.unwrap();
+        assert_eq!(group.range(), &(0x000C.into()..=0x000F.into()));
+        assert_eq!(
+            event.unwrap(),
+            Some(AttEvent::Indication(
+                0x0003.into(),
+                [0x0C, 0x00, 0x0F, 0x00].as_ref().into()
+            ))
+        );
+        let response = client.read(0x000E.into()).await.unwrap();
+        assert_eq!(&**response.attribute_value(), b"model");
+        client.write(0x000F.into(), &[0x01, 0x00]).await.unwrap();
+        task.abort();
+        assert!(task.await.unwrap_err().is_cancelled());
+
+        // replaced while disconnected, at the same handles
+        db.remove_services(group).await.unwrap();
+        let mut services = Registration::new();
+        services.add_primary_service(srv::DEVICE_INFORMATION);
+        services.add_characteristic(
+            ch::SERIAL_NUMBER_STRING,
+            "serial",
+            CharacteristicProperties::READ | CharacteristicProperties::NOTIFY,
+        );
+        let group = db.add_services(services).await.unwrap();
+        assert_eq!(group.range(), &(0x000C.into()..=0x000F.into()));
+        let known_hash = store.load_database_hash(&address).unwrap();
+
+        let (server, client) = Packet::pair();
+        let mut connection = db.connection(server, address.clone());
+        connection.set_subscription_store(store.clone()).unwrap();
+        assert!(!store.load(&address).unwrap().contains_key(&0x000F.into()));
+        assert_eq!(store.load_database_hash(&address).unwrap(), known_hash);
+        let task = tokio::spawn(connection.run());
+        let client = AttClient::new(client, address.clone());
+        let mut client_events = client.events();
+        // the old configuration is not applied to the new characteristic
+        let response = client.read(0x000F.into()).await.unwrap();
+        assert_eq!(&**response.attribute_value(), &[0x00, 0x00]);
+        assert_eq!(
+            client_events.next().await.unwrap(),
+            Some(AttEvent::Indication(
+                0x0003.into(),
+                [0x01, 0x00, 0xFF, 0xFF].as_ref().into()
+            ))
+        );
+        let response = client.read(0x000E.into()).await.unwrap();
+        assert_eq!(&**response.attribute_value(), b"serial");
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_runtime_services() {
+        fn services(uuid: att::Uuid, value: &str) -> Registration<()> {
+            let mut registration = Registration::new();
+            registration.add_primary_service(uuid);
+            registration.add_characteristic(
+                ch::MODEL_NUMBER_STRING,
+                value,
+                CharacteristicProperties::READ,
+            );
+            registration
+        }
+
+        let mut registration = Registration::new();
+        registration.add_primary_service(srv::BATTERY);
+        registration.add_characteristic(ch::BATTERY_LEVEL, [0], CharacteristicProperties::READ);
+        let db = SharedDatabase::<()>::new(registration);
+
+        let address = address();
+        let (server, client) = Packet::pair();
+        let task = tokio::spawn(db.connection(server, address.clone()).run());
+        let client = AttClient::new(client, address);
+        let discover = || async {
+            let response = client
+                .read_by_group_type(0x0001.into(), 0xFFFF.into(), att::Uuid::new_uuid16(0x2800))
+                .await
+                .unwrap();
+            response
+                .into_iter()
+                .map(|(start, end, _)| (start.as_u16(), end.as_u16()))
+                .collect::<Vec<_>>()
+        };
+
+        let debug = db
+            .add_services(services(srv::DEVICE_INFORMATION, "debug"))
+            .await
+            .unwrap();
+        let dfu = db
+            .add_services(services(srv::DEVICE_INFORMATION, "dfu"))
+            .await
+            .unwrap();
+        assert_eq!(debug.range(), &(0x0004.into()..=0x0006.into()));
+        assert_eq!(dfu.range(), &(0x0007.into()..=0x0009.into()));
+        assert_eq!(
+            discover().await,
+            vec![(0x0001, 0x0003), (0x0004, 0x0006), (0x0007, 0x0009)]
+        );
+
+        db.remove_services(debug).await.unwrap();
+        assert_eq!(discover().await, vec![(0x0001, 0x0003), (0x0007, 0x0009)]);
+        assert!(client.read(0x0006.into()).await.is_err());
+
+        // freed handles are reused
+        let debug = db
+            .add_services(services(srv::DEVICE_INFORMATION, "again"))
+            .await
+            .unwrap();
+        assert_eq!(debug.range(), &(0x0004.into()..=0x0006.into()));
+        assert_eq!(
+            discover().await,
+            vec![(0x0001, 0x0003), (0x0004, 0x0006), (0x0007, 0x0009)]
+        );
+        let response = client.read(0x0006.into()).await.unwrap();
+        assert_eq!(&**response.attribute_value(), b"again");
+        let response = client.read(0x0009.into()).await.unwrap();
+        assert_eq!(&**response.attribute_value(), b"dfu");
+
+        task.abort();
     }
 }
@@ -0,0 +1,366 @@
+//! Client Characteristic Configuration persistence for bonded peers.
+//!
+//! ref BLUETOOTH CORE SPECIFICATION Version 5.1 | Vol 3, Part G | 3.3.3.3
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use att::{Address, Handle};
+
+/// Stores Client Characteristic Configuration values by peer identity address.
+pub trait SubscriptionStore: Send + Sync {
+    /// Configuration values of `peer` by descriptor handle.
+    fn load(&self, peer: &Address) -> io::Result<HashMap<Handle, u16>>;
+
+    /// Remember the configuration value the peer wrote to `handle`.
+    ///
+    /// Called while handling the write, so it should not block.
+    fn save(&self, peer: &Address, handle: &Handle, value: u16) -> io::Result<()>;
+
+    /// Database Hash of the database `peer` last knew.
+    ///
+    /// A peer whose database changed since is told with a Service Changed
+    /// indication when it reconnects.
+    fn load_database_hash(&self, _peer: &Address) -> io::Result<Option<[u8; 16]>> {
+        Ok(None)
+    }
+
+    /// Remember the Database Hash of the database `peer` knows.
+    fn save_database_hash(&self, _peer: &Address, _hash: &[u8; 16]) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// In-memory [`SubscriptionStore`]. Survives reconnections, not restarts.
+#[derive(Debug, Default)]
+pub struct MemorySubscriptionStore {
+    peers: Mutex<HashMap<Address, HashMap<Handle, u16>>>,
+    hashes: Mutex<HashMap<Address, [u8; 16]>>,
+}
+
+impl MemorySubscriptionStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl SubscriptionStore for MemorySubscriptionStore {
+    fn load(&self, peer: &Address) -> io::Result<HashMap<Handle, u16>> {
+        let peers = self.peers.lock().unwrap();
+        Ok(peers.get(peer).cloned().unwrap_or_default())
+    }
+
+    fn save(&self, peer: &Address, handle: &Handle, value: u16) -> io::Result<()> {
+        let mut peers = self.peers.lock().unwrap();
+        let values = peers.entry(peer.clone()).or_default();
+        if value == 0 {
+            values.remove(handle);
+        } else {
+            values.insert(handle.clone(), value);
+        }
+        Ok(())
+    }
+
+    fn load_database_hash(&self, peer: &Address) -> io::Result<Option<[u8; 16]>> {
+        Ok(self.hashes.lock().unwrap().get(peer).copied())
+    }
+
+    fn save_database_hash(&self, peer: &Address, hash: &[u8; 16]) -> io::Result<()> {
+        self.hashes.lock().unwrap().insert(peer.clone(), *hash);
+        Ok(())
+    }
+}
+
+/// File-backed [`SubscriptionStore`].
+///
+/// One line per value: `<address type> <address> <handle> <value>`,
+/// and one per Database Hash: `<address type> <address> hash <hash>`.
+///
+/// The file is read once, on first use. Changes are written by a background
+/// thread, so that saving never waits for the file. Dropping the store waits
+/// for the pending writes.
+#[derive(Debug)]
+pub struct FileSubscriptionStore {
+    path: PathBuf,
+    records: Mutex<Option<Vec<(Address, Record)>>>,
+    writer: Mutex<Option<Writer>>,
+}
+
+/// Writes the latest records sent to it to the file.
+#[derive(Debug)]
+struct Writer {
+    tx: mpsc::Sender<Vec<(Address, Record)>>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl Writer {
+    fn spawn(path: PathBuf) -> Self {
+        let (tx, rx) = mpsc::channel::<Vec<(Address, Record)>>();
+        let thread = thread::spawn(move || {
+            while let Ok(mut records) = rx.recv() {
+                // only the latest records matter
+                while let Ok(newer) = rx.try_recv() {
+                    records = newer;
+                }
+                if let Err(err) = write_all(&path, &records) {
+                    log::warn!("failed to write {}: {}", path.display(), err);
+                }
+            }
+        });
+        Self { tx, thread }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Record {
+    Configuration(Handle, u16),
+    DatabaseHash([u8; 16]),
+}
+
+impl FileSubscriptionStore {
+    pub fn new<P>(path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        Self {
+            path: path.into(),
+            records: Mutex::new(None),
+            writer: Mutex::new(None),
+        }
+    }
+
+    /// Apply `f` to the records, reading them from the file first if not yet done.
+    fn with_records<F, R>(&self, f: F) -> io::Result<R>
+    where
+        F: FnOnce(&mut Vec<(Address, Record)>) -> R,
+    {
+        let mut records = self.records.lock().unwrap();
+        let records = match &mut *records {
+            Some(records) => records,
+            None => records.insert(read_all(&self.path)?),
+        };
+        Ok(f(records))
+    }
+
+    /// Hand the records to the writer thread. Called with the records locked,
+    /// so the writes keep their order.
+    fn persist(&self, records: &[(Address, Record)]) {
+        let mut writer = self.writer.lock().unwrap();
+        let writer = writer.get_or_insert_with(|| Writer::spawn(self.path.clone()));
+        if writer.tx.send(records.to_vec()).is_err() {
+            log::warn!("writer of {} is gone", self.path.display());
+        }
+    }
+}
+
+impl Drop for FileSubscriptionStore {
+    fn drop(&mut self) {
+        if let Some(Writer { tx, thread }) = self.writer.lock().unwrap().take() {
+            drop(tx);
+            thread.join().ok();
+        }
+    }
+}
+
+fn read_all(path: &Path) -> io::Result<Vec<(Address, Record)>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err),
+    };
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(parse_line)
+        .collect()
+}
+
+fn write_all(path: &Path, entries: &[(Address, Record)]) -> io::Result<()> {
+    let mut text = String::new();
+    for (peer, record) in entries {
+        let kind = match peer {
+            Address::BrEdr(..) => "bredr",
+            Address::LePublic(..) => "public",
+            Address::LeRandom(..) => "random",
+        };
+        match record {
+            Record::Configuration(handle, value) => text.push_str(&format!(
+                "{} {} {:04x} {:04x}\n",
+                kind,
+                peer,
+                handle.as_u16(),
+                value
+            )),
+            Record::DatabaseHash(hash) => {
+                let hash = hash
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<String>();
+                text.push_str(&format!("{} {} hash {}\n", kind, peer, hash))
+            }
+        }
+    }
+
+    let mut tmp = path.to_path_buf().into_os_string();
+    tmp.push(".tmp");
+    fs::write(&tmp, text)?;
+    fs::rename(&tmp, path)
+}
+
+fn parse_line(line: &str) -> io::Result<(Address, Record)> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid line {:?}", line),
+        )
+    };
+
+    let mut fields = line.split_whitespace();
+    let (kind, peer, handle, value) =
+        match (fields.next(), fields.next(), fields.next(), fields.next()) {
+            (Some(kind), Some(peer), Some(handle), Some(value)) => (kind, peer, handle, value),
+            _ => return Err(invalid()),
+        };
+    let peer = match kind {
+        "bredr" => Address::bredr_from_str(peer),
+        "public" => Address::le_public_from_str(peer),
+        "random" => Address::le_random_from_str(peer),
+        _ => return Err(invalid()),
+    }
+    .map_err(|_| invalid())?;
+    if handle == "hash" {
+        let mut hash = [0; 16];
+        if value.len() != hash.len() * 2 || !value.is_ascii() {
+            return Err(invalid());
+        }
+        for (i, b) in hash.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&value[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+        }
+        return Ok((peer, Record::DatabaseHash(hash)));
+    }
+    let handle = u16::from_str_radix(handle, 16).map_err(|_| invalid())?;
+    let value = u16::from_str_radix(value, 16).map_err(|_| invalid())?;
+    Ok((peer, Record::Configuration(handle.into(), value)))
+}
+
+impl SubscriptionStore for FileSubscriptionStore {
+    fn load(&self, peer: &Address) -> io::Result<HashMap<Handle, u16>> {
+        self.with_records(|entries| {
+            entries
+                .iter()
+                .filter_map(|(p, record)| match record {
+                    Record::Configuration(handle, value) if p == peer => {
+                        Some((handle.clone(), *value))
+                    }
+                    _ => None,
+                })
+                .collect()
+        })
+    }
+
+    fn save(&self, peer: &Address, handle: &Handle, value: u16) -> io::Result<()> {
+        self.with_records(|entries| {
+            entries.retain(|(p, record)| {
+                !(p == peer && matches!(record, Record::Configuration(h, _) if h == handle))
+            });
+            if value != 0 {
+                entries.push((peer.clone(), Record::Configuration(handle.clone(), value)));
+            }
+            self.persist(entries);
+        })
+    }
+
+    fn load_database_hash(&self, peer: &Address) -> io::Result<Option<[u8; 16]>> {
+        self.with_records(|entries| {
+            entries.iter().find_map(|(p, record)| match record {
+                Record::DatabaseHash(hash) if p == peer => Some(*hash),
+                _ => None,
+            })
+        })
+    }
+
+    fn save_database_hash(&self, peer: &Address, hash: &[u8; 16]) -> io::Result<()> {
+        self.with_records(|entries| {
+            entries
+                .retain(|(p, record)| !(p == peer && matches!(record, Record::DatabaseHash(..))));
+            entries.push((peer.clone(), Record::DatabaseHash(*hash)));
+            self.persist(entries);
+        })
+    }
+}
+
+/// [`SubscriptionStore`] bound to the peer of a connection.
+#[derive(Clone)]
+pub(crate) struct PeerSubscriptions {
+    store: Arc<dyn SubscriptionStore>,
+    peer: Address,
+}
+
+impl PeerSubscriptions {
+    pub(crate) fn new(store: Arc<dyn SubscriptionStore>, peer: Address) -> Self {
+        Self { store, peer }
+    }
+
+    pub(crate) fn load(&self) -> io::Result<HashMap<Handle, u16>> {
+        self.store.load(&self.peer)
+    }
+
+    pub(crate) fn save(&self, handle: &Handle, value: u16) {
+        if let Err(err) = self.store.save(&self.peer, handle, value) {
+            log::warn!("failed to save subscription of {}: {}", self.peer, err);
+        }
+    }
+
+    pub(crate) fn load_database_hash(&self) -> io::Result<Option<[u8; 16]>> {
+        self.store.load_database_hash(&self.peer)
+    }
+
+    pub(crate) fn save_database_hash(&self, hash: &[u8; 16]) {
+        if let Err(err) = self.store.save_database_hash(&self.peer, hash) {
+            log::warn!("failed to save database hash of {}: {}", self.peer, err);
+        }
+    }
+}
+
+impl fmt::Debug for PeerSubscriptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PeerSubscriptions")
+            .field("peer", &self.peer)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_store() {
+        let path = std::env::temp_dir().join(format!("gatt-subscriptions-{}", std::process::id()));
+        let store = FileSubscriptionStore::new(&path);
+        let peer0 = Address::le_public_from([0, 1, 2, 3, 4, 5]);
+        let peer1 = Address::le_random_from([0xC0, 1, 2, 3, 4, 0xC5]);
+
+        assert!(store.load(&peer0).unwrap().is_empty());
+        store.save(&peer0, &0x0004.into(), 0x0001).unwrap();
+        store.save(&peer0, &0x0008.into(), 0x0002).unwrap();
+        store.save(&peer1, &0x0004.into(), 0x0002).unwrap();
+        store.save(&peer0, &0x0008.into(), 0x0000).unwrap();
+        store.save_database_hash(&peer1, &[0xA5; 16]).unwrap();
+        store.save_database_hash(&peer1, &[0x5A; 16]).unwrap();
+        drop(store);
+
+        let store = FileSubscriptionStore::new(&path);
+        let loaded = store.load(&peer0).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[&0x0004.into()], 0x0001);
+        assert_eq!(store.load(&peer1).unwrap()[&0x0004.into()], 0x0002);
+        assert_eq!(store.load_database_hash(&peer0).unwrap(), None);
+        assert_eq!(store.load_database_hash(&peer1).unwrap(), Some([0x5A; 16]));
+
+        fs::remove_file(&path).unwrap();
+    }
+}